@@ -7,37 +7,189 @@ pub use iterator::BlockIterator;
 
 pub(crate) const SIZEOF_U16: usize = std::mem::size_of::<u16>();
 
+/// The default number of entries between restart points.
+pub const DEFAULT_RESTART_INTERVAL: usize = 16;
+
+/// The encoding of the entries in a block, recorded as a format version in the SST meta.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BlockFormat {
+    /// The key overlap, key length and value length are u16, and the ts is a u64.
+    #[default]
+    Fixed,
+    /// The key overlap, key length and value length are varints, and the ts is a zigzag varint
+    /// of its difference to the ts of the first entry in the block.
+    Varint,
+    /// The format of the SSTs written before the format version was added to the SST meta, whose
+    /// meta has no version. The fields are as in `Fixed`, but every entry is a restart point and
+    /// its key is delta-encoded against the first key in the block, and there is no hash index.
+    Legacy,
+}
+
+impl BlockFormat {
+    /// The format version in the SST meta. The meta of `Legacy` has no version, and 0 is only
+    /// used in the top-level index of a partitioned index.
+    pub fn version(self) -> u8 {
+        match self {
+            BlockFormat::Legacy => 0,
+            BlockFormat::Fixed => 1,
+            BlockFormat::Varint => 2,
+        }
+    }
+
+    pub fn from_version(version: u8) -> Option<Self> {
+        match version {
+            0 => Some(BlockFormat::Legacy),
+            1 => Some(BlockFormat::Fixed),
+            2 => Some(BlockFormat::Varint),
+            _ => None,
+        }
+    }
+}
+
+/// Appends `value` as a LEB128 varint.
+pub(crate) fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Reads a LEB128 varint and advances the buffer past it.
+pub(crate) fn get_varint(buf: &mut &[u8]) -> u64 {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = buf.get_u8();
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte < 0x80 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+/// The encoded size of `value` as a varint.
+pub(crate) fn varint_len(value: u64) -> usize {
+    (64 - (value | 1).leading_zeros() as usize).div_ceil(7)
+}
+
+/// Maps the difference of `ts` to `base_ts` to an unsigned integer that is small when the
+/// difference is small in either direction.
+pub(crate) fn ts_delta(ts: u64, base_ts: u64) -> u64 {
+    let delta = ts.wrapping_sub(base_ts) as i64;
+    ((delta << 1) ^ (delta >> 63)) as u64
+}
+
+/// The inverse of `ts_delta`.
+pub(crate) fn ts_from_delta(delta: u64, base_ts: u64) -> u64 {
+    let delta = ((delta >> 1) as i64) ^ -((delta & 1) as i64);
+    base_ts.wrapping_add(delta as u64)
+}
+
+/// The target ratio of keys to buckets in the hash index of a block.
+pub(crate) const HASH_INDEX_UTIL_RATIO: f64 = 0.75;
+/// The largest restart point index in a hash index bucket. Blocks with more restart points are
+/// built without a hash index.
+pub(crate) const HASH_INDEX_MAX_RESTART: usize = 253;
+/// A hash index bucket with a key of more than one restart point.
+pub(crate) const HASH_INDEX_COLLISION: u8 = 254;
+/// A hash index bucket without any key.
+pub(crate) const HASH_INDEX_EMPTY: u8 = 255;
+/// The bit in the number of restart points that marks a block with a hash index.
+const HASH_INDEX_FLAG: u16 = 1 << 15;
+
+/// The number of buckets in the hash index for `num_keys` user keys.
+pub(crate) fn hash_index_num_buckets(num_keys: usize) -> usize {
+    ((num_keys as f64 / HASH_INDEX_UTIL_RATIO) as usize + 1).min(u16::MAX as usize)
+}
+
+/// The bucket of `key` in a hash index of `num_buckets` buckets.
+pub(crate) fn hash_index_bucket(key: &[u8], num_buckets: usize) -> usize {
+    farmhash::fingerprint32(key) as usize % num_buckets
+}
+
 /// A block is the smallest unit of read and caching in LSM tree. It is a collection of sorted
 /// key-value pairs.
+///
+/// Each key is stored as the length of its prefix shared with the previous key and the rest of
+/// it. Every few entries there is a restart point, whose key is stored in full, so that a seek
+/// can binary search over the restart points and only decode the entries after one of them.
+///
+/// A block may have a hash index between the entries and the restart points, which maps the
+/// hash of a user key to the restart point before its first entry, so that a point lookup can
+/// skip the binary search.
 pub struct Block {
+    /// The encoded entries.
     pub(crate) data: Vec<u8>,
+    /// The offsets of the restart points in `data`.
     pub(crate) offsets: Vec<u16>,
+    /// The encoding of the entries in `data`.
+    pub(crate) format: BlockFormat,
+    /// The buckets of the hash index, each with the index of a restart point,
+    /// `HASH_INDEX_EMPTY` or `HASH_INDEX_COLLISION`.
+    pub(crate) hash_index: Option<Vec<u8>>,
 }
 
 impl Block {
     pub fn encode(&self) -> Bytes {
         let mut buf = self.data.clone();
-        let offsets_len = self.offsets.len();
+        let mut offsets_len = self.offsets.len() as u16;
+        if let Some(buckets) = &self.hash_index {
+            buf.put_slice(buckets);
+            buf.put_u16(buckets.len() as u16);
+            offsets_len |= HASH_INDEX_FLAG;
+        }
         for offset in &self.offsets {
             buf.put_u16(*offset);
         }
-        // Adds number of elements at the end of the block
-        buf.put_u16(offsets_len as u16);
+        // Adds number of restart points at the end of the block
+        buf.put_u16(offsets_len);
         buf.into()
     }
 
     pub fn decode(data: &[u8]) -> Self {
-        // get number of elements in the block
-        let entry_offsets_len = (&data[data.len() - SIZEOF_U16..]).get_u16() as usize;
-        let data_end = data.len() - SIZEOF_U16 - entry_offsets_len * SIZEOF_U16;
+        Self::decode_with_format(data, BlockFormat::Fixed)
+    }
+
+    pub fn decode_with_format(data: &[u8], format: BlockFormat) -> Self {
+        // get number of restart points in the block
+        let entry_offsets_len = (&data[data.len() - SIZEOF_U16..]).get_u16();
+        let has_hash_index = entry_offsets_len & HASH_INDEX_FLAG != 0;
+        let entry_offsets_len = (entry_offsets_len & !HASH_INDEX_FLAG) as usize;
+        let mut data_end = data.len() - SIZEOF_U16 - entry_offsets_len * SIZEOF_U16;
         let offsets_raw = &data[data_end..data.len() - SIZEOF_U16];
         // get offset array
         let offsets = offsets_raw
             .chunks(SIZEOF_U16)
             .map(|mut x| x.get_u16())
             .collect();
+        // get hash index
+        let hash_index = if has_hash_index {
+            let num_buckets = (&data[data_end - SIZEOF_U16..]).get_u16() as usize;
+            data_end -= SIZEOF_U16 + num_buckets;
+            Some(data[data_end..data_end + num_buckets].to_vec())
+        } else {
+            None
+        };
         // retrieve data
         let data = data[0..data_end].to_vec();
-        Self { data, offsets }
+        Self {
+            data,
+            offsets,
+            format,
+            hash_index,
+        }
+    }
+
+    /// Returns the restart point before the first entry of `key` in the hash index. The key may
+    /// be in the block even if there is none, when there is no hash index or on a collision, and
+    /// may not be in the block even if there is one.
+    pub(crate) fn probe_hash_index(&self, key: &[u8]) -> Option<usize> {
+        let buckets = self.hash_index.as_ref()?;
+        match buckets[hash_index_bucket(key, buckets.len())] {
+            HASH_INDEX_EMPTY | HASH_INDEX_COLLISION => None,
+            restart => Some(restart as usize),
+        }
     }
 }
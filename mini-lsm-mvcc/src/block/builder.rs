@@ -2,27 +2,47 @@ use bytes::BufMut;
 
 use crate::key::{KeySlice, KeyVec};
 
-use super::{Block, SIZEOF_U16};
+use super::{
+    hash_index_bucket, hash_index_num_buckets, put_varint, ts_delta, varint_len, Block,
+    BlockFormat, DEFAULT_RESTART_INTERVAL, HASH_INDEX_COLLISION, HASH_INDEX_EMPTY,
+    HASH_INDEX_MAX_RESTART, SIZEOF_U16,
+};
 
 /// Builds a block.
 pub struct BlockBuilder {
-    /// Offsets of each key-value entries.
+    /// Offsets of the restart points.
     offsets: Vec<u16>,
     /// All serialized key-value pairs in the block.
     data: Vec<u8>,
     /// The expected block size.
     block_size: usize,
-    /// The first key in the block
+    /// The number of entries between restart points.
+    restart_interval: usize,
+    /// The number of entries since the last restart point.
+    entries_since_restart: usize,
+    /// The last key in the block, which the next key is delta-encoded against.
+    last_key: KeyVec,
+    /// The first key in the block, which the keys are delta-encoded against in the legacy
+    /// format.
     first_key: KeyVec,
+    /// The encoding of the entries.
+    format: BlockFormat,
+    /// The ts of the first entry, which the ts of the other entries are delta-encoded against in
+    /// the varint format.
+    base_ts: u64,
+    /// Whether to build a hash index.
+    hash_index: bool,
+    /// The user keys in the block, with the restart point before their first entry.
+    hash_index_keys: Vec<(Vec<u8>, usize)>,
 }
 
-fn compute_overlap(first_key: KeySlice, key: KeySlice) -> usize {
+fn compute_overlap(last_key: KeySlice, key: KeySlice) -> usize {
     let mut i = 0;
     loop {
-        if i >= first_key.key_len() || i >= key.key_len() {
+        if i >= last_key.key_len() || i >= key.key_len() {
             break;
         }
-        if first_key.key_ref()[i] != key.key_ref()[i] {
+        if last_key.key_ref()[i] != key.key_ref()[i] {
             break;
         }
         i += 1;
@@ -33,54 +53,136 @@ fn compute_overlap(first_key: KeySlice, key: KeySlice) -> usize {
 impl BlockBuilder {
     /// Creates a new block builder.
     pub fn new(block_size: usize) -> Self {
+        Self::with_restart_interval(block_size, DEFAULT_RESTART_INTERVAL)
+    }
+
+    /// Creates a new block builder with a restart point every `restart_interval` entries.
+    pub fn with_restart_interval(block_size: usize, restart_interval: usize) -> Self {
         Self {
             offsets: Vec::new(),
             data: Vec::new(),
             block_size,
+            restart_interval: restart_interval.max(1),
+            entries_since_restart: 0,
+            last_key: KeyVec::new(),
             first_key: KeyVec::new(),
+            format: BlockFormat::Fixed,
+            base_ts: 0,
+            hash_index: false,
+            hash_index_keys: Vec::new(),
         }
     }
 
-    fn estimated_size(&self) -> usize {
-        SIZEOF_U16 /* number of key-value pairs in the block */ +  self.offsets.len() * SIZEOF_U16 /* offsets */ + self.data.len()
-        // key-value pairs
+    /// Encode the entries in `format`.
+    pub fn with_format(mut self, format: BlockFormat) -> Self {
+        assert!(self.is_empty(), "format set after adding keys");
+        self.format = format;
+        self
+    }
+
+    /// Build a hash index of the user keys for point lookups.
+    pub fn with_hash_index(mut self, hash_index: bool) -> Self {
+        assert!(self.is_empty(), "hash index set after adding keys");
+        self.hash_index = hash_index;
+        self
+    }
+
+    /// Whether a hash index is built, which the legacy format has none of.
+    fn builds_hash_index(&self) -> bool {
+        self.hash_index && self.format != BlockFormat::Legacy
+    }
+
+    fn hash_index_size(&self, num_keys: usize) -> usize {
+        if self.builds_hash_index() {
+            hash_index_num_buckets(num_keys) + SIZEOF_U16 /* number of buckets */
+        } else {
+            0
+        }
     }
 
     /// Adds a key-value pair to the block. Returns false when the block is full.
     #[must_use]
     pub fn add(&mut self, key: KeySlice, value: &[u8]) -> bool {
         assert!(!key.is_empty(), "key must not be empty");
-        if self.estimated_size() + key.raw_len() + value.len() + SIZEOF_U16 * 3 /* key_len, value_len and offset */ > self.block_size
-            && !self.is_empty()
-        {
+        let first_entry = self.is_empty();
+        let overlap = match self.format {
+            BlockFormat::Legacy => compute_overlap(self.first_key.as_key_slice(), key),
+            _ if first_entry || self.entries_since_restart >= self.restart_interval => 0,
+            _ => compute_overlap(self.last_key.as_key_slice(), key),
+        };
+        let restart = first_entry
+            || self.entries_since_restart >= self.restart_interval
+            || self.format == BlockFormat::Legacy;
+        let rest = &key.key_ref()[overlap..];
+        let new_user_key = first_entry || self.last_key.key_ref() != key.key_ref();
+        let entry_size = match self.format {
+            BlockFormat::Fixed | BlockFormat::Legacy => {
+                SIZEOF_U16 * 3 /* overlap, key_len and value_len */ + rest.len() + std::mem::size_of::<u64>() + value.len()
+            }
+            BlockFormat::Varint => {
+                varint_len(overlap as u64)
+                    + varint_len(rest.len() as u64)
+                    + varint_len(value.len() as u64)
+                    + rest.len()
+                    + varint_len(ts_delta(key.ts(), self.base_ts))
+                    + value.len()
+            }
+        };
+        let num_offsets = self.offsets.len() + restart as usize;
+        let num_hash_index_keys = self.hash_index_keys.len() + new_user_key as usize;
+        let size = SIZEOF_U16 /* number of restart points */ + num_offsets * SIZEOF_U16 /* restart points */ + self.data.len() + entry_size /* key-value pairs */ + self.hash_index_size(num_hash_index_keys);
+        if size > self.block_size && !first_entry {
             return false;
         }
-        // Add the offset of the data into the offset array.
-        self.offsets.push(self.data.len() as u16);
-        let overlap = compute_overlap(self.first_key.as_key_slice(), key);
-        // Encode key overlap.
-        self.data.put_u16(overlap as u16);
-        // Encode key length.
-        self.data.put_u16((key.key_len() - overlap) as u16);
-        // Encode key content.
-        self.data.put(&key.key_ref()[overlap..]);
-        // Encode key ts
-        self.data.put_u64(key.ts());
-        // Encode value length.
-        self.data.put_u16(value.len() as u16);
-        // Encode value content.
-        self.data.put(value);
-
-        if self.first_key.is_empty() {
-            self.first_key = key.to_key_vec();
+        if restart {
+            // Add the offset of the data into the restart points.
+            self.offsets.push(self.data.len() as u16);
+            self.entries_since_restart = 0;
+        }
+        self.entries_since_restart += 1;
+        match self.format {
+            BlockFormat::Fixed | BlockFormat::Legacy => {
+                // Encode key overlap.
+                self.data.put_u16(overlap as u16);
+                // Encode key length.
+                self.data.put_u16(rest.len() as u16);
+                // Encode key content.
+                self.data.put(rest);
+                // Encode key ts
+                self.data.put_u64(key.ts());
+                // Encode value length.
+                self.data.put_u16(value.len() as u16);
+                // Encode value content.
+                self.data.put(value);
+            }
+            BlockFormat::Varint => {
+                // Encode key overlap, key length and value length.
+                put_varint(&mut self.data, overlap as u64);
+                put_varint(&mut self.data, rest.len() as u64);
+                put_varint(&mut self.data, value.len() as u64);
+                self.data.put(rest);
+                // Encode key ts, where the first entry is encoded against ts 0.
+                put_varint(&mut self.data, ts_delta(key.ts(), self.base_ts));
+                self.data.put(value);
+            }
         }
+        if first_entry {
+            self.base_ts = key.ts();
+            self.first_key.set_from_slice(key);
+        }
+        if self.builds_hash_index() && new_user_key {
+            self.hash_index_keys
+                .push((key.key_ref().to_vec(), self.offsets.len() - 1));
+        }
+
+        self.last_key.set_from_slice(key);
 
         true
     }
 
     /// Check if there are no key-value pairs in the block.
     pub fn is_empty(&self) -> bool {
-        self.offsets.is_empty()
+        self.data.is_empty()
     }
 
     /// Finalize the block.
@@ -88,9 +190,27 @@ impl BlockBuilder {
         if self.is_empty() {
             panic!("block should not be empty");
         }
+        let hash_index = (self.builds_hash_index()
+            && self.offsets.len() <= HASH_INDEX_MAX_RESTART + 1)
+            .then(|| {
+                let num_buckets = hash_index_num_buckets(self.hash_index_keys.len());
+                let mut buckets = vec![HASH_INDEX_EMPTY; num_buckets];
+                for (key, restart) in &self.hash_index_keys {
+                    let bucket = &mut buckets[hash_index_bucket(key, num_buckets)];
+                    // keys of the same restart point can share a bucket
+                    *bucket = if *bucket == HASH_INDEX_EMPTY || *bucket == *restart as u8 {
+                        *restart as u8
+                    } else {
+                        HASH_INDEX_COLLISION
+                    };
+                }
+                buckets
+            });
         Block {
             data: self.data,
             offsets: self.offsets,
+            format: self.format,
+            hash_index,
         }
     }
 }
@@ -3,7 +3,7 @@ use std::sync::Arc;
 use bytes::Buf;
 
 use crate::{
-    block::SIZEOF_U16,
+    block::{get_varint, ts_from_delta, BlockFormat, SIZEOF_U16},
     key::{KeySlice, KeyVec},
 };
 
@@ -17,32 +17,39 @@ pub struct BlockIterator {
     key: KeyVec,
     /// the current value range in the block.data, corresponds to the current key
     value_range: (usize, usize),
-    /// the current index at the iterator position
-    idx: usize,
-    /// the first key in the block
+    /// the offset of the entry after the current one in the block.data
+    next_offset: usize,
+    /// the ts of the first entry in the block, which the other ts are delta-encoded against in
+    /// the varint format
+    base_ts: u64,
+    /// the first key in the block, which the other keys are delta-encoded against in the legacy
+    /// format
     first_key: KeyVec,
 }
 
-impl Block {
-    fn get_first_key(&self) -> KeyVec {
-        let mut buf = &self.data[..];
-        buf.get_u16();
-        let key_len = buf.get_u16() as usize;
-        let key = &buf[..key_len];
-        buf.advance(key_len);
-        KeyVec::from_vec_with_ts(key.to_vec(), buf.get_u64())
-    }
-}
-
 impl BlockIterator {
     fn new(block: Arc<Block>) -> Self {
-        Self {
-            first_key: block.get_first_key(),
+        let mut iter = Self {
             block,
             key: KeyVec::new(),
             value_range: (0, 0),
-            idx: 0,
+            next_offset: 0,
+            base_ts: 0,
+            first_key: KeyVec::new(),
+        };
+        match iter.block.format {
+            BlockFormat::Varint => {
+                iter.seek_to_offset(0);
+                iter.base_ts = iter.key.ts();
+                iter.key.clear();
+            }
+            BlockFormat::Legacy => {
+                iter.seek_to_offset(0);
+                iter.first_key = std::mem::take(&mut iter.key);
+            }
+            BlockFormat::Fixed => {}
         }
+        iter
     }
 
     /// Creates a block iterator and seek to the first entry.
@@ -59,6 +66,13 @@ impl BlockIterator {
         iter
     }
 
+    /// Creates a block iterator and seek to the first key that >= `key` for a point lookup.
+    pub fn create_and_seek_for_get(block: Arc<Block>, key: KeySlice) -> Self {
+        let mut iter = Self::new(block);
+        iter.seek_for_get(key);
+        iter
+    }
+
     /// Returns the key of the current entry.
     pub fn key(&self) -> KeySlice {
         debug_assert!(!self.key.is_empty(), "invalid iterator");
@@ -78,65 +92,108 @@ impl BlockIterator {
 
     /// Seeks to the first key in the block.
     pub fn seek_to_first(&mut self) {
-        self.seek_to(0);
+        self.seek_to_restart(0);
     }
 
-    /// Seeks to the idx-th key in the block.
-    fn seek_to(&mut self, idx: usize) {
-        if idx >= self.block.offsets.len() {
-            self.key.clear();
-            self.value_range = (0, 0);
-            return;
-        }
+    /// Seeks to the idx-th restart point in the block.
+    fn seek_to_restart(&mut self, idx: usize) {
         let offset = self.block.offsets[idx] as usize;
         self.seek_to_offset(offset);
-        self.idx = idx;
     }
 
     /// Move to the next key in the block.
     pub fn next(&mut self) {
-        self.idx += 1;
-        self.seek_to(self.idx);
+        if self.next_offset >= self.block.data.len() {
+            self.key.clear();
+            self.value_range = (0, 0);
+            return;
+        }
+        self.seek_to_offset(self.next_offset);
     }
 
-    /// Seek to the specified position and update the current `key` and `value`
-    /// Index update will be handled by caller
+    /// Decode the entry at the offset and update the current `key` and `value`. The current key
+    /// must be the previous key of the entry, unless the entry is a restart point.
     fn seek_to_offset(&mut self, offset: usize) {
         let mut entry = &self.block.data[offset..];
-        // Since `get_u16()` will automatically move the ptr 2 bytes ahead here,
-        // we don't need to manually advance it
-        let overlap_len = entry.get_u16() as usize;
-        let key_len = entry.get_u16() as usize;
-        let key = &entry[..key_len];
-        self.key.clear();
-        self.key.append(&self.first_key.key_ref()[..overlap_len]);
-        self.key.append(key);
-        entry.advance(key_len);
-        let ts = entry.get_u64();
-        self.key.set_ts(ts);
-        let value_len = entry.get_u16() as usize;
-        // REMEMBER TO CHANGE THIS every time you change the encoding!
-        let value_offset_begin =
-            offset + SIZEOF_U16 + SIZEOF_U16 + std::mem::size_of::<u64>() + key_len + SIZEOF_U16;
-        let value_offset_end = value_offset_begin + value_len;
-        self.value_range = (value_offset_begin, value_offset_end);
-        entry.advance(value_len);
+        match self.block.format {
+            BlockFormat::Fixed | BlockFormat::Legacy => {
+                // Since `get_u16()` will automatically move the ptr 2 bytes ahead here,
+                // we don't need to manually advance it
+                let overlap_len = entry.get_u16() as usize;
+                let key_len = entry.get_u16() as usize;
+                let key = &entry[..key_len];
+                if self.block.format == BlockFormat::Legacy {
+                    self.key.clear();
+                    self.key.append(&self.first_key.key_ref()[..overlap_len]);
+                } else {
+                    self.key.truncate(overlap_len);
+                }
+                self.key.append(key);
+                entry.advance(key_len);
+                let ts = entry.get_u64();
+                self.key.set_ts(ts);
+                let value_len = entry.get_u16() as usize;
+                // REMEMBER TO CHANGE THIS every time you change the encoding!
+                let value_offset_begin = offset
+                    + SIZEOF_U16
+                    + SIZEOF_U16
+                    + std::mem::size_of::<u64>()
+                    + key_len
+                    + SIZEOF_U16;
+                self.value_range = (value_offset_begin, value_offset_begin + value_len);
+            }
+            BlockFormat::Varint => {
+                let overlap_len = get_varint(&mut entry) as usize;
+                let key_len = get_varint(&mut entry) as usize;
+                let value_len = get_varint(&mut entry) as usize;
+                self.key.truncate(overlap_len);
+                self.key.append(&entry[..key_len]);
+                entry.advance(key_len);
+                // the first entry is encoded against ts 0
+                let base_ts = if offset == 0 { 0 } else { self.base_ts };
+                self.key
+                    .set_ts(ts_from_delta(get_varint(&mut entry), base_ts));
+                let value_offset_begin = self.block.data.len() - entry.len();
+                self.value_range = (value_offset_begin, value_offset_begin + value_len);
+            }
+        }
+        self.next_offset = self.value_range.1;
+    }
+
+    /// Seek to the first key that is >= `key`, like `seek_to_key`, for a point lookup of the user
+    /// key. The hash index of the block is probed first, and the binary search is only done when
+    /// the user key is not found with it.
+    pub fn seek_for_get(&mut self, key: KeySlice) {
+        if let Some(restart) = self.block.probe_hash_index(key.key_ref()) {
+            self.seek_to_restart(restart);
+            while self.is_valid() && self.key() < key {
+                self.next();
+            }
+            if self.is_valid() && self.key().key_ref() == key.key_ref() {
+                return;
+            }
+        }
+        self.seek_to_key(key);
     }
 
     /// Seek to the first key that is >= `key`.
     pub fn seek_to_key(&mut self, key: KeySlice) {
+        // find the first restart point whose key is >= `key`, and the key is in the entries after
+        // the restart point before it
         let mut low = 0;
         let mut high = self.block.offsets.len();
         while low < high {
             let mid = low + (high - low) / 2;
-            self.seek_to(mid);
-            assert!(self.is_valid());
+            self.seek_to_restart(mid);
             match self.key().cmp(&key) {
                 std::cmp::Ordering::Less => low = mid + 1,
                 std::cmp::Ordering::Greater => high = mid,
                 std::cmp::Ordering::Equal => return,
             }
         }
-        self.seek_to(low);
+        self.seek_to_restart(low.saturating_sub(1));
+        while self.is_valid() && self.key() < key {
+            self.next();
+        }
     }
 }
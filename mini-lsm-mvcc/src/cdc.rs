@@ -0,0 +1,274 @@
+//! Change data capture: committed writes in commit ts order, read from the memtables and the WAL
+//! files retained after flush, and a live subscription of new commits.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+use crossbeam_channel::{Receiver, Sender};
+use crossbeam_skiplist::SkipMap;
+use parking_lot::Mutex;
+
+use crate::error::{Error, IoResultExt, Result};
+use crate::fs::FileSystem;
+use crate::key::KeyBytes;
+use crate::lsm_storage::{LsmStorageOptions, WriteBatchRecord};
+use crate::wal::Wal;
+
+/// The writes committed at one commit ts, sorted by key.
+pub type WriteBatch = Vec<WriteBatchRecord<Bytes>>;
+
+struct ArchivedWal {
+    min_ts: Option<u64>,
+    max_ts: u64,
+    size: u64,
+    modified: SystemTime,
+}
+
+/// WAL files of flushed memtables that are retained for change data capture, and the subscribers
+/// of new commits.
+pub(crate) struct ChangeLog {
+    fs: Arc<dyn FileSystem>,
+    archive_dir: PathBuf,
+    ttl: Duration,
+    size_limit: u64,
+    /// Archived WAL id -> the WAL file info, guarded by the lock while reading the files.
+    archived_wals: Mutex<BTreeMap<usize, ArchivedWal>>,
+    /// Writes committed at or below this ts may no longer be retained.
+    purged_ts: AtomicU64,
+    subscribers: Mutex<Vec<Sender<(u64, WriteBatch)>>>,
+}
+
+/// Add the writes in a memtable (or a WAL read into a skiplist) committed after `since_ts` to
+/// `updates`.
+pub(crate) fn collect_updates(
+    map: &SkipMap<KeyBytes, Bytes>,
+    since_ts: u64,
+    updates: &mut BTreeMap<u64, WriteBatch>,
+) {
+    for entry in map.iter() {
+        let ts = entry.key().ts();
+        if ts <= since_ts {
+            continue;
+        }
+        let key = Bytes::copy_from_slice(entry.key().key_ref());
+        let record = if entry.value().is_empty() {
+            WriteBatchRecord::Del(key)
+        } else {
+            WriteBatchRecord::Put(key, entry.value().clone())
+        };
+        updates.entry(ts).or_default().push(record);
+    }
+}
+
+/// Sort the batches collected from several memtables by key. A batch can be split across
+/// memtables frozen while it was written, and a key written more than once in it keeps the write
+/// from the latest memtable, given that the memtables are collected from the oldest.
+pub(crate) fn sort_batches(updates: &mut BTreeMap<u64, WriteBatch>) {
+    fn key_of(record: &WriteBatchRecord<Bytes>) -> &Bytes {
+        match record {
+            WriteBatchRecord::Put(key, _) | WriteBatchRecord::Del(key) => key,
+        }
+    }
+    for batch in updates.values_mut() {
+        // the sort is stable, so the latest write of a key comes first and is kept
+        batch.reverse();
+        batch.sort_by(|a, b| key_of(a).cmp(key_of(b)));
+        batch.dedup_by(|a, b| key_of(a) == key_of(b));
+    }
+}
+
+/// The smallest and the largest commit ts in a memtable (or a WAL read into a skiplist).
+pub(crate) fn ts_range(map: &SkipMap<KeyBytes, Bytes>) -> Option<(u64, u64)> {
+    map.iter()
+        .map(|x| x.key().ts())
+        .fold(None, |range, ts| match range {
+            None => Some((ts, ts)),
+            Some((min_ts, max_ts)) => Some((min_ts.min(ts), max_ts.max(ts))),
+        })
+}
+
+impl ChangeLog {
+    pub fn archive_dir(path: impl AsRef<Path>) -> PathBuf {
+        path.as_ref().join("archive")
+    }
+
+    /// Load the archived WAL files in the DB dir and remove the expired ones.
+    pub fn open(path: &Path, options: &LsmStorageOptions) -> Result<Self> {
+        let fs = options.fs.clone();
+        let archive_dir = Self::archive_dir(path);
+        let mut archived_wals = BTreeMap::new();
+        if fs.exists(&archive_dir) {
+            for wal_path in fs.list_dir(&archive_dir)? {
+                let Some(id) = wal_path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .and_then(|name| name.strip_suffix(".wal"))
+                    .and_then(|id| id.parse::<usize>().ok())
+                else {
+                    continue;
+                };
+                let map = SkipMap::new();
+                Wal::read(fs.as_ref(), &wal_path, &map)?;
+                let metadata = fs.metadata(&wal_path)?;
+                let ts_range = ts_range(&map);
+                archived_wals.insert(
+                    id,
+                    ArchivedWal {
+                        min_ts: ts_range.map(|(min_ts, _)| min_ts),
+                        max_ts: ts_range.map(|(_, max_ts)| max_ts).unwrap_or_default(),
+                        size: metadata.len,
+                        modified: metadata.modified,
+                    },
+                );
+            }
+        }
+        let change_log = Self {
+            fs,
+            archive_dir,
+            ttl: Duration::from_secs(options.wal_ttl_seconds),
+            size_limit: options.wal_size_limit_mb << 20,
+            archived_wals: Mutex::new(archived_wals),
+            purged_ts: AtomicU64::new(0),
+            subscribers: Mutex::new(Vec::new()),
+        };
+        change_log.purge()?;
+        Ok(change_log)
+    }
+
+    /// The smallest commit ts in the archived WALs.
+    pub fn min_archived_ts(&self) -> Option<u64> {
+        self.archived_wals
+            .lock()
+            .values()
+            .filter_map(|x| x.min_ts)
+            .min()
+    }
+
+    fn retain_wals(&self) -> bool {
+        !self.ttl.is_zero() || self.size_limit != 0
+    }
+
+    pub fn purged_ts(&self) -> u64 {
+        self.purged_ts.load(Ordering::SeqCst)
+    }
+
+    /// Mark the writes committed at or below `ts` as no longer retained.
+    pub fn advance_purged_ts(&self, ts: u64) {
+        self.purged_ts.fetch_max(ts, Ordering::SeqCst);
+    }
+
+    /// Archive or remove the WAL of a flushed memtable, with the commit ts range of the memtable.
+    pub fn retire_wal(
+        &self,
+        wal_path: &Path,
+        id: usize,
+        ts_range: Option<(u64, u64)>,
+    ) -> Result<()> {
+        let min_ts = ts_range.map(|(min_ts, _)| min_ts);
+        let max_ts = ts_range.map(|(_, max_ts)| max_ts).unwrap_or_default();
+        if !self.retain_wals() {
+            self.fs.remove_file(wal_path)?;
+            self.advance_purged_ts(max_ts);
+            return Ok(());
+        }
+        self.fs
+            .create_dir_all(&self.archive_dir)
+            .context("failed to create WAL archive dir")?;
+        let archive_path = self.archive_dir.join(format!("{:05}.wal", id));
+        self.fs.rename(wal_path, &archive_path)?;
+        // persist the rename, so that the archived WAL does not vanish in a crash
+        self.fs.sync_dir(&self.archive_dir)?;
+        if let Some(dir) = wal_path.parent() {
+            self.fs.sync_dir(dir)?;
+        }
+        let metadata = self.fs.metadata(&archive_path)?;
+        self.archived_wals.lock().insert(
+            id,
+            ArchivedWal {
+                min_ts,
+                max_ts,
+                size: metadata.len,
+                modified: metadata.modified,
+            },
+        );
+        self.purge()
+    }
+
+    /// Remove the archived WALs that are older than the TTL, and the oldest ones until the total
+    /// size is within the limit.
+    fn purge(&self) -> Result<()> {
+        let mut archived_wals = self.archived_wals.lock();
+        let mut total_size = archived_wals.values().map(|x| x.size).sum::<u64>();
+        let now = SystemTime::now();
+        while let Some(entry) = archived_wals.first_entry() {
+            let wal = entry.get();
+            let expired = !self.ttl.is_zero()
+                && now.duration_since(wal.modified).unwrap_or_default() > self.ttl;
+            let oversized = self.size_limit != 0 && total_size > self.size_limit;
+            if !expired && !oversized {
+                break;
+            }
+            self.fs
+                .remove_file(&self.archive_dir.join(format!("{:05}.wal", entry.key())))?;
+            total_size -= wal.size;
+            self.advance_purged_ts(wal.max_ts);
+            entry.remove();
+        }
+        Ok(())
+    }
+
+    /// Add the writes in the archived WALs committed after `since_ts` to `updates`.
+    pub fn read_archived(
+        &self,
+        since_ts: u64,
+        updates: &mut BTreeMap<u64, WriteBatch>,
+    ) -> Result<()> {
+        let archived_wals = self.archived_wals.lock();
+        for (id, wal) in archived_wals.iter() {
+            if wal.max_ts <= since_ts {
+                continue;
+            }
+            let map = SkipMap::new();
+            Wal::read(
+                self.fs.as_ref(),
+                self.archive_dir.join(format!("{:05}.wal", id)),
+                &map,
+            )?;
+            collect_updates(&map, since_ts, updates);
+        }
+        Ok(())
+    }
+
+    /// Check that no write committed after `since_ts` has been removed.
+    pub fn check_retained(&self, since_ts: u64) -> Result<()> {
+        let purged_ts = self.purged_ts();
+        if since_ts < purged_ts {
+            return Err(Error::InvalidArgument(format!(
+                "updates since ts={} are not retained, writes up to ts={} have been removed",
+                since_ts, purged_ts
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn subscribe(&self) -> Receiver<(u64, WriteBatch)> {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        self.subscribers.lock().push(tx);
+        rx
+    }
+
+    pub fn has_subscribers(&self) -> bool {
+        !self.subscribers.lock().is_empty()
+    }
+
+    /// Send a committed write batch to the subscribers, and drop the ones that are gone.
+    pub fn publish(&self, ts: u64, batch: WriteBatch) {
+        self.subscribers
+            .lock()
+            .retain(|tx| tx.send((ts, batch.clone())).is_ok());
+    }
+}
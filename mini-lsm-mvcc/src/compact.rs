@@ -6,7 +6,6 @@ use std::collections::HashSet;
 use std::sync::Arc;
 use std::time::Duration;
 
-use anyhow::Result;
 pub use leveled::{LeveledCompactionController, LeveledCompactionOptions, LeveledCompactionTask};
 use serde::{Deserialize, Serialize};
 pub use simple_leveled::{
@@ -14,6 +13,7 @@ pub use simple_leveled::{
 };
 pub use tiered::{TieredCompactionController, TieredCompactionOptions, TieredCompactionTask};
 
+use crate::error::{Error, Result};
 use crate::iterators::concat_iterator::SstConcatIterator;
 use crate::iterators::merge_iterator::MergeIterator;
 use crate::iterators::two_merge_iterator::TwoMergeIterator;
@@ -21,6 +21,7 @@ use crate::iterators::StorageIterator;
 use crate::key::KeySlice;
 use crate::lsm_storage::{CompactionFilter, LsmStorageInner, LsmStorageState};
 use crate::manifest::ManifestRecord;
+use crate::options_file::PersistedOptions;
 use crate::table::{SsTable, SsTableBuilder, SsTableIterator};
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -53,6 +54,21 @@ pub(crate) enum CompactionController {
 }
 
 impl CompactionController {
+    pub fn new(compaction_options: &CompactionOptions) -> Self {
+        match compaction_options {
+            CompactionOptions::Leveled(options) => {
+                Self::Leveled(LeveledCompactionController::new(options.clone()))
+            }
+            CompactionOptions::Tiered(options) => {
+                Self::Tiered(TieredCompactionController::new(options.clone()))
+            }
+            CompactionOptions::Simple(options) => {
+                Self::Simple(SimpleLeveledCompactionController::new(options.clone()))
+            }
+            CompactionOptions::NoCompaction => Self::NoCompaction,
+        }
+    }
+
     pub fn generate_compaction_task(&self, snapshot: &LsmStorageState) -> Option<CompactionTask> {
         match self {
             CompactionController::Leveled(ctrl) => ctrl
@@ -64,7 +80,7 @@ impl CompactionController {
             CompactionController::Tiered(ctrl) => ctrl
                 .generate_compaction_task(snapshot)
                 .map(CompactionTask::Tiered),
-            CompactionController::NoCompaction => unreachable!(),
+            CompactionController::NoCompaction => None,
         }
     }
 
@@ -74,8 +90,8 @@ impl CompactionController {
         task: &CompactionTask,
         output: &[usize],
         in_recovery: bool,
-    ) -> (LsmStorageState, Vec<usize>) {
-        match (self, task) {
+    ) -> Result<(LsmStorageState, Vec<usize>)> {
+        let result = match (self, task) {
             (CompactionController::Leveled(ctrl), CompactionTask::Leveled(task)) => {
                 ctrl.apply_compaction_result(snapshot, task, output, in_recovery)
             }
@@ -85,8 +101,30 @@ impl CompactionController {
             (CompactionController::Tiered(ctrl), CompactionTask::Tiered(task)) => {
                 ctrl.apply_compaction_result(snapshot, task, output)
             }
-            _ => unreachable!(),
-        }
+            (
+                CompactionController::NoCompaction,
+                CompactionTask::ForceFullCompaction {
+                    l0_sstables,
+                    l1_sstables,
+                },
+            ) => {
+                let mut snapshot = snapshot.clone();
+                let l0_sstables_set = l0_sstables.iter().collect::<HashSet<_>>();
+                snapshot
+                    .l0_sstables
+                    .retain(|x| !l0_sstables_set.contains(x));
+                snapshot.levels[0].1 = output.to_vec();
+                let files_to_remove = l0_sstables.iter().chain(l1_sstables).copied().collect();
+                (snapshot, files_to_remove)
+            }
+            _ => {
+                return Err(Error::InvalidArgument(format!(
+                    "compaction task {:?} does not match the compaction strategy",
+                    task
+                )))
+            }
+        };
+        Ok(result)
     }
 }
 
@@ -99,7 +137,7 @@ impl CompactionController {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum CompactionOptions {
     /// Leveled compaction with partial compaction + dynamic level support (= RocksDB's Leveled
     /// Compaction)
@@ -117,18 +155,15 @@ impl LsmStorageInner {
         &self,
         mut iter: impl for<'a> StorageIterator<KeyType<'a> = KeySlice<'a>>,
         compact_to_bottom_level: bool,
+        output_level: usize,
     ) -> Result<Vec<Arc<SsTable>>> {
-        let mut builder = None;
+        let mut builder: Option<(usize, SsTableBuilder)> = None;
         let mut new_sst = Vec::new();
         let watermark = self.mvcc().watermark();
         let mut last_key = Vec::<u8>::new();
         let mut first_key_below_watermark = false;
         let compaction_filters = self.compaction_filters.lock().clone();
         'outer: while iter.is_valid() {
-            if builder.is_none() {
-                builder = Some(SsTableBuilder::new(self.options.block_size));
-            }
-
             let same_as_last_key = iter.key().key_ref() == last_key;
             if !same_as_last_key {
                 first_key_below_watermark = true;
@@ -168,21 +203,31 @@ impl LsmStorageInner {
                 }
             }
 
-            let builder_inner = builder.as_mut().unwrap();
-
-            if builder_inner.estimated_size() >= self.options.target_sst_size && !same_as_last_key {
+            if let Some((sst_id, builder_inner)) = &builder {
+                if builder_inner.estimated_size() >= self.options.target_sst_size
+                    && !same_as_last_key
+                {
+                    let sst_id = *sst_id;
+                    let (_, old_builder) = builder.take().unwrap();
+                    let sst = Arc::new(old_builder.build_in(
+                        self.options.fs.as_ref(),
+                        sst_id,
+                        Some(self.block_cache.clone()),
+                        self.path_of_sst(sst_id),
+                    )?);
+                    new_sst.push(sst);
+                }
+            }
+            // only create the builder when there is something to add, as all keys can be removed
+            if builder.is_none() {
                 let sst_id = self.next_sst_id();
-                let old_builder = builder.take().unwrap();
-                let sst = Arc::new(old_builder.build(
+                builder = Some((
                     sst_id,
-                    Some(self.block_cache.clone()),
-                    self.path_of_sst(sst_id),
-                )?);
-                new_sst.push(sst);
-                builder = Some(SsTableBuilder::new(self.options.block_size));
+                    self.new_sst_builder(sst_id, output_level, compact_to_bottom_level)?,
+                ));
             }
 
-            let builder_inner = builder.as_mut().unwrap();
+            let (_, builder_inner) = builder.as_mut().unwrap();
             builder_inner.add(iter.key(), iter.value());
 
             if !same_as_last_key {
@@ -192,9 +237,9 @@ impl LsmStorageInner {
 
             iter.next()?;
         }
-        if let Some(builder) = builder {
-            let sst_id = self.next_sst_id(); // lock dropped here
-            let sst = Arc::new(builder.build(
+        if let Some((sst_id, builder)) = builder {
+            let sst = Arc::new(builder.build_in(
+                self.options.fs.as_ref(),
                 sst_id,
                 Some(self.block_cache.clone()),
                 self.path_of_sst(sst_id),
@@ -228,19 +273,19 @@ impl LsmStorageInner {
                     MergeIterator::create(l0_iters),
                     SstConcatIterator::create_and_seek_to_first(l1_iters)?,
                 )?;
-                self.compact_generate_sst_from_iter(iter, task.compact_to_bottom_level())
+                self.compact_generate_sst_from_iter(iter, task.compact_to_bottom_level(), 1)
             }
             CompactionTask::Simple(SimpleLeveledCompactionTask {
                 upper_level,
                 upper_level_sst_ids,
-                lower_level: _,
+                lower_level,
                 lower_level_sst_ids,
                 ..
             })
             | CompactionTask::Leveled(LeveledCompactionTask {
                 upper_level,
                 upper_level_sst_ids,
-                lower_level: _,
+                lower_level,
                 lower_level_sst_ids,
                 ..
             }) => match upper_level {
@@ -258,6 +303,7 @@ impl LsmStorageInner {
                     self.compact_generate_sst_from_iter(
                         TwoMergeIterator::create(upper_iter, lower_iter)?,
                         task.compact_to_bottom_level(),
+                        *lower_level,
                     )
                 }
                 None => {
@@ -276,6 +322,7 @@ impl LsmStorageInner {
                     self.compact_generate_sst_from_iter(
                         TwoMergeIterator::create(upper_iter, lower_iter)?,
                         task.compact_to_bottom_level(),
+                        *lower_level,
                     )
                 }
             },
@@ -288,17 +335,27 @@ impl LsmStorageInner {
                     }
                     iters.push(Box::new(SstConcatIterator::create_and_seek_to_first(ssts)?));
                 }
+                // the merged tiers are replaced by the new tier in place
+                let output_level = snapshot
+                    .levels
+                    .iter()
+                    .position(|(tier_id, _)| *tier_id == tiers[0].0)
+                    .map_or(1, |idx| idx + 1);
                 self.compact_generate_sst_from_iter(
                     MergeIterator::create(iters),
                     task.compact_to_bottom_level(),
+                    output_level,
                 )
             }
         }
     }
 
     pub fn force_full_compaction(&self) -> Result<()> {
-        let CompactionOptions::NoCompaction = self.options.compaction_options else {
-            panic!("full compaction can only be called with compaction is not enabled")
+        let _compaction_lock = self.compaction_lock.lock();
+        let CompactionController::NoCompaction = *self.compaction_controller.read() else {
+            return Err(Error::InvalidArgument(
+                "full compaction can only be called with compaction is not enabled".to_string(),
+            ));
         };
 
         let snapshot = {
@@ -348,7 +405,7 @@ impl LsmStorageInner {
             )?;
         }
         for sst in l0_sstables.iter().chain(l1_sstables.iter()) {
-            std::fs::remove_file(self.path_of_sst(*sst))?;
+            self.options.fs.remove_file(&self.path_of_sst(*sst))?;
         }
 
         println!("force full compaction done, new SSTs: {:?}", ids);
@@ -356,14 +413,118 @@ impl LsmStorageInner {
         Ok(())
     }
 
+    /// Compact all SSTs into a single sorted run laid out for `compaction_options`, and switch to
+    /// the new strategy. SSTs flushed in the meantime are kept on top of the sorted run.
+    pub fn change_compaction_strategy(&self, compaction_options: CompactionOptions) -> Result<()> {
+        self.check_open()?;
+        let new_levels = LsmStorageState::empty_levels(&compaction_options);
+        if new_levels.is_empty() && !matches!(compaction_options, CompactionOptions::Tiered(_)) {
+            return Err(Error::InvalidArgument(
+                "max_levels must be at least 1".to_string(),
+            ));
+        }
+        let _compaction_lock = self.compaction_lock.lock();
+
+        let snapshot = {
+            let state = self.state.read();
+            state.clone()
+        };
+        let mut l0_iters = Vec::with_capacity(snapshot.l0_sstables.len());
+        for id in snapshot.l0_sstables.iter() {
+            l0_iters.push(Box::new(SsTableIterator::create_and_seek_to_first(
+                snapshot.sstables[id].clone(),
+            )?));
+        }
+        // every level or tier is a sorted run
+        let mut level_iters = Vec::with_capacity(snapshot.levels.len());
+        for (_, level_sst_ids) in snapshot.levels.iter() {
+            let ssts = level_sst_ids
+                .iter()
+                .map(|id| snapshot.sstables[id].clone())
+                .collect();
+            level_iters.push(Box::new(SstConcatIterator::create_and_seek_to_first(ssts)?));
+        }
+        let iter = TwoMergeIterator::create(
+            MergeIterator::create(l0_iters),
+            MergeIterator::create(level_iters),
+        )?;
+        println!("change compaction strategy to {:?}", compaction_options);
+        let sstables = self.compact_generate_sst_from_iter(iter, true, new_levels.len().max(1))?;
+        let output = sstables.iter().map(|x| x.sst_id()).collect::<Vec<_>>();
+        let compacted = snapshot
+            .l0_sstables
+            .iter()
+            .chain(snapshot.levels.iter().flat_map(|(_, files)| files))
+            .copied()
+            .collect::<HashSet<_>>();
+
+        let compaction_controller = CompactionController::new(&compaction_options);
+        {
+            let state_lock = self.state_lock.lock();
+            let mut state = self.state.read().as_ref().clone();
+            // the SSTs flushed in the meantime, newest first
+            let flushed = state
+                .l0_sstables
+                .iter()
+                .chain(state.levels.iter().flat_map(|(_, files)| files))
+                .filter(|id| !compacted.contains(id))
+                .copied()
+                .collect::<Vec<_>>();
+            state.l0_sstables = Vec::new();
+            state.levels = new_levels;
+            match &compaction_options {
+                CompactionOptions::Tiered(_) => {
+                    if let Some(first) = output.first() {
+                        state.levels.push((*first, output.clone()));
+                    }
+                }
+                _ => state.levels.last_mut().unwrap().1.clone_from(&output),
+            }
+            if compaction_controller.flush_to_l0() {
+                state.l0_sstables = flushed;
+            } else {
+                state
+                    .levels
+                    .splice(0..0, flushed.iter().map(|id| (*id, vec![*id])));
+            }
+            for id in &compacted {
+                state.sstables.remove(id);
+            }
+            for sst in sstables {
+                state.sstables.insert(sst.sst_id(), sst);
+            }
+            let record = ManifestRecord::ChangeCompactionStrategy {
+                options: compaction_options.clone(),
+                l0_sstables: state.l0_sstables.clone(),
+                levels: state.levels.clone(),
+            };
+            *self.state.write() = Arc::new(state);
+            *self.compaction_controller.write() = compaction_controller;
+            self.sync_dir()?;
+            self.manifest().add_record(&state_lock, record)?;
+        }
+
+        let mut options = PersistedOptions::new(&self.options);
+        options.compaction_options = compaction_options;
+        options.write(self.options.fs.as_ref(), &self.path)?;
+        for id in compacted {
+            self.options.fs.remove_file(&self.path_of_sst(id))?;
+        }
+        self.sync_dir()?;
+
+        println!("compaction strategy changed, new SSTs: {:?}", output);
+
+        Ok(())
+    }
+
     fn trigger_compaction(&self) -> Result<()> {
+        let _compaction_lock = self.compaction_lock.lock();
         let snapshot = {
             let state = self.state.read();
             state.clone()
         };
-        let task = self
-            .compaction_controller
-            .generate_compaction_task(&snapshot);
+        let compaction_controller = self.compaction_controller.read();
+        let task = compaction_controller.generate_compaction_task(&snapshot);
         let Some(task) = task else {
             return Ok(());
         };
@@ -380,9 +541,8 @@ impl LsmStorageInner {
                 let result = snapshot.sstables.insert(file_to_add.sst_id(), file_to_add);
                 assert!(result.is_none());
             }
-            let (mut snapshot, files_to_remove) = self
-                .compaction_controller
-                .apply_compaction_result(&snapshot, &task, &output, false);
+            let (mut snapshot, files_to_remove) =
+                compaction_controller.apply_compaction_result(&snapshot, &task, &output, false)?;
 
             let mut ssts_to_remove = Vec::with_capacity(files_to_remove.len());
             for file_to_remove in &files_to_remove {
@@ -405,7 +565,9 @@ impl LsmStorageInner {
             output
         );
         for sst in ssts_to_remove {
-            std::fs::remove_file(self.path_of_sst(sst.sst_id()))?;
+            self.options
+                .fs
+                .remove_file(&self.path_of_sst(sst.sst_id()))?;
         }
         self.sync_dir()?;
 
@@ -416,25 +578,20 @@ impl LsmStorageInner {
         self: &Arc<Self>,
         rx: crossbeam_channel::Receiver<()>,
     ) -> Result<Option<std::thread::JoinHandle<()>>> {
-        if let CompactionOptions::Leveled(_)
-        | CompactionOptions::Simple(_)
-        | CompactionOptions::Tiered(_) = self.options.compaction_options
-        {
-            let this = self.clone();
-            let handle = std::thread::spawn(move || {
-                let ticker = crossbeam_channel::tick(Duration::from_millis(50));
-                loop {
-                    crossbeam_channel::select! {
-                        recv(ticker) -> _ => if let Err(e) = this.trigger_compaction() {
-                            eprintln!("compaction failed: {}", e);
-                        },
-                        recv(rx) -> _ => return
-                    }
+        // spawned without compaction as well, as the strategy can be changed online
+        let this = self.clone();
+        let handle = std::thread::spawn(move || {
+            let ticker = crossbeam_channel::tick(Duration::from_millis(50));
+            loop {
+                crossbeam_channel::select! {
+                    recv(ticker) -> _ => if let Err(e) = this.trigger_compaction() {
+                        eprintln!("compaction failed: {}", e);
+                    },
+                    recv(rx) -> _ => return
                 }
-            });
-            return Ok(Some(handle));
-        }
-        Ok(None)
+            }
+        });
+        Ok(Some(handle))
     }
 
     fn trigger_flush(&self) -> Result<()> {
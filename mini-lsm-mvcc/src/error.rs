@@ -0,0 +1,140 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// The error type of the storage engine.
+#[derive(Debug)]
+pub enum Error {
+    /// The transaction conflicts with a concurrent one, and can be retried.
+    Conflict(String),
+    /// A file is corrupted at `offset`.
+    Corruption {
+        file: PathBuf,
+        offset: u64,
+        message: String,
+    },
+    Io(std::io::Error),
+    /// A lock cannot be acquired in time or without a deadlock. The operation can be retried.
+    Busy(String),
+    InvalidArgument(String),
+    /// The storage engine is closed.
+    Closed,
+    /// The database dir is opened by another storage engine, which holds the lock file.
+    Locked(PathBuf),
+    /// Errors from other sources, e.g., iterators.
+    Other(anyhow::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    pub(crate) fn corruption(
+        file: impl Into<PathBuf>,
+        offset: u64,
+        message: impl Into<String>,
+    ) -> Self {
+        Self::Corruption {
+            file: file.into(),
+            offset,
+            message: message.into(),
+        }
+    }
+
+    /// Locate a corruption found while decoding a part of a file that starts at `base_offset`.
+    pub(crate) fn in_file(self, file: &Path, base_offset: u64) -> Self {
+        match self {
+            Self::Corruption {
+                offset, message, ..
+            } => Self::Corruption {
+                file: file.to_path_buf(),
+                offset: base_offset + offset,
+                message,
+            },
+            e => e,
+        }
+    }
+
+    /// Copy an error shared by the block cache loaders. I/O errors keep their kind.
+    pub(crate) fn duplicate(&self) -> Self {
+        match self {
+            Self::Conflict(message) => Self::Conflict(message.clone()),
+            Self::Corruption {
+                file,
+                offset,
+                message,
+            } => Self::corruption(file, *offset, message.clone()),
+            Self::Io(e) => Self::Io(std::io::Error::new(e.kind(), e.to_string())),
+            Self::Busy(message) => Self::Busy(message.clone()),
+            Self::InvalidArgument(message) => Self::InvalidArgument(message.clone()),
+            Self::Closed => Self::Closed,
+            Self::Locked(path) => Self::Locked(path.clone()),
+            Self::Other(e) => Self::Other(anyhow::anyhow!("{:#}", e)),
+        }
+    }
+
+    /// Whether the operation may succeed if retried.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::Conflict(_) | Self::Busy(_))
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Conflict(message) => write!(f, "conflict: {}", message),
+            Self::Corruption {
+                file,
+                offset,
+                message,
+            } => write!(
+                f,
+                "corruption in {} at offset {}: {}",
+                file.display(),
+                offset,
+                message
+            ),
+            Self::Io(e) => write!(f, "io error: {}", e),
+            Self::Busy(message) => write!(f, "busy: {}", message),
+            Self::InvalidArgument(message) => write!(f, "invalid argument: {}", message),
+            Self::Closed => write!(f, "storage is closed"),
+            Self::Locked(path) => write!(f, "database is locked: {}", path.display()),
+            Self::Other(e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(e) => Some(e),
+            Self::Other(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    /// Errors of this crate that are passed through `anyhow` (e.g., by iterators) keep their type.
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<Error>() {
+            Ok(e) => e,
+            Err(e) => Self::Other(e),
+        }
+    }
+}
+
+/// Add a message to I/O errors, e.g., the file being operated on.
+pub(crate) trait IoResultExt<T> {
+    fn context(self, message: &str) -> Result<T>;
+}
+
+impl<T> IoResultExt<T> for std::io::Result<T> {
+    fn context(self, message: &str) -> Result<T> {
+        self.map_err(|e| Error::Io(std::io::Error::new(e.kind(), format!("{}: {}", message, e))))
+    }
+}
@@ -0,0 +1,794 @@
+//! The file system that all file I/O of the storage engine goes through, so that tests can run
+//! on an in-memory file system and inject faults such as crashes and failed writes.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use parking_lot::Mutex;
+
+/// A file opened for appending.
+pub trait WritableFile: Send {
+    fn append(&mut self, data: &[u8]) -> io::Result<()>;
+
+    /// Persist the data appended so far.
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+/// A file opened for positional reads.
+pub trait RandomAccessFile: Send + Sync {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+}
+
+/// An exclusive lock on a file, released when dropped.
+pub trait FileLock: Send + Sync {}
+
+pub struct FileMetadata {
+    pub len: u64,
+    pub modified: SystemTime,
+}
+
+pub trait FileSystem: Debug + Send + Sync {
+    /// Create a file for appending, truncating it if it exists.
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WritableFile>>;
+
+    /// Open an existing file for appending.
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn WritableFile>>;
+
+    fn open_random_access(&self, path: &Path) -> io::Result<Box<dyn RandomAccessFile>>;
+
+    /// Read the whole file.
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    fn metadata(&self, path: &Path) -> io::Result<FileMetadata>;
+
+    fn exists(&self, path: &Path) -> bool;
+
+    /// The paths of the entries in a directory.
+    fn list_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Persist the entries of a directory, i.e., the files created, removed or renamed in it.
+    fn sync_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Create the file if it does not exist and lock it exclusively. Fails with
+    /// `io::ErrorKind::WouldBlock` if the file is locked, by this process or another one.
+    fn lock_file(&self, path: &Path) -> io::Result<Box<dyn FileLock>>;
+}
+
+/// The suffix of the temporary files written by `write_file_atomic`.
+pub const TEMP_FILE_SUFFIX: &str = ".tmp";
+
+/// The temporary path a file is written to before it is renamed to `path`.
+pub fn temp_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(TEMP_FILE_SUFFIX);
+    path.with_file_name(file_name)
+}
+
+/// Write a file so that it either exists with all of `data` or not at all after a crash: the
+/// data is written to a temporary file and synced, which is then renamed to `path`, and the
+/// directory is synced.
+pub fn write_file_atomic(fs: &dyn FileSystem, path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut file = fs.create(&temp_path(path))?;
+    file.append(data)?;
+    file.sync()?;
+    drop(file);
+    rename_temp_file(fs, path)
+}
+
+/// Rename the synced temporary file of `path` to `path`, and sync the directory.
+pub fn rename_temp_file(fs: &dyn FileSystem, path: &Path) -> io::Result<()> {
+    fs.rename(&temp_path(path), path)?;
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => fs.sync_dir(dir),
+        _ => Ok(()),
+    }
+}
+
+/// The file system of the OS.
+#[derive(Debug, Default)]
+pub struct PosixFs;
+
+struct PosixWritableFile(BufWriter<File>);
+
+impl WritableFile for PosixWritableFile {
+    fn append(&mut self, data: &[u8]) -> io::Result<()> {
+        self.0.write_all(data)
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.0.flush()?;
+        self.0.get_mut().sync_all()
+    }
+}
+
+/// A `flock` on the file, released when the file is closed.
+struct PosixFileLock {
+    _file: File,
+}
+
+impl FileLock for PosixFileLock {}
+
+struct PosixRandomAccessFile(File);
+
+impl RandomAccessFile for PosixRandomAccessFile {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        use std::os::unix::fs::FileExt;
+        self.0.read_exact_at(buf, offset)
+    }
+}
+
+impl FileSystem for PosixFs {
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        Ok(Box::new(PosixWritableFile(BufWriter::new(file))))
+    }
+
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let file = OpenOptions::new().append(true).open(path)?;
+        Ok(Box::new(PosixWritableFile(BufWriter::new(file))))
+    }
+
+    fn open_random_access(&self, path: &Path) -> io::Result<Box<dyn RandomAccessFile>> {
+        Ok(Box::new(PosixRandomAccessFile(File::open(path)?)))
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        std::fs::read(path)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<FileMetadata> {
+        let metadata = std::fs::metadata(path)?;
+        Ok(FileMetadata {
+            len: metadata.len(),
+            modified: metadata.modified()?,
+        })
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn list_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        std::fs::read_dir(path)?
+            .map(|entry| Ok(entry?.path()))
+            .collect()
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        std::fs::rename(from, to)
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        File::open(path)?.sync_all()
+    }
+
+    fn lock_file(&self, path: &Path) -> io::Result<Box<dyn FileLock>> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        match file.try_lock() {
+            Ok(()) => Ok(Box::new(PosixFileLock { _file: file })),
+            Err(TryLockError::WouldBlock) => Err(locked(path)),
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+}
+
+struct MemFileData {
+    data: Vec<u8>,
+    modified: SystemTime,
+}
+
+type MemFile = Arc<Mutex<MemFileData>>;
+
+#[derive(Default)]
+struct MemFsState {
+    files: HashMap<PathBuf, MemFile>,
+    dirs: HashSet<PathBuf>,
+    locked: HashSet<PathBuf>,
+}
+
+/// A file system in memory. Everything written is considered persisted, use `FaultInjectionFs`
+/// on top of it to lose the unsynced data.
+#[derive(Default)]
+pub struct MemFs {
+    state: Arc<Mutex<MemFsState>>,
+}
+
+impl Debug for MemFs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MemFs").finish_non_exhaustive()
+    }
+}
+
+fn locked(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::WouldBlock,
+        format!("{} is locked", path.display()),
+    )
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("{} not found", path.display()),
+    )
+}
+
+impl MemFs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn get(&self, path: &Path) -> io::Result<MemFile> {
+        self.state
+            .lock()
+            .files
+            .get(path)
+            .cloned()
+            .ok_or_else(|| not_found(path))
+    }
+}
+
+impl MemFsState {
+    fn create_file(&mut self, path: &Path) -> io::Result<MemFile> {
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() && !self.dirs.contains(parent) {
+                return Err(not_found(parent));
+            }
+        }
+        let file = Arc::new(Mutex::new(MemFileData {
+            data: Vec::new(),
+            modified: SystemTime::now(),
+        }));
+        self.files.insert(path.to_path_buf(), file.clone());
+        Ok(file)
+    }
+}
+
+struct MemFileLock {
+    path: PathBuf,
+    state: Arc<Mutex<MemFsState>>,
+}
+
+impl FileLock for MemFileLock {}
+
+impl Drop for MemFileLock {
+    fn drop(&mut self) {
+        self.state.lock().locked.remove(&self.path);
+    }
+}
+
+struct MemWritableFile(MemFile);
+
+impl WritableFile for MemWritableFile {
+    fn append(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut file = self.0.lock();
+        file.data.extend_from_slice(data);
+        file.modified = SystemTime::now();
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct MemRandomAccessFile(MemFile);
+
+impl RandomAccessFile for MemRandomAccessFile {
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let file = self.0.lock();
+        let data = usize::try_from(offset)
+            .ok()
+            .and_then(|offset| file.data.get(offset..offset.checked_add(buf.len())?))
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer")
+            })?;
+        buf.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+impl FileSystem for MemFs {
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let file = self.state.lock().create_file(path)?;
+        Ok(Box::new(MemWritableFile(file)))
+    }
+
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        Ok(Box::new(MemWritableFile(self.get(path)?)))
+    }
+
+    fn open_random_access(&self, path: &Path) -> io::Result<Box<dyn RandomAccessFile>> {
+        Ok(Box::new(MemRandomAccessFile(self.get(path)?)))
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        Ok(self.get(path)?.lock().data.clone())
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<FileMetadata> {
+        let file = self.get(path)?;
+        let file = file.lock();
+        Ok(FileMetadata {
+            len: file.data.len() as u64,
+            modified: file.modified,
+        })
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        let state = self.state.lock();
+        state.files.contains_key(path) || state.dirs.contains(path)
+    }
+
+    fn list_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let state = self.state.lock();
+        if !state.dirs.contains(path) {
+            return Err(not_found(path));
+        }
+        Ok(state
+            .files
+            .keys()
+            .chain(state.dirs.iter())
+            .filter(|x| x.parent() == Some(path))
+            .cloned()
+            .collect())
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock();
+        for dir in path.ancestors() {
+            if !dir.as_os_str().is_empty() {
+                state.dirs.insert(dir.to_path_buf());
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.state
+            .lock()
+            .files
+            .remove(path)
+            .map(|_| ())
+            .ok_or_else(|| not_found(path))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state.lock();
+        let file = state.files.remove(from).ok_or_else(|| not_found(from))?;
+        state.files.insert(to.to_path_buf(), file);
+        Ok(())
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        if !self.state.lock().dirs.contains(path) {
+            return Err(not_found(path));
+        }
+        Ok(())
+    }
+
+    fn lock_file(&self, path: &Path) -> io::Result<Box<dyn FileLock>> {
+        let mut state = self.state.lock();
+        if !state.files.contains_key(path) {
+            state.create_file(path)?;
+        }
+        if !state.locked.insert(path.to_path_buf()) {
+            return Err(locked(path));
+        }
+        Ok(Box::new(MemFileLock {
+            path: path.to_path_buf(),
+            state: self.state.clone(),
+        }))
+    }
+}
+
+#[derive(Clone)]
+struct FaultFileState {
+    len: u64,
+    /// The length persisted by the last sync, `None` if neither the file nor its directory has
+    /// been synced since it was created.
+    synced_len: Option<u64>,
+}
+
+/// A file removed or replaced by a rename, restored in a crash.
+struct RemovedFile {
+    data: Vec<u8>,
+    /// `None` if the file was written before this file system was created, and is persisted.
+    state: Option<FaultFileState>,
+}
+
+enum DirOp {
+    /// `to` is replaced if it existed and was persisted.
+    Rename {
+        from: PathBuf,
+        to: PathBuf,
+        replaced: Option<RemovedFile>,
+    },
+    /// The file is `None` if it would not have been persisted anyway.
+    Remove {
+        path: PathBuf,
+        file: Option<RemovedFile>,
+    },
+}
+
+/// A rename or a removal, undone in a crash unless all the directories it changes are synced.
+struct PendingDirOp {
+    op: DirOp,
+    unsynced_dirs: Vec<PathBuf>,
+}
+
+#[derive(Default)]
+struct FaultState {
+    files: HashMap<PathBuf, FaultFileState>,
+    /// The renames and removals not persisted yet, in order.
+    dir_ops: Vec<PendingDirOp>,
+    /// The locks of the inner file system, released in a crash like the ones of an exited
+    /// process.
+    locks: HashMap<PathBuf, Box<dyn FileLock>>,
+    /// The number of writes that still succeed before the disk is full and all writes fail,
+    /// `None` for no limit.
+    writes_before_disk_full: Option<usize>,
+    crashed: bool,
+}
+
+impl FaultState {
+    /// Count a write (a create or an append), and fail it if the file system has crashed or the
+    /// disk is full.
+    fn check_write(&mut self) -> io::Result<()> {
+        self.check_crashed()?;
+        match &mut self.writes_before_disk_full {
+            Some(0) => Err(io::Error::new(
+                io::ErrorKind::StorageFull,
+                "injected write failure",
+            )),
+            Some(n) => {
+                *n -= 1;
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn check_crashed(&self) -> io::Result<()> {
+        if self.crashed {
+            return Err(io::Error::other("the file system has crashed"));
+        }
+        Ok(())
+    }
+}
+
+/// A wrapper of another file system that tracks the data synced to each file, to simulate a
+/// crash that loses the unsynced data, and can fail writes as if the disk is full.
+///
+/// A created file is lost in a crash unless the file or its directory is synced. Removals and
+/// renames are undone in a crash unless their directories are synced, so that a file renamed
+/// from a temporary file without syncing the directory is back at the temporary path.
+pub struct FaultInjectionFs {
+    inner: Arc<dyn FileSystem>,
+    state: Arc<Mutex<FaultState>>,
+}
+
+impl Debug for FaultInjectionFs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FaultInjectionFs")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FaultInjectionFs {
+    pub fn new(inner: Arc<dyn FileSystem>) -> Self {
+        Self {
+            inner,
+            state: Arc::new(Mutex::new(FaultState::default())),
+        }
+    }
+
+    /// Let the next `n` writes succeed, after which the disk is full: every later write fails
+    /// until `clear_write_failures`.
+    pub fn fill_disk_after_writes(&self, n: usize) {
+        self.state.lock().writes_before_disk_full = Some(n);
+    }
+
+    pub fn clear_write_failures(&self) {
+        self.state.lock().writes_before_disk_full = None;
+    }
+
+    /// Simulate a crash: the renames and removals in directories not synced are undone, the data
+    /// not synced is dropped from the files in the inner file system, and the created files not
+    /// synced are removed. All operations on this file system fail after the crash, including the
+    /// ones of the files opened before. Returns a new file system on top of the same inner file
+    /// system to restart with.
+    pub fn crash(&self) -> io::Result<Arc<FaultInjectionFs>> {
+        let mut state = self.state.lock();
+        state.crashed = true;
+        state.locks.clear();
+        for dir_op in std::mem::take(&mut state.dir_ops).into_iter().rev() {
+            match dir_op.op {
+                DirOp::Rename { from, to, replaced } => {
+                    self.inner.rename(&to, &from)?;
+                    if let Some(file) = state.files.remove(&to) {
+                        state.files.insert(from, file);
+                    }
+                    if let Some(file) = replaced {
+                        self.restore_file(&mut state, &to, file)?;
+                    }
+                }
+                DirOp::Remove { path, file } => {
+                    if let Some(file) = file {
+                        self.restore_file(&mut state, &path, file)?;
+                    }
+                }
+            }
+        }
+        for (path, file) in state.files.drain() {
+            match file.synced_len {
+                None => match self.inner.remove_file(&path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                },
+                Some(synced_len) if synced_len < file.len => {
+                    let data = self.inner.read(&path)?;
+                    let mut writer = self.inner.create(&path)?;
+                    writer.append(&data[..synced_len as usize])?;
+                    writer.sync()?;
+                }
+                Some(_) => {}
+            }
+        }
+        Ok(Arc::new(Self::new(self.inner.clone())))
+    }
+
+    /// The file at `path` to restore if it is removed, `None` if it would not be persisted anyway.
+    fn removed_file(&self, state: &FaultState, path: &Path) -> io::Result<Option<RemovedFile>> {
+        let file_state = state.files.get(path).cloned();
+        if file_state
+            .as_ref()
+            .is_some_and(|file| file.synced_len.is_none())
+        {
+            return Ok(None);
+        }
+        Ok(Some(RemovedFile {
+            data: self.inner.read(path)?,
+            state: file_state,
+        }))
+    }
+
+    fn restore_file(
+        &self,
+        state: &mut FaultState,
+        path: &Path,
+        file: RemovedFile,
+    ) -> io::Result<()> {
+        let mut writer = self.inner.create(path)?;
+        writer.append(&file.data)?;
+        writer.sync()?;
+        match file.state {
+            Some(file_state) => state.files.insert(path.to_path_buf(), file_state),
+            None => state.files.remove(path),
+        };
+        Ok(())
+    }
+
+    fn wrap(&self, path: &Path, file: Box<dyn WritableFile>) -> Box<dyn WritableFile> {
+        Box::new(FaultInjectionFile {
+            inner: file,
+            path: path.to_path_buf(),
+            state: self.state.clone(),
+        })
+    }
+}
+
+/// The directories of the paths, which are synced to persist the renames and removals in them.
+fn parent_dirs<'a>(paths: impl IntoIterator<Item = &'a Path>) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    for dir in paths.into_iter().filter_map(Path::parent) {
+        if !dirs.iter().any(|d: &PathBuf| d == dir) {
+            dirs.push(dir.to_path_buf());
+        }
+    }
+    dirs
+}
+
+struct FaultInjectionLock {
+    path: PathBuf,
+    state: Arc<Mutex<FaultState>>,
+}
+
+impl FileLock for FaultInjectionLock {}
+
+impl Drop for FaultInjectionLock {
+    fn drop(&mut self) {
+        self.state.lock().locks.remove(&self.path);
+    }
+}
+
+struct FaultInjectionFile {
+    inner: Box<dyn WritableFile>,
+    path: PathBuf,
+    state: Arc<Mutex<FaultState>>,
+}
+
+impl WritableFile for FaultInjectionFile {
+    fn append(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut state = self.state.lock();
+        state.check_write()?;
+        self.inner.append(data)?;
+        if let Some(file) = state.files.get_mut(&self.path) {
+            file.len += data.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        let mut state = self.state.lock();
+        state.check_crashed()?;
+        self.inner.sync()?;
+        if let Some(file) = state.files.get_mut(&self.path) {
+            file.synced_len = Some(file.len);
+        }
+        Ok(())
+    }
+}
+
+impl FileSystem for FaultInjectionFs {
+    fn create(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let mut state = self.state.lock();
+        state.check_write()?;
+        let file = self.inner.create(path)?;
+        state.files.insert(
+            path.to_path_buf(),
+            FaultFileState {
+                len: 0,
+                synced_len: None,
+            },
+        );
+        Ok(self.wrap(path, file))
+    }
+
+    fn open_append(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let mut state = self.state.lock();
+        state.check_crashed()?;
+        let file = self.inner.open_append(path)?;
+        if !state.files.contains_key(path) {
+            // the files written before are considered synced
+            let len = self.inner.metadata(path)?.len;
+            state.files.insert(
+                path.to_path_buf(),
+                FaultFileState {
+                    len,
+                    synced_len: Some(len),
+                },
+            );
+        }
+        Ok(self.wrap(path, file))
+    }
+
+    fn open_random_access(&self, path: &Path) -> io::Result<Box<dyn RandomAccessFile>> {
+        self.inner.open_random_access(path)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.inner.read(path)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<FileMetadata> {
+        self.inner.metadata(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn list_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        self.inner.list_dir(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        self.state.lock().check_crashed()?;
+        self.inner.create_dir_all(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock();
+        state.check_crashed()?;
+        let file = if self.inner.exists(path) {
+            self.removed_file(&state, path)?
+        } else {
+            None
+        };
+        self.inner.remove_file(path)?;
+        state.files.remove(path);
+        state.dir_ops.push(PendingDirOp {
+            op: DirOp::Remove {
+                path: path.to_path_buf(),
+                file,
+            },
+            unsynced_dirs: parent_dirs([path]),
+        });
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state.lock();
+        state.check_crashed()?;
+        let replaced = if self.inner.exists(to) {
+            self.removed_file(&state, to)?
+        } else {
+            None
+        };
+        self.inner.rename(from, to)?;
+        state.files.remove(to);
+        if let Some(file) = state.files.remove(from) {
+            state.files.insert(to.to_path_buf(), file);
+        }
+        state.dir_ops.push(PendingDirOp {
+            op: DirOp::Rename {
+                from: from.to_path_buf(),
+                to: to.to_path_buf(),
+                replaced,
+            },
+            unsynced_dirs: parent_dirs([from, to]),
+        });
+        Ok(())
+    }
+
+    fn sync_dir(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.lock();
+        state.check_crashed()?;
+        self.inner.sync_dir(path)?;
+        for dir_op in &mut state.dir_ops {
+            dir_op.unsynced_dirs.retain(|dir| dir != path);
+        }
+        state
+            .dir_ops
+            .retain(|dir_op| !dir_op.unsynced_dirs.is_empty());
+        // the files in the directory now exist after a crash, though their data may not
+        for (_, file) in state
+            .files
+            .iter_mut()
+            .filter(|(file_path, _)| file_path.parent() == Some(path))
+        {
+            file.synced_len.get_or_insert(0);
+        }
+        Ok(())
+    }
+
+    fn lock_file(&self, path: &Path) -> io::Result<Box<dyn FileLock>> {
+        let mut state = self.state.lock();
+        state.check_crashed()?;
+        let lock = self.inner.lock_file(path)?;
+        state.locks.insert(path.to_path_buf(), lock);
+        Ok(Box::new(FaultInjectionLock {
+            path: path.to_path_buf(),
+            state: self.state.clone(),
+        }))
+    }
+}
@@ -50,6 +50,20 @@ impl SstConcatIterator {
     }
 
     pub fn create_and_seek_to_key(sstables: Vec<Arc<SsTable>>, key: KeySlice) -> Result<Self> {
+        Self::seek_to_key_inner(sstables, key, false)
+    }
+
+    /// Seek to the first key-value pair which >= `key` for a point lookup of the user key, with
+    /// the hash index of the blocks.
+    pub fn create_and_seek_for_get(sstables: Vec<Arc<SsTable>>, key: KeySlice) -> Result<Self> {
+        Self::seek_to_key_inner(sstables, key, true)
+    }
+
+    fn seek_to_key_inner(
+        sstables: Vec<Arc<SsTable>>,
+        key: KeySlice,
+        for_get: bool,
+    ) -> Result<Self> {
         Self::check_sst_valid(&sstables);
         let idx: usize = sstables
             .partition_point(|table| table.first_key().as_key_slice() <= key)
@@ -62,10 +76,11 @@ impl SstConcatIterator {
             });
         }
         let mut iter = Self {
-            current: Some(SsTableIterator::create_and_seek_to_key(
-                sstables[idx].clone(),
-                key,
-            )?),
+            current: Some(if for_get {
+                SsTableIterator::create_and_seek_for_get(sstables[idx].clone(), key)?
+            } else {
+                SsTableIterator::create_and_seek_to_key(sstables[idx].clone(), key)?
+            }),
             next_sst_idx: idx + 1,
             sstables,
         };
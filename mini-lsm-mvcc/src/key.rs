@@ -61,6 +61,11 @@ impl Key<Vec<u8>> {
         self.0.extend(data)
     }
 
+    /// Keep the first `len` bytes of the key.
+    pub fn truncate(&mut self, len: usize) {
+        self.0.truncate(len)
+    }
+
     pub fn set_ts(&mut self, ts: u64) {
         self.1 = ts;
     }
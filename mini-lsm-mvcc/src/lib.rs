@@ -1,6 +1,9 @@
 pub mod block;
+pub mod cdc;
 pub mod compact;
 pub mod debug;
+pub mod error;
+pub mod fs;
 pub mod iterators;
 pub mod key;
 pub mod lsm_iterator;
@@ -8,6 +11,7 @@ pub mod lsm_storage;
 pub mod manifest;
 pub mod mem_table;
 pub mod mvcc;
+pub mod options_file;
 pub mod table;
 pub mod wal;
 
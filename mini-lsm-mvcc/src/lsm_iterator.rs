@@ -37,22 +37,27 @@ impl LsmIterator {
             read_ts,
             prev_key: Vec::new(),
         };
+        iter.check_end_bound();
         iter.move_to_key()?;
         Ok(iter)
     }
 
     fn next_inner(&mut self) -> Result<()> {
         self.inner.next()?;
+        self.check_end_bound();
+        Ok(())
+    }
+
+    fn check_end_bound(&mut self) {
         if !self.inner.is_valid() {
             self.is_valid = false;
-            return Ok(());
+            return;
         }
         match self.end_bound.as_ref() {
             Bound::Unbounded => {}
             Bound::Included(key) => self.is_valid = self.inner.key().key_ref() <= key.as_ref(),
             Bound::Excluded(key) => self.is_valid = self.inner.key().key_ref() < key.as_ref(),
         }
-        Ok(())
     }
 
     fn move_to_key(&mut self) -> Result<()> {
@@ -1,19 +1,21 @@
-use std::collections::{BTreeSet, HashMap};
-use std::fs::File;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::ops::Bound;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
-use anyhow::{Context, Result};
 use bytes::Bytes;
 use parking_lot::{Mutex, MutexGuard, RwLock};
 
-use crate::block::Block;
+use crate::block::{Block, BlockFormat};
+use crate::cdc::{collect_updates, sort_batches, ts_range, ChangeLog, WriteBatch};
 use crate::compact::{
-    CompactionController, CompactionOptions, LeveledCompactionController, LeveledCompactionOptions,
-    SimpleLeveledCompactionController, SimpleLeveledCompactionOptions, TieredCompactionController,
+    CompactionController, CompactionOptions, LeveledCompactionOptions,
+    SimpleLeveledCompactionOptions,
 };
+use crate::error::{Error, IoResultExt, Result};
+use crate::fs::{FileLock, FileSystem, PosixFs, TEMP_FILE_SUFFIX};
 use crate::iterators::concat_iterator::SstConcatIterator;
 use crate::iterators::merge_iterator::MergeIterator;
 use crate::iterators::two_merge_iterator::TwoMergeIterator;
@@ -21,12 +23,58 @@ use crate::iterators::StorageIterator;
 use crate::key::{self, KeySlice};
 use crate::lsm_iterator::{FusedIterator, LsmIterator};
 use crate::manifest::{Manifest, ManifestRecord};
-use crate::mem_table::{map_bound, map_key_bound_plus_ts, MemTable};
+use crate::mem_table::{map_bound, map_lower_key_bound, map_upper_key_bound, MemTable};
+use crate::mvcc::pessimistic_txn::PessimisticTransaction;
+use crate::mvcc::snapshot::Snapshot;
 use crate::mvcc::txn::{Transaction, TxnIterator};
-use crate::mvcc::LsmMvccInner;
-use crate::table::{FileObject, SsTable, SsTableBuilder, SsTableIterator};
+use crate::mvcc::{IsolationLevel, LsmMvccInner, PreparedTxnData};
+use crate::options_file::PersistedOptions;
+use crate::table::filter::Filter;
+use crate::table::index::{IndexPartition, TopLevelIndex};
+use crate::table::{
+    FileObject, FilterPolicy, SsTable, SsTableBuilder, SsTableIterator, SstPinning,
+};
+use crate::wal::PreparedLog;
+
+/// How far ahead of a commit ts the storage records in the manifest, see
+/// `LsmStorageInner::reserve_ts`.
+const COMMIT_TS_RESERVATION: u64 = 1000;
+
+/// A block in the block cache.
+#[derive(Clone)]
+pub enum CachedBlock {
+    Data(Arc<Block>),
+    IndexPartition(Arc<IndexPartition>),
+    TopLevelIndex(Arc<TopLevelIndex>),
+    Filter(Arc<Filter>),
+}
 
-pub type BlockCache = moka::sync::Cache<(usize, usize), Arc<Block>>;
+impl CachedBlock {
+    /// The memory charged to the block cache for the block.
+    pub fn charge(&self) -> usize {
+        match self {
+            CachedBlock::Data(block) => {
+                block.data.len()
+                    + block.offsets.len() * std::mem::size_of::<u16>()
+                    + block.hash_index.as_ref().map_or(0, |buckets| buckets.len())
+            }
+            CachedBlock::IndexPartition(partition) => partition.memory_size(),
+            CachedBlock::TopLevelIndex(index) => index.memory_size(),
+            CachedBlock::Filter(filter) => filter.memory_size(),
+        }
+    }
+}
+
+/// The block cache, keyed by the SST id and the offset of the block in the SST.
+pub type BlockCache = moka::sync::Cache<(usize, usize), CachedBlock>;
+
+/// Create a block cache that holds blocks of up to `capacity` bytes in total.
+pub fn new_block_cache(capacity: u64) -> BlockCache {
+    moka::sync::Cache::builder()
+        .max_capacity(capacity)
+        .weigher(|_, block: &CachedBlock| block.charge().try_into().unwrap_or(u32::MAX))
+        .build()
+}
 
 /// Represents the state of the storage engine.
 #[derive(Clone)]
@@ -44,14 +92,50 @@ pub struct LsmStorageState {
     pub sstables: HashMap<usize, Arc<SsTable>>,
 }
 
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum WriteBatchRecord<T: AsRef<[u8]>> {
     Put(T, T),
     Del(T),
 }
 
+/// Check a key and a value (`None` for deletes) to be written. An empty value is a tombstone, and
+/// the lengths are encoded as u16 in the WAL and the SSTs.
+pub(crate) fn check_key_value(key: &[u8], value: Option<&[u8]>) -> Result<()> {
+    if key.is_empty() {
+        return Err(Error::InvalidArgument("key cannot be empty".to_string()));
+    }
+    if key.len() > u16::MAX as usize {
+        return Err(Error::InvalidArgument(format!(
+            "key length {} exceeds {}",
+            key.len(),
+            u16::MAX
+        )));
+    }
+    match value {
+        Some([]) => Err(Error::InvalidArgument("value cannot be empty".to_string())),
+        Some(value) if value.len() > u16::MAX as usize => Err(Error::InvalidArgument(format!(
+            "value length {} exceeds {}",
+            value.len(),
+            u16::MAX
+        ))),
+        _ => Ok(()),
+    }
+}
+
 impl LsmStorageState {
     fn create(options: &LsmStorageOptions) -> Self {
-        let levels = match &options.compaction_options {
+        Self {
+            memtable: Arc::new(MemTable::create(0)),
+            imm_memtables: Vec::new(),
+            l0_sstables: Vec::new(),
+            levels: Self::empty_levels(&options.compaction_options),
+            sstables: Default::default(),
+        }
+    }
+
+    /// The levels of an empty LSM tree with the given compaction strategy.
+    pub(crate) fn empty_levels(compaction_options: &CompactionOptions) -> Vec<(usize, Vec<usize>)> {
+        match compaction_options {
             CompactionOptions::Leveled(LeveledCompactionOptions { max_levels, .. })
             | CompactionOptions::Simple(SimpleLeveledCompactionOptions { max_levels, .. }) => (1
                 ..=*max_levels)
@@ -59,13 +143,6 @@ impl LsmStorageState {
                 .collect::<Vec<_>>(),
             CompactionOptions::Tiered(_) => Vec::new(),
             CompactionOptions::NoCompaction => vec![(1, Vec::new())],
-        };
-        Self {
-            memtable: Arc::new(MemTable::create(0)),
-            imm_memtables: Vec::new(),
-            l0_sstables: Vec::new(),
-            levels,
-            sstables: Default::default(),
         }
     }
 }
@@ -80,10 +157,97 @@ pub struct LsmStorageOptions {
     pub num_memtable_limit: usize,
     pub compaction_options: CompactionOptions,
     pub enable_wal: bool,
+    // Default isolation level of `new_txn` and single writes; other levels can be chosen per
+    // transaction with `new_txn_with_isolation`
     pub serializable: bool,
+    // Number of commit timestamps below the latest one that stay readable with `get_at`. The
+    // watermark never advances past `latest_commit_ts - history_retention`.
+    pub history_retention: u64,
+    // Maximum time a pessimistic transaction waits for a row lock
+    pub lock_timeout: Duration,
+    // Keep the WAL of a flushed memtable in the archive dir for this many seconds, so that its
+    // writes can still be read by `updates_since`. WALs are removed on flush if both this and
+    // `wal_size_limit_mb` are 0.
+    pub wal_ttl_seconds: u64,
+    // Remove the oldest archived WALs when their total size exceeds this limit, 0 for no limit
+    pub wal_size_limit_mb: u64,
+    // The file system that all files of the storage engine are read and written through
+    pub fs: Arc<dyn FileSystem>,
+    // Delete the `.sst` and `.wal` files not referenced by the recovered state and the leftover
+    // temporary files on open. When disabled, they are only reported by `MiniLsm::orphan_files`.
+    pub delete_orphan_files: bool,
+    // Number of entries between the restart points of a block, where a key is stored in full
+    // instead of delta-encoded against the previous key
+    pub block_restart_interval: usize,
+    // The encoding of the entries in the blocks of new SSTs. The SSTs in the other format stay
+    // readable.
+    pub block_format: BlockFormat,
+    // Append a hash index of the user keys to the blocks of new SSTs, so that `get` can find a
+    // key in a block without a binary search
+    pub block_hash_index: bool,
+    // Split the index of new SSTs into partitions of about this many bytes, which are loaded on
+    // demand through the block cache, instead of keeping all block meta in memory. 0 for a flat
+    // index.
+    pub index_partition_size: usize,
+    // Keep the top-level index of a partitioned index in memory instead of loading it through
+    // the block cache
+    pub pin_top_level_index: bool,
+    // Capacity of the block cache in bytes, shared by data blocks, index partitions and the
+    // filters and top-level indexes that are not pinned
+    pub block_cache_size: u64,
+    // Load the bloom filters of SSTs on demand through the block cache, where they are evicted
+    // under memory pressure, instead of keeping them in memory
+    pub cache_filters: bool,
+    // With `cache_filters`, keep the filters of the SSTs written to L0 and the upper levels, up
+    // to this many levels counting L0, in memory. 0 for none.
+    pub pinned_filter_levels: usize,
+    // How the filters of the SSTs are built, unless overridden for the level
+    pub filter_policy: FilterPolicy,
+    // The filter policies of the first levels, where L0 is level 0, over `filter_policy`
+    pub level_filter_policies: Vec<FilterPolicy>,
+    // Build no filters for the SSTs written to the bottom level, where most lookups of existing
+    // keys end, so that the filters take much less memory
+    pub skip_bottom_level_filters: bool,
 }
 
 impl LsmStorageOptions {
+    /// The parts of the meta to pin in memory for an SST in the level, where L0 is level 0.
+    pub(crate) fn sst_pinning(&self, level: usize) -> SstPinning {
+        SstPinning {
+            top_level_index: self.pin_top_level_index,
+            filter: !self.cache_filters || level < self.pinned_filter_levels,
+        }
+    }
+
+    /// Check the options that would make the storage fail later, when it is opened.
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.block_restart_interval == 0 {
+            return Err(Error::InvalidArgument(
+                "block restart interval must be positive".to_string(),
+            ));
+        }
+        if self.lock_timeout.is_zero() {
+            return Err(Error::InvalidArgument(
+                "lock timeout must be positive".to_string(),
+            ));
+        }
+        for policy in std::iter::once(&self.filter_policy).chain(&self.level_filter_policies) {
+            policy.validate()?;
+        }
+        Ok(())
+    }
+
+    /// The filter policy of an SST in the level, where L0 is level 0.
+    pub(crate) fn filter_policy(&self, level: usize, bottom_level: bool) -> FilterPolicy {
+        if bottom_level && self.skip_bottom_level_filters {
+            return FilterPolicy::None;
+        }
+        self.level_filter_policies
+            .get(level)
+            .copied()
+            .unwrap_or(self.filter_policy)
+    }
+
     pub fn default_for_week1_test() -> Self {
         Self {
             block_size: 4096,
@@ -92,6 +256,23 @@ impl LsmStorageOptions {
             enable_wal: false,
             num_memtable_limit: 50,
             serializable: false,
+            history_retention: 0,
+            lock_timeout: Duration::from_secs(1),
+            wal_ttl_seconds: 0,
+            wal_size_limit_mb: 0,
+            fs: Arc::new(PosixFs),
+            delete_orphan_files: true,
+            block_restart_interval: 16,
+            block_format: BlockFormat::Fixed,
+            block_hash_index: false,
+            index_partition_size: 0,
+            pin_top_level_index: true,
+            block_cache_size: 4 << 30, // 4GB
+            cache_filters: false,
+            pinned_filter_levels: 1,
+            filter_policy: FilterPolicy::default(),
+            level_filter_policies: Vec::new(),
+            skip_bottom_level_filters: false,
         }
     }
 
@@ -103,6 +284,23 @@ impl LsmStorageOptions {
             enable_wal: false,
             num_memtable_limit: 2,
             serializable: false,
+            history_retention: 0,
+            lock_timeout: Duration::from_secs(1),
+            wal_ttl_seconds: 0,
+            wal_size_limit_mb: 0,
+            fs: Arc::new(PosixFs),
+            delete_orphan_files: true,
+            block_restart_interval: 16,
+            block_format: BlockFormat::Fixed,
+            block_hash_index: false,
+            index_partition_size: 0,
+            pin_top_level_index: true,
+            block_cache_size: 4 << 30, // 4GB
+            cache_filters: false,
+            pinned_filter_levels: 1,
+            filter_policy: FilterPolicy::default(),
+            level_filter_policies: Vec::new(),
+            skip_bottom_level_filters: false,
         }
     }
 
@@ -114,6 +312,23 @@ impl LsmStorageOptions {
             enable_wal: false,
             num_memtable_limit: 2,
             serializable: false,
+            history_retention: 0,
+            lock_timeout: Duration::from_secs(1),
+            wal_ttl_seconds: 0,
+            wal_size_limit_mb: 0,
+            fs: Arc::new(PosixFs),
+            delete_orphan_files: true,
+            block_restart_interval: 16,
+            block_format: BlockFormat::Fixed,
+            block_hash_index: false,
+            index_partition_size: 0,
+            pin_top_level_index: true,
+            block_cache_size: 4 << 30, // 4GB
+            cache_filters: false,
+            pinned_filter_levels: 1,
+            filter_policy: FilterPolicy::default(),
+            level_filter_policies: Vec::new(),
+            skip_bottom_level_filters: false,
         }
     }
 }
@@ -154,18 +369,53 @@ pub enum CompactionFilter {
     Prefix(Bytes),
 }
 
+/// A `.sst` or `.wal` file in the DB dir that the recovered state does not refer to, or a
+/// temporary file, left by a crash in the middle of a flush or a compaction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OrphanFile {
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// The memory of the filters and indexes of the SSTs, in bytes. The pinned ones are held by the
+/// SSTs, and the cached ones are in the block cache, where they are charged to its capacity.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub pinned_filters: usize,
+    pub cached_filters: usize,
+    /// The flat block meta and the pinned top-level indexes.
+    pub pinned_indexes: usize,
+    /// The index partitions and the top-level indexes in the block cache.
+    pub cached_indexes: usize,
+    /// The data blocks in the block cache.
+    pub cached_data_blocks: usize,
+}
+
 /// The storage interface of the LSM tree.
 pub(crate) struct LsmStorageInner {
     pub(crate) state: Arc<RwLock<Arc<LsmStorageState>>>,
     pub(crate) state_lock: Mutex<()>,
-    path: PathBuf,
+    pub(crate) path: PathBuf,
     pub(crate) block_cache: Arc<BlockCache>,
     next_sst_id: AtomicUsize,
     pub(crate) options: Arc<LsmStorageOptions>,
-    pub(crate) compaction_controller: CompactionController,
+    /// The controller of the current compaction strategy. It is replaced when the strategy is
+    /// changed online, after which `options.compaction_options` is outdated.
+    pub(crate) compaction_controller: RwLock<CompactionController>,
+    /// Held by compactions for their whole run, so that the strategy is not changed in between.
+    pub(crate) compaction_lock: Mutex<()>,
     pub(crate) manifest: Option<Manifest>,
     pub(crate) mvcc: Option<LsmMvccInner>,
     pub(crate) compaction_filters: Arc<Mutex<Vec<CompactionFilter>>>,
+    pub(crate) change_log: ChangeLog,
+    /// Set when the storage is closed, after which reads and writes fail with `Error::Closed`.
+    closed: AtomicBool,
+    /// The lock on the LOCK file in the DB dir, held until the storage is closed or dropped so
+    /// that the dir is not opened twice.
+    lock: Mutex<Option<Box<dyn FileLock>>>,
+    /// The orphan files found on open, which are already deleted unless
+    /// `options.delete_orphan_files` is disabled.
+    orphan_files: Vec<OrphanFile>,
 }
 
 /// A thin wrapper for `LsmStorageInner` and the user interface for MiniLSM.
@@ -185,11 +435,20 @@ impl Drop for MiniLsm {
     fn drop(&mut self) {
         self.compaction_notifier.send(()).ok();
         self.flush_notifier.send(()).ok();
+        // wait for the background threads to stop writing before releasing the lock
+        if let Some(compaction_thread) = self.compaction_thread.get_mut().take() {
+            compaction_thread.join().ok();
+        }
+        if let Some(flush_thread) = self.flush_thread.get_mut().take() {
+            flush_thread.join().ok();
+        }
+        self.inner.release_lock();
     }
 }
 
 impl MiniLsm {
     pub fn close(&self) -> Result<()> {
+        self.inner.closed.store(true, Ordering::SeqCst);
         self.inner.sync_dir()?;
         self.compaction_notifier.send(()).ok();
         self.flush_notifier.send(()).ok();
@@ -198,18 +457,19 @@ impl MiniLsm {
         if let Some(compaction_thread) = compaction_thread.take() {
             compaction_thread
                 .join()
-                .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+                .map_err(|e| Error::Other(anyhow::anyhow!("{:?}", e)))?;
         }
         let mut flush_thread = self.flush_thread.lock();
         if let Some(flush_thread) = flush_thread.take() {
             flush_thread
                 .join()
-                .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+                .map_err(|e| Error::Other(anyhow::anyhow!("{:?}", e)))?;
         }
 
         if self.inner.options.enable_wal {
             self.inner.sync()?;
             self.inner.sync_dir()?;
+            self.inner.release_lock();
             return Ok(());
         }
 
@@ -228,6 +488,7 @@ impl MiniLsm {
             self.inner.force_flush_next_imm_memtable()?;
         }
         self.inner.sync_dir()?;
+        self.inner.release_lock();
 
         Ok(())
     }
@@ -265,6 +526,20 @@ impl MiniLsm {
         self.inner.put(key, value)
     }
 
+    /// Write the batch at a caller-provided commit ts, e.g., from a hybrid logical clock. The ts
+    /// must be larger than the latest commit ts.
+    pub fn write_batch_with_ts<T: AsRef<[u8]>>(
+        &self,
+        batch: &[WriteBatchRecord<T>],
+        ts: u64,
+    ) -> Result<()> {
+        self.inner.write_batch_with_ts(batch, ts)
+    }
+
+    pub fn put_with_ts(&self, key: &[u8], value: &[u8], ts: u64) -> Result<()> {
+        self.inner.put_with_ts(key, value, ts)
+    }
+
     pub fn delete(&self, key: &[u8]) -> Result<()> {
         self.inner.delete(key)
     }
@@ -277,10 +552,64 @@ impl MiniLsm {
         self.inner.new_txn()
     }
 
+    pub fn new_txn_with_isolation(&self, isolation: IsolationLevel) -> Result<Arc<Transaction>> {
+        self.inner.new_txn_with_isolation(isolation)
+    }
+
+    pub fn new_pessimistic_txn(&self) -> Result<Arc<PessimisticTransaction>> {
+        self.inner.new_pessimistic_txn()
+    }
+
     pub fn scan(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<TxnIterator> {
         self.inner.scan(lower, upper)
     }
 
+    /// Names of the transactions that are prepared but not committed or rolled back yet.
+    pub fn prepared_txns(&self) -> Vec<String> {
+        self.inner.prepared_txns()
+    }
+
+    pub fn commit_prepared(&self, name: &str) -> Result<()> {
+        self.inner.commit_prepared(name)
+    }
+
+    pub fn rollback_prepared(&self, name: &str) -> Result<()> {
+        self.inner.rollback_prepared(name)
+    }
+
+    /// The write batches committed after `ts`, in commit ts order. Fails if some of them are no
+    /// longer retained.
+    pub fn updates_since(&self, ts: u64) -> Result<impl Iterator<Item = (u64, WriteBatch)>> {
+        self.inner.updates_since(ts)
+    }
+
+    /// Subscribe to the write batches committed from now on.
+    pub fn subscribe(&self) -> crossbeam_channel::Receiver<(u64, WriteBatch)> {
+        self.inner.subscribe()
+    }
+
+    /// Create a read-only snapshot at the latest commit ts.
+    pub fn snapshot(&self) -> Result<Arc<Snapshot>> {
+        self.inner.snapshot()
+    }
+
+    /// Get a key as of a commit ts that is still retained. Reading above the latest commit ts
+    /// advances it, so that later writes are not committed at or below a ts that has been read.
+    pub fn get_at(&self, key: &[u8], ts: u64) -> Result<Option<Bytes>> {
+        self.inner.get_at(key, ts)
+    }
+
+    /// Create a read-only snapshot at a caller-provided ts, see `get_at`.
+    pub fn snapshot_at(&self, ts: u64) -> Result<Arc<Snapshot>> {
+        self.inner.snapshot_at(ts)
+    }
+
+    /// Keep the versions visible at or above `ts` from being garbage collected, in addition to the
+    /// local readers. The safe point can only move forward.
+    pub fn set_safe_point(&self, ts: u64) -> Result<()> {
+        self.inner.set_safe_point(ts)
+    }
+
     /// Only call this in test cases due to race conditions
     pub fn force_flush(&self) -> Result<()> {
         if !self.inner.state.read().memtable.is_empty() {
@@ -296,6 +625,26 @@ impl MiniLsm {
     pub fn force_full_compaction(&self) -> Result<()> {
         self.inner.force_full_compaction()
     }
+
+    /// The `.sst` and `.wal` files not referenced by the storage when it was opened and the
+    /// leftover temporary files, along with their sizes. They are deleted on open unless
+    /// `delete_orphan_files` is disabled.
+    pub fn orphan_files(&self) -> &[OrphanFile] {
+        &self.inner.orphan_files
+    }
+
+    /// Report the memory of the filters and indexes of the SSTs, and of the data blocks in the
+    /// block cache.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.inner.memory_usage()
+    }
+
+    /// Change the compaction strategy without reopening the storage. All SSTs are compacted into
+    /// a single sorted run that is valid for the new strategy, after which it takes over. Reopen
+    /// the storage with the new strategy afterwards.
+    pub fn change_compaction_strategy(&self, compaction_options: CompactionOptions) -> Result<()> {
+        self.inner.change_compaction_strategy(compaction_options)
+    }
 }
 
 impl LsmStorageInner {
@@ -312,54 +661,95 @@ impl LsmStorageInner {
         self.manifest.as_ref().unwrap()
     }
 
+    pub(crate) fn check_open(&self) -> Result<()> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(Error::Closed);
+        }
+        Ok(())
+    }
+
     /// Start the storage engine by either loading an existing directory or creating a new one if the directory does
     /// not exist.
     pub(crate) fn open(path: impl AsRef<Path>, options: LsmStorageOptions) -> Result<Self> {
+        options.validate()?;
         let mut state = LsmStorageState::create(&options);
         let path = path.as_ref();
         let mut next_sst_id = 1;
-        let block_cache = Arc::new(BlockCache::new(1 << 20)); // 4GB block cache,
+        let block_cache = Arc::new(new_block_cache(options.block_cache_size));
         let manifest;
 
-        let compaction_controller = match &options.compaction_options {
-            CompactionOptions::Leveled(options) => {
-                CompactionController::Leveled(LeveledCompactionController::new(options.clone()))
-            }
-            CompactionOptions::Tiered(options) => {
-                CompactionController::Tiered(TieredCompactionController::new(options.clone()))
-            }
-            CompactionOptions::Simple(options) => CompactionController::Simple(
-                SimpleLeveledCompactionController::new(options.clone()),
-            ),
-            CompactionOptions::NoCompaction => CompactionController::NoCompaction,
-        };
+        let compaction_controller = CompactionController::new(&options.compaction_options);
 
-        if !path.exists() {
-            std::fs::create_dir_all(path).context("failed to create DB dir")?;
+        let fs = options.fs.clone();
+        if !fs.exists(path) {
+            fs.create_dir_all(path).context("failed to create DB dir")?;
         }
+        let lock = fs
+            .lock_file(&path.join("LOCK"))
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::WouldBlock => Error::Locked(path.to_path_buf()),
+                _ => Error::Io(e),
+            })?;
+        let change_log = ChangeLog::open(path, &options)?;
         let manifest_path = path.join("MANIFEST");
+        let new_options = PersistedOptions::new(&options);
+        let mut old_options = PersistedOptions::read(fs.as_ref(), path)?;
         let mut last_commit_ts = 0;
-        if !manifest_path.exists() {
+        let mut prepared_log = PreparedLog::default();
+        let mut safe_point = None;
+        // the memtables in the manifest, whose WALs are still referenced
+        let mut memtables = BTreeSet::new();
+        if !fs.exists(&manifest_path) {
             if options.enable_wal {
                 state.memtable = Arc::new(MemTable::create_with_wal(
+                    fs.as_ref(),
                     state.memtable.id(),
                     Self::path_of_wal_static(path, state.memtable.id()),
                 )?);
+                fs.sync_dir(path)?;
             }
-            manifest = Manifest::create(&manifest_path).context("failed to create manifest")?;
+            manifest = Manifest::create(fs.as_ref(), &manifest_path)?;
             manifest.add_record_when_init(ManifestRecord::NewMemtable(state.memtable.id()))?;
         } else {
-            let (m, records) = Manifest::recover(&manifest_path)?;
-            let mut memtables = BTreeSet::new();
-            for record in records {
+            let (m, records) = Manifest::recover(fs.as_ref(), &manifest_path)?;
+            // The SSTs are laid out for the strategy of the last change, and the records before it
+            // are replayed without the state changes. The OPTIONS file is only rewritten after the
+            // change is recorded, and may still have the old strategy.
+            let last_strategy_change = records.iter().rposition(|(_, record)| {
+                matches!(record, ManifestRecord::ChangeCompactionStrategy { .. })
+            });
+            if let (Some(old_options), Some(idx)) = (&mut old_options, last_strategy_change) {
+                if let ManifestRecord::ChangeCompactionStrategy { options, .. } = &records[idx].1 {
+                    old_options.compaction_options = options.clone();
+                }
+            }
+            if let Some(old_options) = &old_options {
+                let has_compaction_records = records.iter().any(|(_, record)| {
+                    matches!(
+                        record,
+                        ManifestRecord::Compaction(..)
+                            | ManifestRecord::ChangeCompactionStrategy { .. }
+                    )
+                });
+                old_options.check_compatible(&new_options, has_compaction_records)?;
+            }
+            for (idx, (offset, record)) in records.into_iter().enumerate() {
+                let replay_state = last_strategy_change.is_none_or(|change| idx >= change);
                 match record {
                     ManifestRecord::Flush(sst_id) => {
-                        let res = memtables.remove(&sst_id);
-                        assert!(res, "memtable not exist?");
-                        if compaction_controller.flush_to_l0() {
-                            state.l0_sstables.insert(0, sst_id);
-                        } else {
-                            state.levels.insert(0, (sst_id, vec![sst_id]));
+                        if !memtables.remove(&sst_id) {
+                            return Err(Error::corruption(
+                                &manifest_path,
+                                offset,
+                                format!("flushed memtable {} does not exist", sst_id),
+                            ));
+                        }
+                        if replay_state {
+                            if compaction_controller.flush_to_l0() {
+                                state.l0_sstables.insert(0, sst_id);
+                            } else {
+                                state.levels.insert(0, (sst_id, vec![sst_id]));
+                            }
                         }
                         next_sst_id = next_sst_id.max(sst_id);
                     }
@@ -367,30 +757,52 @@ impl LsmStorageInner {
                         next_sst_id = next_sst_id.max(x);
                         memtables.insert(x);
                     }
+                    ManifestRecord::CommitTs(ts) => {
+                        last_commit_ts = last_commit_ts.max(ts);
+                    }
+                    ManifestRecord::SafePoint(ts) => {
+                        safe_point = Some(ts);
+                    }
                     ManifestRecord::Compaction(task, output) => {
-                        let (new_state, _) = compaction_controller
-                            .apply_compaction_result(&state, &task, &output, true);
-                        // TODO: apply remove again
-                        state = new_state;
+                        if replay_state {
+                            let (new_state, _) = compaction_controller
+                                .apply_compaction_result(&state, &task, &output, true)?;
+                            // the SSTs removed by the compaction are deleted as orphans below
+                            state = new_state;
+                        }
                         next_sst_id =
                             next_sst_id.max(output.iter().max().copied().unwrap_or_default());
                     }
+                    ManifestRecord::ChangeCompactionStrategy {
+                        options: _,
+                        l0_sstables,
+                        levels,
+                    } => {
+                        next_sst_id = l0_sstables
+                            .iter()
+                            .chain(levels.iter().flat_map(|(_, files)| files))
+                            .fold(next_sst_id, |max, id| max.max(*id));
+                        state.l0_sstables = l0_sstables;
+                        state.levels = levels;
+                    }
                 }
             }
 
             let mut sst_cnt = 0;
             // recover SSTs
-            for table_id in state
-                .l0_sstables
-                .iter()
-                .chain(state.levels.iter().flat_map(|(_, files)| files))
-            {
+            for (level, table_id) in state.l0_sstables.iter().map(|id| (0, id)).chain(
+                state
+                    .levels
+                    .iter()
+                    .enumerate()
+                    .flat_map(|(idx, (_, files))| files.iter().map(move |id| (idx + 1, id))),
+            ) {
                 let table_id = *table_id;
-                let sst = SsTable::open(
+                let sst = SsTable::open_with(
                     table_id,
                     Some(block_cache.clone()),
-                    FileObject::open(&Self::path_of_sst_static(path, table_id))
-                        .context("failed to open SST")?,
+                    FileObject::open_in(fs.as_ref(), &Self::path_of_sst_static(path, table_id))?,
+                    options.sst_pinning(level),
                 )?;
                 last_commit_ts = last_commit_ts.max(sst.max_ts());
                 state.sstables.insert(table_id, Arc::new(sst));
@@ -417,9 +829,19 @@ impl LsmStorageInner {
             // recover memtables
             if options.enable_wal {
                 let mut wal_cnt = 0;
+                let mut empty_wals = Vec::new();
+                // The memtables are all flushed on close if the WAL was disabled, and their WALs
+                // do not exist.
+                if old_options.as_ref().is_some_and(|x| !x.enable_wal) {
+                    memtables.clear();
+                }
                 for id in memtables.iter() {
-                    let memtable =
-                        MemTable::recover_from_wal(*id, Self::path_of_wal_static(path, *id))?;
+                    let memtable = MemTable::recover_from_wal(
+                        fs.as_ref(),
+                        *id,
+                        Self::path_of_wal_static(path, *id),
+                        &mut prepared_log,
+                    )?;
                     let max_ts = memtable
                         .map
                         .iter()
@@ -430,13 +852,33 @@ impl LsmStorageInner {
                     if !memtable.is_empty() {
                         state.imm_memtables.insert(0, Arc::new(memtable));
                         wal_cnt += 1;
+                    } else {
+                        empty_wals.push(*id);
                     }
                 }
                 println!("{} WALs recovered", wal_cnt);
                 state.memtable = Arc::new(MemTable::create_with_wal(
+                    fs.as_ref(),
                     next_sst_id,
                     Self::path_of_wal_static(path, next_sst_id),
                 )?);
+                fs.sync_dir(path)?;
+                // WALs of empty memtables are never flushed and removed, so the two-phase commit
+                // records in them are logged again to the new WAL before they are truncated.
+                // Decided transactions are logged as rolled back, so that prepare records left in
+                // the recovered WALs are not restored again.
+                for name in &prepared_log.decided {
+                    state.memtable.log_rollback_prepared(name)?;
+                }
+                for (name, (read_ts, batch)) in &prepared_log.prepared {
+                    state.memtable.log_prepare(name, *read_ts, batch)?;
+                }
+                state.memtable.sync_wal()?;
+                for id in empty_wals {
+                    fs.create(&Self::path_of_wal_static(path, id))
+                        .and_then(|mut file| file.sync())
+                        .context("failed to truncate WAL")?;
+                }
             } else {
                 state.memtable = Arc::new(MemTable::create(next_sst_id));
             }
@@ -444,6 +886,45 @@ impl LsmStorageInner {
             next_sst_id += 1;
             manifest = m;
         };
+        if old_options.as_ref() != Some(&new_options) {
+            new_options.write(fs.as_ref(), path)?;
+        }
+
+        memtables.insert(state.memtable.id());
+        let orphan_files = Self::find_orphan_files(fs.as_ref(), path, &state, &memtables)?;
+        if !orphan_files.is_empty() {
+            let size = orphan_files.iter().map(|x| x.size).sum::<u64>();
+            if options.delete_orphan_files {
+                for file in &orphan_files {
+                    fs.remove_file(&file.path)
+                        .context("failed to delete orphan file")?;
+                }
+                println!(
+                    "{} orphan files deleted, {} bytes",
+                    orphan_files.len(),
+                    size
+                );
+            } else {
+                println!("{} orphan files found, {} bytes", orphan_files.len(), size);
+            }
+        }
+
+        // writes before the ones in the retained WALs are only in the SSTs
+        let retained_ts = state
+            .imm_memtables
+            .iter()
+            .filter_map(|memtable| ts_range(&memtable.map).map(|(min_ts, _)| min_ts))
+            .chain(change_log.min_archived_ts())
+            .min();
+        change_log.advance_purged_ts(retained_ts.map_or(last_commit_ts, |ts| ts - 1));
+
+        let mvcc = LsmMvccInner::new(last_commit_ts, options.history_retention);
+        *mvcc.safe_point.lock() = safe_point;
+        *mvcc.prepared_txns.lock() = prepared_log
+            .prepared
+            .into_iter()
+            .map(|(name, (read_ts, batch))| (name, PreparedTxnData { read_ts, batch }))
+            .collect();
 
         let storage = Self {
             state: Arc::new(RwLock::new(Arc::new(state))),
@@ -451,17 +932,61 @@ impl LsmStorageInner {
             path: path.to_path_buf(),
             block_cache,
             next_sst_id: AtomicUsize::new(next_sst_id),
-            compaction_controller,
+            compaction_controller: RwLock::new(compaction_controller),
+            compaction_lock: Mutex::new(()),
             manifest: Some(manifest),
+            mvcc: Some(mvcc),
             options: options.into(),
-            mvcc: Some(LsmMvccInner::new(last_commit_ts)),
             compaction_filters: Arc::new(Mutex::new(Vec::new())),
+            change_log,
+            closed: AtomicBool::new(false),
+            lock: Mutex::new(Some(lock)),
+            orphan_files,
         };
         storage.sync_dir()?;
 
         Ok(storage)
     }
 
+    /// List the `.sst` and `.wal` files in the DB dir that are not referenced by the recovered
+    /// state, and the temporary files of interrupted writes. WALs are referenced by the memtables
+    /// recorded in the manifest.
+    fn find_orphan_files(
+        fs: &dyn FileSystem,
+        path: &Path,
+        state: &LsmStorageState,
+        memtables: &BTreeSet<usize>,
+    ) -> Result<Vec<OrphanFile>> {
+        let mut orphan_files = Vec::new();
+        for file_path in fs.list_dir(path).context("failed to list DB dir")? {
+            let is_temp_file = file_path
+                .to_str()
+                .is_some_and(|x| x.ends_with(TEMP_FILE_SUFFIX));
+            let id = file_path
+                .file_stem()
+                .and_then(|x| x.to_str())
+                .and_then(|x| x.parse::<usize>().ok());
+            let orphan = match (file_path.extension().and_then(|x| x.to_str()), id) {
+                _ if is_temp_file => true,
+                (Some("sst"), Some(id)) => !state.sstables.contains_key(&id),
+                (Some("wal"), Some(id)) => !memtables.contains(&id),
+                _ => false,
+            };
+            if orphan {
+                let size = fs
+                    .metadata(&file_path)
+                    .context("failed to stat orphan file")?
+                    .len;
+                orphan_files.push(OrphanFile {
+                    path: file_path,
+                    size,
+                });
+            }
+        }
+        orphan_files.sort_by(|x, y| x.path.cmp(&y.path));
+        Ok(orphan_files)
+    }
+
     pub fn add_compaction_filter(&self, compaction_filter: CompactionFilter) {
         let mut compaction_filters = self.compaction_filters.lock();
         compaction_filters.push(compaction_filter);
@@ -473,11 +998,17 @@ impl LsmStorageInner {
 
     /// Get a key from the storage. In day 7, this can be further optimized by using a bloom filter.
     pub fn get(self: &Arc<Self>, key: &[u8]) -> Result<Option<Bytes>> {
-        let txn = self.mvcc().new_txn(self.clone(), self.options.serializable);
+        let txn = self.mvcc().new_txn(self.clone(), self.default_isolation());
         txn.get(key)
     }
 
+    pub fn get_at(self: &Arc<Self>, key: &[u8], ts: u64) -> Result<Option<Bytes>> {
+        let snapshot = self.mvcc().new_snapshot_at(self.clone(), ts)?;
+        snapshot.get(key)
+    }
+
     pub(crate) fn get_with_ts(&self, key: &[u8], read_ts: u64) -> Result<Option<Bytes>> {
+        self.check_open()?;
         let snapshot = {
             let guard = self.state.read();
             Arc::clone(&guard)
@@ -498,27 +1029,21 @@ impl LsmStorageInner {
 
         let mut l0_iters = Vec::with_capacity(snapshot.l0_sstables.len());
 
-        let keep_table = |key: &[u8], table: &SsTable| {
+        let keep_table = |key: &[u8], table: &SsTable| -> Result<bool> {
             if key_within(
                 key,
                 table.first_key().as_key_slice(),
                 table.last_key().as_key_slice(),
             ) {
-                if let Some(bloom) = &table.bloom {
-                    if bloom.may_contain(farmhash::fingerprint32(key)) {
-                        return true;
-                    }
-                } else {
-                    return true;
-                }
+                return table.may_contain(key);
             }
-            false
+            Ok(false)
         };
 
         for table in snapshot.l0_sstables.iter() {
             let table = snapshot.sstables[table].clone();
-            if keep_table(key, &table) {
-                l0_iters.push(Box::new(SsTableIterator::create_and_seek_to_key(
+            if keep_table(key, &table)? {
+                l0_iters.push(Box::new(SsTableIterator::create_and_seek_for_get(
                     table,
                     KeySlice::from_slice(key, key::TS_RANGE_BEGIN),
                 )?));
@@ -530,11 +1055,11 @@ impl LsmStorageInner {
             let mut level_ssts = Vec::with_capacity(level_sst_ids.len());
             for table in level_sst_ids {
                 let table = snapshot.sstables[table].clone();
-                if keep_table(key, &table) {
+                if keep_table(key, &table)? {
                     level_ssts.push(table);
                 }
             }
-            let level_iter = SstConcatIterator::create_and_seek_to_key(
+            let level_iter = SstConcatIterator::create_and_seek_for_get(
                 level_ssts,
                 KeySlice::from_slice(key, key::TS_RANGE_BEGIN),
             )?;
@@ -556,14 +1081,84 @@ impl LsmStorageInner {
         Ok(None)
     }
 
+    /// Write the batch at the next commit ts. Should be called with the commit lock held, and the
+    /// write set recorded for conflict checks by the caller.
     pub fn write_batch_inner<T: AsRef<[u8]>>(&self, batch: &[WriteBatchRecord<T>]) -> Result<u64> {
+        self.write_batch_at(batch, None)
+    }
+
+    /// Write a batch outside of transactions, at the given commit ts or the next one. Its write
+    /// set is recorded like a committed transaction, so that the transactions that started before
+    /// it see it as a conflicting commit.
+    fn write_batch_committed<T: AsRef<[u8]>>(
+        &self,
+        batch: &[WriteBatchRecord<T>],
+        ts: Option<u64>,
+    ) -> Result<u64> {
+        let _commit_lock = self.mvcc().commit_lock.lock();
+        let ts = self.write_batch_at(batch, ts)?;
+        let write_set = batch
+            .iter()
+            .map(|record| match record {
+                WriteBatchRecord::Del(key) | WriteBatchRecord::Put(key, _) => {
+                    Bytes::copy_from_slice(key.as_ref())
+                }
+            })
+            .collect();
+        self.mvcc().record_committed_txn(write_set, ts, ts);
+        Ok(ts)
+    }
+
+    /// Check the commit ts given by the caller, or pick the next one, and reserve it. Should be
+    /// called with the write lock held.
+    fn next_commit_ts(&self, ts: Option<u64>) -> Result<u64> {
+        let latest_commit_ts = self.mvcc().latest_commit_ts();
+        let requested = ts.is_some();
+        let ts = match ts {
+            Some(ts) if ts <= latest_commit_ts => {
+                return Err(Error::InvalidArgument(format!(
+                    "commit ts {} is not larger than the latest commit ts {}",
+                    ts, latest_commit_ts
+                )));
+            }
+            Some(ts) => ts,
+            None => latest_commit_ts.saturating_add(1),
+        };
+        // the largest ts is reserved for seeking to the latest version of a key
+        if ts == key::TS_RANGE_BEGIN {
+            return Err(Error::InvalidArgument(format!(
+                "commit ts {} is out of range",
+                ts
+            )));
+        }
+        self.reserve_ts(ts, !requested)?;
+        Ok(ts)
+    }
+
+    /// Write the batch at the given commit ts, or at the next commit ts if not given. Returns the
+    /// commit ts.
+    fn write_batch_at<T: AsRef<[u8]>>(
+        &self,
+        batch: &[WriteBatchRecord<T>],
+        ts: Option<u64>,
+    ) -> Result<u64> {
+        self.check_open()?;
+        // check the whole batch first so that it is not partially applied, and no commit ts is
+        // taken for it
+        for record in batch {
+            match record {
+                WriteBatchRecord::Del(key) => check_key_value(key.as_ref(), None)?,
+                WriteBatchRecord::Put(key, value) => {
+                    check_key_value(key.as_ref(), Some(value.as_ref()))?
+                }
+            }
+        }
         let _lck = self.mvcc().write_lock.lock();
-        let ts = self.mvcc().latest_commit_ts() + 1;
+        let ts = self.next_commit_ts(ts)?;
         for record in batch {
             match record {
                 WriteBatchRecord::Del(key) => {
                     let key = key.as_ref();
-                    assert!(!key.is_empty(), "key cannot be empty");
                     let size;
                     {
                         let guard = self.state.read();
@@ -575,8 +1170,6 @@ impl LsmStorageInner {
                 WriteBatchRecord::Put(key, value) => {
                     let key = key.as_ref();
                     let value = value.as_ref();
-                    assert!(!key.is_empty(), "key cannot be empty");
-                    assert!(!value.is_empty(), "value cannot be empty");
                     let size;
                     {
                         let guard = self.state.read();
@@ -588,24 +1181,87 @@ impl LsmStorageInner {
             }
         }
         self.mvcc().update_commit_ts(ts);
+        if self.change_log.has_subscribers() {
+            let batch = batch
+                .iter()
+                .map(|record| match record {
+                    WriteBatchRecord::Del(key) => {
+                        (Bytes::copy_from_slice(key.as_ref()), Bytes::new())
+                    }
+                    WriteBatchRecord::Put(key, value) => (
+                        Bytes::copy_from_slice(key.as_ref()),
+                        Bytes::copy_from_slice(value.as_ref()),
+                    ),
+                })
+                .collect::<Vec<_>>();
+            self.publish(ts, batch);
+        }
         Ok(ts)
     }
 
+    /// Send a committed batch of key -> value (empty for deletes) to the subscribers.
+    fn publish(&self, ts: u64, batch: Vec<(Bytes, Bytes)>) {
+        // later writes to the same key in the batch win, same as in the memtable
+        let batch = batch.into_iter().collect::<BTreeMap<_, _>>();
+        let batch = batch
+            .into_iter()
+            .map(|(key, value)| {
+                if value.is_empty() {
+                    WriteBatchRecord::Del(key)
+                } else {
+                    WriteBatchRecord::Put(key, value)
+                }
+            })
+            .collect();
+        self.change_log.publish(ts, batch);
+    }
+
+    pub fn updates_since(&self, ts: u64) -> Result<impl Iterator<Item = (u64, WriteBatch)>> {
+        self.check_open()?;
+        // writes above the latest commit ts are not committed yet
+        let latest_commit_ts = self.mvcc().latest_commit_ts();
+        let mut updates = BTreeMap::new();
+        {
+            // no memtable can be flushed and have its WAL archived in the meantime
+            let _state_lock = self.state_lock.lock();
+            let snapshot = self.state.read().clone();
+            self.change_log.read_archived(ts, &mut updates)?;
+            for memtable in snapshot
+                .imm_memtables
+                .iter()
+                .rev()
+                .chain(std::iter::once(&snapshot.memtable))
+            {
+                collect_updates(&memtable.map, ts, &mut updates);
+            }
+        }
+        sort_batches(&mut updates);
+        self.change_log.check_retained(ts)?;
+        updates.split_off(&(latest_commit_ts + 1));
+        Ok(updates.into_iter())
+    }
+
+    pub fn subscribe(&self) -> crossbeam_channel::Receiver<(u64, WriteBatch)> {
+        // register under the write lock so that every batch committed after this is sent
+        let _lck = self.mvcc().write_lock.lock();
+        self.change_log.subscribe()
+    }
+
     pub fn write_batch<T: AsRef<[u8]>>(
         self: &Arc<Self>,
         batch: &[WriteBatchRecord<T>],
     ) -> Result<()> {
         if !self.options.serializable {
-            self.write_batch_inner(batch)?;
+            self.write_batch_committed(batch, None)?;
         } else {
-            let txn = self.mvcc().new_txn(self.clone(), self.options.serializable);
+            let txn = self.mvcc().new_txn(self.clone(), self.default_isolation());
             for record in batch {
                 match record {
                     WriteBatchRecord::Del(key) => {
-                        txn.delete(key.as_ref());
+                        txn.delete(key.as_ref())?;
                     }
                     WriteBatchRecord::Put(key, value) => {
-                        txn.put(key.as_ref(), value.as_ref());
+                        txn.put(key.as_ref(), value.as_ref())?;
                     }
                 }
             }
@@ -614,13 +1270,28 @@ impl LsmStorageInner {
         Ok(())
     }
 
+    /// Write the batch at a commit ts provided by the caller, which must be larger than the latest
+    /// commit ts. Transactions that started before `ts` see the batch as a conflicting commit.
+    pub fn write_batch_with_ts<T: AsRef<[u8]>>(
+        &self,
+        batch: &[WriteBatchRecord<T>],
+        ts: u64,
+    ) -> Result<()> {
+        self.write_batch_committed(batch, Some(ts))?;
+        Ok(())
+    }
+
+    pub fn put_with_ts(&self, key: &[u8], value: &[u8], ts: u64) -> Result<()> {
+        self.write_batch_with_ts(&[WriteBatchRecord::Put(key, value)], ts)
+    }
+
     /// Put a key-value pair into the storage by writing into the current memtable.
     pub fn put(self: &Arc<Self>, key: &[u8], value: &[u8]) -> Result<()> {
         if !self.options.serializable {
-            self.write_batch_inner(&[WriteBatchRecord::Put(key, value)])?;
+            self.write_batch_committed(&[WriteBatchRecord::Put(key, value)], None)?;
         } else {
-            let txn = self.mvcc().new_txn(self.clone(), self.options.serializable);
-            txn.put(key, value);
+            let txn = self.mvcc().new_txn(self.clone(), self.default_isolation());
+            txn.put(key, value)?;
             txn.commit()?;
         }
         Ok(())
@@ -629,10 +1300,10 @@ impl LsmStorageInner {
     /// Remove a key from the storage by writing an empty value.
     pub fn delete(self: &Arc<Self>, key: &[u8]) -> Result<()> {
         if !self.options.serializable {
-            self.write_batch_inner(&[WriteBatchRecord::Del(key)])?;
+            self.write_batch_committed(&[WriteBatchRecord::Del(key)], None)?;
         } else {
-            let txn = self.mvcc().new_txn(self.clone(), self.options.serializable);
-            txn.delete(key);
+            let txn = self.mvcc().new_txn(self.clone(), self.default_isolation());
+            txn.delete(key)?;
             txn.commit()?;
         }
         Ok(())
@@ -655,6 +1326,47 @@ impl LsmStorageInner {
         path.as_ref().join(format!("{:05}.sst", id))
     }
 
+    pub(crate) fn memory_usage(&self) -> MemoryUsage {
+        let mut usage = MemoryUsage::default();
+        let snapshot = self.state.read().clone();
+        for sst in snapshot.sstables.values() {
+            usage.pinned_filters += sst.pinned_filter_size();
+            usage.pinned_indexes += sst.pinned_index_size();
+        }
+        for (_, block) in self.block_cache.iter() {
+            let charge = block.charge();
+            match block {
+                CachedBlock::Data(_) => usage.cached_data_blocks += charge,
+                CachedBlock::IndexPartition(_) | CachedBlock::TopLevelIndex(_) => {
+                    usage.cached_indexes += charge
+                }
+                CachedBlock::Filter(_) => usage.cached_filters += charge,
+            }
+        }
+        usage
+    }
+
+    /// Create a streaming builder for the SST in the level, with the block, index and filter
+    /// options.
+    pub(crate) fn new_sst_builder(
+        &self,
+        sst_id: usize,
+        level: usize,
+        bottom_level: bool,
+    ) -> Result<SsTableBuilder> {
+        Ok(SsTableBuilder::new_streaming(
+            self.options.fs.clone(),
+            self.path_of_sst(sst_id),
+            self.options.block_size,
+        )?
+        .with_restart_interval(self.options.block_restart_interval)
+        .with_block_format(self.options.block_format)
+        .with_hash_index(self.options.block_hash_index)
+        .with_partitioned_index(self.options.index_partition_size)
+        .with_pinning(self.options.sst_pinning(level))
+        .with_filter_policy(self.options.filter_policy(level, bottom_level)))
+    }
+
     pub(crate) fn path_of_sst(&self, id: usize) -> PathBuf {
         Self::path_of_sst_static(&self.path, id)
     }
@@ -667,12 +1379,24 @@ impl LsmStorageInner {
         Self::path_of_wal_static(&self.path, id)
     }
 
+    /// Release the lock on the DB dir, so that it can be opened again.
+    fn release_lock(&self) {
+        self.lock.lock().take();
+    }
+
     pub(super) fn sync_dir(&self) -> Result<()> {
-        File::open(&self.path)?.sync_all()?;
+        self.options.fs.sync_dir(&self.path)?;
         Ok(())
     }
 
     fn freeze_memtable_with_memtable(&self, memtable: Arc<MemTable>) -> Result<()> {
+        // Log the prepared transactions again to the new WAL, as the WALs they were logged to are
+        // removed once flushed. Hold the lock until the memtable is swapped so that no
+        // transaction is prepared to the old WAL in between.
+        let prepared_txns = self.mvcc().prepared_txns.lock();
+        for (name, txn_data) in prepared_txns.iter() {
+            memtable.log_prepare(name, txn_data.read_ts, &txn_data.batch)?;
+        }
         let mut guard = self.state.write();
         // Swap the current memtable with a new one.
         let mut snapshot = guard.as_ref().clone();
@@ -683,6 +1407,7 @@ impl LsmStorageInner {
         *guard = Arc::new(snapshot);
 
         drop(guard);
+        drop(prepared_txns);
         old_memtable.sync_wal()?;
 
         Ok(())
@@ -692,10 +1417,14 @@ impl LsmStorageInner {
     pub fn force_freeze_memtable(&self, state_lock_observer: &MutexGuard<'_, ()>) -> Result<()> {
         let memtable_id = self.next_sst_id();
         let memtable = if self.options.enable_wal {
-            Arc::new(MemTable::create_with_wal(
+            let memtable = MemTable::create_with_wal(
+                self.options.fs.as_ref(),
                 memtable_id,
                 self.path_of_wal(memtable_id),
-            )?)
+            )?;
+            // the WAL must exist once the manifest refers to it
+            self.sync_dir()?;
+            Arc::new(memtable)
         } else {
             Arc::new(MemTable::create(memtable_id))
         };
@@ -706,7 +1435,6 @@ impl LsmStorageInner {
             state_lock_observer,
             ManifestRecord::NewMemtable(memtable_id),
         )?;
-        self.sync_dir()?;
 
         Ok(())
     }
@@ -715,21 +1443,16 @@ impl LsmStorageInner {
     pub fn force_flush_next_imm_memtable(&self) -> Result<()> {
         let state_lock = self.state_lock.lock();
 
-        let flush_memtable;
-
-        {
-            let guard = self.state.read();
-            flush_memtable = guard
-                .imm_memtables
-                .last()
-                .expect("no imm memtables!")
-                .clone();
-        }
+        // the memtable may have been flushed by the flush thread while waiting for the lock
+        let Some(flush_memtable) = self.state.read().imm_memtables.last().cloned() else {
+            return Ok(());
+        };
 
-        let mut builder = SsTableBuilder::new(self.options.block_size);
-        flush_memtable.flush(&mut builder)?;
         let sst_id = flush_memtable.id();
-        let sst = Arc::new(builder.build(
+        let mut builder = self.new_sst_builder(sst_id, 0, false)?;
+        flush_memtable.flush(&mut builder)?;
+        let sst = Arc::new(builder.build_in(
+            self.options.fs.as_ref(),
             sst_id,
             Some(self.block_cache.clone()),
             self.path_of_sst(sst_id),
@@ -743,7 +1466,7 @@ impl LsmStorageInner {
             let mem = snapshot.imm_memtables.pop().unwrap();
             assert_eq!(mem.id(), sst_id);
             // Add L0 table
-            if self.compaction_controller.flush_to_l0() {
+            if self.compaction_controller.read().flush_to_l0() {
                 // In leveled compaction or no compaction, simply flush to L0
                 snapshot.l0_sstables.insert(0, sst_id);
             } else {
@@ -756,20 +1479,182 @@ impl LsmStorageInner {
             *guard = Arc::new(snapshot);
         }
 
-        if self.options.enable_wal {
-            std::fs::remove_file(self.path_of_wal(sst_id))?;
-        }
-
         self.manifest()
             .add_record(&state_lock, ManifestRecord::Flush(sst_id))?;
 
+        // The WAL is retired only after the flush is recorded, as the manifest still refers to it
+        // before that.
+        let ts_range = ts_range(&flush_memtable.map);
+        if self.options.enable_wal {
+            self.change_log
+                .retire_wal(&self.path_of_wal(sst_id), sst_id, ts_range)?;
+        } else if let Some((_, max_ts)) = ts_range {
+            self.change_log.advance_purged_ts(max_ts);
+        }
+
         self.sync_dir()?;
 
         Ok(())
     }
 
+    /// Record a ts at or above `ts` in the manifest before `ts` is handed out, so that no ts is
+    /// handed out again after a crash. Compaction may remove the versions with the latest
+    /// timestamps, and the timestamps of reads and empty commits are not recorded anywhere else.
+    /// The commit ts picked by the storage are reserved `COMMIT_TS_RESERVATION` ahead, so that
+    /// most commits do not write the manifest, while a ts given by the caller is recorded as is.
+    /// Should be called with the write lock held.
+    pub(crate) fn reserve_ts(&self, ts: u64, ahead: bool) -> Result<()> {
+        let mut reserved_ts = self.mvcc().reserved_ts.lock();
+        if ts <= *reserved_ts {
+            return Ok(());
+        }
+        let ts = if ahead {
+            ts.saturating_add(COMMIT_TS_RESERVATION)
+                .min(key::TS_RANGE_BEGIN - 1)
+        } else {
+            ts
+        };
+        self.manifest()
+            .add_record(&self.state_lock.lock(), ManifestRecord::CommitTs(ts))?;
+        *reserved_ts = ts;
+        Ok(())
+    }
+
+    pub fn set_safe_point(&self, ts: u64) -> Result<()> {
+        self.check_open()?;
+        let state_lock = self.state_lock.lock();
+        self.mvcc().set_safe_point(ts)?;
+        self.manifest()
+            .add_record(&state_lock, ManifestRecord::SafePoint(ts))
+    }
+
+    /// The isolation level of transactions created without specifying one.
+    fn default_isolation(&self) -> IsolationLevel {
+        if self.options.serializable {
+            IsolationLevel::Serializable
+        } else {
+            IsolationLevel::SnapshotRead
+        }
+    }
+
     pub fn new_txn(self: &Arc<Self>) -> Result<Arc<Transaction>> {
-        Ok(self.mvcc().new_txn(self.clone(), self.options.serializable))
+        self.check_open()?;
+        Ok(self.mvcc().new_txn(self.clone(), self.default_isolation()))
+    }
+
+    pub fn new_txn_with_isolation(
+        self: &Arc<Self>,
+        isolation: IsolationLevel,
+    ) -> Result<Arc<Transaction>> {
+        self.check_open()?;
+        Ok(self.mvcc().new_txn(self.clone(), isolation))
+    }
+
+    pub fn new_pessimistic_txn(self: &Arc<Self>) -> Result<Arc<PessimisticTransaction>> {
+        self.check_open()?;
+        Ok(self
+            .mvcc()
+            .new_pessimistic_txn(self.clone(), self.options.lock_timeout))
+    }
+
+    pub fn snapshot(self: &Arc<Self>) -> Result<Arc<Snapshot>> {
+        self.check_open()?;
+        Ok(self.mvcc().new_snapshot(self.clone()))
+    }
+
+    pub fn snapshot_at(self: &Arc<Self>, ts: u64) -> Result<Arc<Snapshot>> {
+        self.check_open()?;
+        self.mvcc().new_snapshot_at(self.clone(), ts)
+    }
+
+    /// Persist the write batch of a transaction to the WAL as prepared. Should be called with the
+    /// commit lock held, after the transaction is validated.
+    pub(crate) fn prepare_batch(
+        &self,
+        name: &str,
+        read_ts: u64,
+        batch: Vec<(Bytes, Bytes)>,
+    ) -> Result<()> {
+        self.check_open()?;
+        if !self.options.enable_wal {
+            return Err(Error::InvalidArgument(
+                "two-phase commit requires the WAL to be enabled".to_string(),
+            ));
+        }
+        // the name is logged with a u16 length
+        if name.is_empty() || name.len() > u16::MAX as usize {
+            return Err(Error::InvalidArgument(format!(
+                "the name of a prepared transaction must be 1 to {} bytes, got {} bytes",
+                u16::MAX,
+                name.len()
+            )));
+        }
+        let mut prepared_txns = self.mvcc().prepared_txns.lock();
+        if prepared_txns.contains_key(name) {
+            return Err(Error::InvalidArgument(format!(
+                "transaction {} is already prepared",
+                name
+            )));
+        }
+        {
+            let guard = self.state.read();
+            guard.memtable.log_prepare(name, read_ts, &batch)?;
+            guard.memtable.sync_wal()?;
+        }
+        prepared_txns.insert(name.to_string(), PreparedTxnData { read_ts, batch });
+        Ok(())
+    }
+
+    pub fn prepared_txns(&self) -> Vec<String> {
+        self.mvcc().prepared_txns.lock().keys().cloned().collect()
+    }
+
+    pub fn commit_prepared(&self, name: &str) -> Result<()> {
+        self.check_open()?;
+        let _commit_lock = self.mvcc().commit_lock.lock();
+        let _lck = self.mvcc().write_lock.lock();
+        let ts = self.next_commit_ts(None)?;
+        let (txn_data, size) = {
+            let mut prepared_txns = self.mvcc().prepared_txns.lock();
+            let Some(txn_data) = prepared_txns.get(name) else {
+                return Err(Error::InvalidArgument(format!(
+                    "no prepared transaction named {}",
+                    name
+                )));
+            };
+            let guard = self.state.read();
+            guard.memtable.commit_prepared(name, ts, &txn_data.batch)?;
+            guard.memtable.sync_wal()?;
+            let size = guard.memtable.approximate_size();
+            (prepared_txns.remove(name).unwrap(), size)
+        };
+        self.mvcc().update_commit_ts(ts);
+        if self.change_log.has_subscribers() {
+            self.publish(ts, txn_data.batch.clone());
+        }
+        let write_set = txn_data.batch.into_iter().map(|(key, _)| key).collect();
+        self.mvcc()
+            .record_committed_txn(write_set, txn_data.read_ts, ts);
+        self.try_freeze(size)?;
+        Ok(())
+    }
+
+    pub fn rollback_prepared(&self, name: &str) -> Result<()> {
+        self.check_open()?;
+        let mut prepared_txns = self.mvcc().prepared_txns.lock();
+        if !prepared_txns.contains_key(name) {
+            return Err(Error::InvalidArgument(format!(
+                "no prepared transaction named {}",
+                name
+            )));
+        }
+        {
+            let guard = self.state.read();
+            guard.memtable.log_rollback_prepared(name)?;
+            guard.memtable.sync_wal()?;
+        }
+        prepared_txns.remove(name);
+        Ok(())
     }
 
     /// Create an iterator over a range of keys.
@@ -778,7 +1663,7 @@ impl LsmStorageInner {
         lower: Bound<&[u8]>,
         upper: Bound<&[u8]>,
     ) -> Result<TxnIterator> {
-        let txn = self.mvcc().new_txn(self.clone(), self.options.serializable);
+        let txn = self.mvcc().new_txn(self.clone(), self.default_isolation());
         txn.scan(lower, upper)
     }
 
@@ -788,21 +1673,22 @@ impl LsmStorageInner {
         upper: Bound<&[u8]>,
         read_ts: u64,
     ) -> Result<FusedIterator<LsmIterator>> {
+        self.check_open()?;
         let snapshot = {
             let guard = self.state.read();
             Arc::clone(&guard)
         }; // drop global lock here
 
         let mut memtable_iters = Vec::with_capacity(snapshot.imm_memtables.len() + 1);
-        memtable_iters.push(Box::new(snapshot.memtable.scan(
-            map_key_bound_plus_ts(lower, key::TS_RANGE_BEGIN),
-            map_key_bound_plus_ts(upper, key::TS_RANGE_END),
-        )));
+        memtable_iters.push(Box::new(
+            snapshot
+                .memtable
+                .scan(map_lower_key_bound(lower), map_upper_key_bound(upper)),
+        ));
         for memtable in snapshot.imm_memtables.iter() {
-            memtable_iters.push(Box::new(memtable.scan(
-                map_key_bound_plus_ts(lower, key::TS_RANGE_BEGIN),
-                map_key_bound_plus_ts(upper, key::TS_RANGE_END),
-            )));
+            memtable_iters.push(Box::new(
+                memtable.scan(map_lower_key_bound(lower), map_upper_key_bound(upper)),
+            ));
         }
         let memtable_iter = MergeIterator::create(memtable_iters);
 
@@ -1,17 +1,16 @@
-use std::fs::{File, OpenOptions};
-use std::io::{Read, Write};
 use std::path::Path;
 use std::sync::Arc;
 
-use anyhow::{bail, Context, Result};
 use bytes::{Buf, BufMut};
 use parking_lot::{Mutex, MutexGuard};
 use serde::{Deserialize, Serialize};
 
-use crate::compact::CompactionTask;
+use crate::compact::{CompactionOptions, CompactionTask};
+use crate::error::{Error, IoResultExt, Result};
+use crate::fs::{FileSystem, WritableFile};
 
 pub struct Manifest {
-    file: Arc<Mutex<File>>,
+    file: Arc<Mutex<Box<dyn WritableFile>>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -19,43 +18,59 @@ pub enum ManifestRecord {
     Flush(usize),
     NewMemtable(usize),
     Compaction(CompactionTask, Vec<usize>),
+    /// The high-water mark of commit ts. No ts at or below it is handed out after recovery.
+    CommitTs(u64),
+    /// The GC safe point set by the user.
+    SafePoint(u64),
+    /// The compaction strategy is changed online, with the SSTs laid out as recorded. The records
+    /// before it are replayed without changing the LSM state.
+    ChangeCompactionStrategy {
+        options: CompactionOptions,
+        l0_sstables: Vec<usize>,
+        levels: Vec<(usize, Vec<usize>)>,
+    },
 }
 
 impl Manifest {
-    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+    pub fn create(fs: &dyn FileSystem, path: impl AsRef<Path>) -> Result<Self> {
         Ok(Self {
             file: Arc::new(Mutex::new(
-                OpenOptions::new()
-                    .read(true)
-                    .create_new(true)
-                    .write(true)
-                    .open(path)
+                fs.create(path.as_ref())
                     .context("failed to create manifest")?,
             )),
         })
     }
 
-    pub fn recover(path: impl AsRef<Path>) -> Result<(Self, Vec<ManifestRecord>)> {
-        let mut file = OpenOptions::new()
-            .read(true)
-            .append(true)
-            .open(path)
-            .context("failed to recover manifest")?;
-        let mut buf = Vec::new();
-        file.read_to_end(&mut buf)?;
+    /// Open the manifest for appending, and read its records along with their offsets.
+    pub fn recover(
+        fs: &dyn FileSystem,
+        path: impl AsRef<Path>,
+    ) -> Result<(Self, Vec<(u64, ManifestRecord)>)> {
+        let path = path.as_ref();
+        let buf = fs.read(path).context("failed to recover manifest")?;
         let mut buf_ptr = buf.as_slice();
         let mut records = Vec::new();
         while buf_ptr.has_remaining() {
+            let offset = (buf.len() - buf_ptr.len()) as u64;
+            let corruption = |message: String| Error::corruption(path, offset, message);
+            if buf_ptr.remaining() < 8 {
+                return Err(corruption("incomplete manifest record".to_string()));
+            }
             let len = buf_ptr.get_u64();
+            if (buf_ptr.remaining() as u64) < len.saturating_add(4) {
+                return Err(corruption("incomplete manifest record".to_string()));
+            }
             let slice = &buf_ptr[..len as usize];
-            let json = serde_json::from_slice::<ManifestRecord>(slice)?;
             buf_ptr.advance(len as usize);
             let checksum = buf_ptr.get_u32();
             if checksum != crc32fast::hash(slice) {
-                bail!("checksum mismatched!");
+                return Err(corruption("checksum mismatched!".to_string()));
             }
-            records.push(json);
+            let json = serde_json::from_slice::<ManifestRecord>(slice)
+                .map_err(|e| corruption(format!("invalid manifest record: {}", e)))?;
+            records.push((offset, json));
         }
+        let file = fs.open_append(path).context("failed to recover manifest")?;
         Ok((
             Self {
                 file: Arc::new(Mutex::new(file)),
@@ -74,12 +89,13 @@ impl Manifest {
 
     pub fn add_record_when_init(&self, record: ManifestRecord) -> Result<()> {
         let mut file = self.file.lock();
-        let mut buf = serde_json::to_vec(&record)?;
-        let hash = crc32fast::hash(&buf);
-        file.write_all(&(buf.len() as u64).to_be_bytes())?;
-        buf.put_u32(hash);
-        file.write_all(&buf)?;
-        file.sync_all()?;
+        let json = serde_json::to_vec(&record).map_err(|e| Error::Other(e.into()))?;
+        let mut buf = Vec::with_capacity(json.len() + 12);
+        buf.put_u64(json.len() as u64);
+        buf.put_slice(&json);
+        buf.put_u32(crc32fast::hash(&json));
+        file.append(&buf).context("failed to write manifest")?;
+        file.sync().context("failed to write manifest")?;
         Ok(())
     }
 }
@@ -3,16 +3,17 @@ use std::path::Path;
 use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
 
-use anyhow::Result;
 use bytes::Bytes;
 use crossbeam_skiplist::map::Entry;
 use crossbeam_skiplist::SkipMap;
 use ouroboros::self_referencing;
 
+use crate::error::Result;
+use crate::fs::FileSystem;
 use crate::iterators::StorageIterator;
-use crate::key::{KeyBytes, KeySlice, TS_DEFAULT};
+use crate::key::{KeyBytes, KeySlice, TS_DEFAULT, TS_RANGE_BEGIN, TS_RANGE_END};
 use crate::table::SsTableBuilder;
-use crate::wal::Wal;
+use crate::wal::{PreparedLog, Wal};
 
 /// A basic mem-table based on crossbeam-skiplist.
 ///
@@ -49,6 +50,25 @@ pub(crate) fn map_key_bound(bound: Bound<KeySlice>) -> Bound<KeyBytes> {
     }
 }
 
+/// Create the lower bound of a scan over all versions of the keys in a user key range. Versions
+/// are sorted by ts in descending order, so an excluded key is skipped past its oldest version.
+pub(crate) fn map_lower_key_bound(bound: Bound<&[u8]>) -> Bound<KeySlice<'_>> {
+    match bound {
+        Bound::Included(x) => Bound::Included(KeySlice::from_slice(x, TS_RANGE_BEGIN)),
+        Bound::Excluded(x) => Bound::Excluded(KeySlice::from_slice(x, TS_RANGE_END)),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// Create the upper bound of a scan over all versions of the keys in a user key range.
+pub(crate) fn map_upper_key_bound(bound: Bound<&[u8]>) -> Bound<KeySlice<'_>> {
+    match bound {
+        Bound::Included(x) => Bound::Included(KeySlice::from_slice(x, TS_RANGE_END)),
+        Bound::Excluded(x) => Bound::Excluded(KeySlice::from_slice(x, TS_RANGE_BEGIN)),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
 /// Create a bound of `Bytes` from a bound of `KeySlice`.
 pub(crate) fn map_key_bound_plus_ts(bound: Bound<&[u8]>, ts: u64) -> Bound<KeySlice> {
     match bound {
@@ -70,21 +90,27 @@ impl MemTable {
     }
 
     /// Create a new mem-table with WAL
-    pub fn create_with_wal(id: usize, path: impl AsRef<Path>) -> Result<Self> {
+    pub fn create_with_wal(fs: &dyn FileSystem, id: usize, path: impl AsRef<Path>) -> Result<Self> {
         Ok(Self {
             id,
             map: Arc::new(SkipMap::new()),
-            wal: Some(Wal::create(path.as_ref())?),
+            wal: Some(Wal::create(fs, path.as_ref())?),
             approximate_size: Arc::new(AtomicUsize::new(0)),
         })
     }
 
-    /// Create a memtable from WAL
-    pub fn recover_from_wal(id: usize, path: impl AsRef<Path>) -> Result<Self> {
+    /// Create a memtable from WAL. The two-phase commit records in the WAL are applied to
+    /// `prepared_log`.
+    pub fn recover_from_wal(
+        fs: &dyn FileSystem,
+        id: usize,
+        path: impl AsRef<Path>,
+        prepared_log: &mut PreparedLog,
+    ) -> Result<Self> {
         let map = Arc::new(SkipMap::new());
         Ok(Self {
             id,
-            wal: Some(Wal::recover(path.as_ref(), &map)?),
+            wal: Some(Wal::recover(fs, path.as_ref(), &map, prepared_log)?),
             map,
             approximate_size: Arc::new(AtomicUsize::new(0)),
         })
@@ -129,6 +155,10 @@ impl MemTable {
 
     /// Implement this in week 3, day 5.
     pub fn put_batch(&self, data: &[(KeySlice, &[u8])]) -> Result<()> {
+        // log first, so that a batch failed to be written to the WAL is not visible
+        if let Some(ref wal) = self.wal {
+            wal.put_batch(data)?;
+        }
         let mut estimated_size = 0;
         for (key, value) in data {
             estimated_size += key.raw_len() + value.len();
@@ -139,8 +169,38 @@ impl MemTable {
         }
         self.approximate_size
             .fetch_add(estimated_size, std::sync::atomic::Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Log a transaction prepared for two-phase commit to the WAL.
+    pub fn log_prepare(&self, name: &str, read_ts: u64, batch: &[(Bytes, Bytes)]) -> Result<()> {
+        if let Some(ref wal) = self.wal {
+            wal.prepare(name, read_ts, batch)?;
+        }
+        Ok(())
+    }
+
+    /// Put the write batch of a prepared transaction at `ts`, and log the commit to the WAL.
+    pub fn commit_prepared(&self, name: &str, ts: u64, batch: &[(Bytes, Bytes)]) -> Result<()> {
+        if let Some(ref wal) = self.wal {
+            wal.commit_prepared(name, ts, batch)?;
+        }
+        let mut estimated_size = 0;
+        for (key, value) in batch {
+            let key = KeySlice::from_slice(key, ts);
+            estimated_size += key.raw_len() + value.len();
+            self.map
+                .insert(key.to_key_vec().into_key_bytes(), value.clone());
+        }
+        self.approximate_size
+            .fetch_add(estimated_size, std::sync::atomic::Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Log the rollback of a prepared transaction to the WAL.
+    pub fn log_rollback_prepared(&self, name: &str) -> Result<()> {
         if let Some(ref wal) = self.wal {
-            wal.put_batch(data)?;
+            wal.rollback_prepared(name)?;
         }
         Ok(())
     }
@@ -235,7 +295,7 @@ impl StorageIterator for MemTableIterator {
         !self.borrow_item().0.is_empty()
     }
 
-    fn next(&mut self) -> Result<()> {
+    fn next(&mut self) -> anyhow::Result<()> {
         let entry = self.with_iter_mut(|iter| MemTableIterator::entry_to_item(iter.next()));
         self.with_mut(|x| *x.item = entry);
         Ok(())
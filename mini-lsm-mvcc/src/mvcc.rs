@@ -1,43 +1,92 @@
 #![allow(unused_variables)] // TODO(you): remove this lint after implementing this mod
 #![allow(dead_code)] // TODO(you): remove this lint after implementing this mod
 
+pub(crate) mod lock_manager;
+pub mod pessimistic_txn;
+pub mod snapshot;
 pub mod txn;
 pub mod watermark;
 
 use std::{
-    collections::{BTreeMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap},
     sync::{atomic::AtomicBool, Arc},
+    time::Duration,
 };
 
+use bytes::Bytes;
 use crossbeam_skiplist::SkipMap;
 use parking_lot::Mutex;
 
+use crate::error::{Error, Result};
 use crate::lsm_storage::LsmStorageInner;
 
-use self::{txn::Transaction, watermark::Watermark};
+use self::{
+    lock_manager::LockManager,
+    pessimistic_txn::PessimisticTransaction,
+    snapshot::Snapshot,
+    txn::{ReadWriteSet, Savepoints, Transaction},
+    watermark::Watermark,
+};
+
+/// The isolation level of a transaction. All levels read from a snapshot at `read_ts`, and differ
+/// in what is validated on commit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IsolationLevel {
+    /// No validation on commit. Concurrent writes to the same key are resolved by the last writer.
+    SnapshotRead,
+    /// Abort the commit if any key in the write set was committed by another transaction after
+    /// `read_ts` (write-write conflict).
+    SnapshotIsolation,
+    /// Abort the commit if any key in the read set was committed by another transaction after
+    /// `read_ts` (serializable snapshot isolation).
+    Serializable,
+}
 
 pub(crate) struct CommittedTxnData {
-    pub(crate) key_hashes: HashSet<u32>,
+    pub(crate) write_set: BTreeSet<Bytes>,
     #[allow(dead_code)]
     pub(crate) read_ts: u64,
     #[allow(dead_code)]
     pub(crate) commit_ts: u64,
 }
 
+/// A transaction prepared for two-phase commit. Its write batch is durable in the WAL, and it is
+/// committed or rolled back by name.
+pub(crate) struct PreparedTxnData {
+    pub(crate) read_ts: u64,
+    /// Key -> value, where an empty value is a delete.
+    pub(crate) batch: Vec<(Bytes, Bytes)>,
+}
+
 pub(crate) struct LsmMvccInner {
     pub(crate) write_lock: Mutex<()>,
     pub(crate) commit_lock: Mutex<()>,
     pub(crate) ts: Arc<Mutex<(u64, Watermark)>>,
+    /// The largest ts recorded in the manifest. A ts up to it can be handed out without writing
+    /// the manifest, and the latest commit ts starts from it after recovery.
+    pub(crate) reserved_ts: Mutex<u64>,
     pub(crate) committed_txns: Arc<Mutex<BTreeMap<u64, CommittedTxnData>>>,
+    /// Number of timestamps below the latest commit ts that are always kept readable.
+    pub(crate) history_retention: u64,
+    pub(crate) lock_manager: LockManager,
+    /// The GC safe point set by the caller. The watermark never advances past it.
+    pub(crate) safe_point: Mutex<Option<u64>>,
+    /// Prepared transactions that are not committed or rolled back yet, by name.
+    pub(crate) prepared_txns: Mutex<BTreeMap<String, PreparedTxnData>>,
 }
 
 impl LsmMvccInner {
-    pub fn new(initial_ts: u64) -> Self {
+    pub fn new(initial_ts: u64, history_retention: u64) -> Self {
         Self {
             write_lock: Mutex::new(()),
             commit_lock: Mutex::new(()),
             ts: Arc::new(Mutex::new((initial_ts, Watermark::new()))),
+            reserved_ts: Mutex::new(initial_ts),
             committed_txns: Arc::new(Mutex::new(BTreeMap::new())),
+            history_retention,
+            lock_manager: LockManager::new(),
+            safe_point: Mutex::new(None),
+            prepared_txns: Mutex::new(BTreeMap::new()),
         }
     }
 
@@ -49,26 +98,156 @@ impl LsmMvccInner {
         self.ts.lock().0 = ts;
     }
 
+    /// Record the write set of a committed transaction for conflict checks, and remove the data
+    /// of transactions committed below the read ts of all active transactions, which are never
+    /// checked against. Should be called with the commit lock held.
+    pub(crate) fn record_committed_txn(&self, write_set: BTreeSet<Bytes>, read_ts: u64, ts: u64) {
+        let mut committed_txns = self.committed_txns.lock();
+        let old_data = committed_txns.insert(
+            ts,
+            CommittedTxnData {
+                write_set,
+                read_ts,
+                commit_ts: ts,
+            },
+        );
+        assert!(old_data.is_none());
+
+        // remove unneeded txn data, regardless of the history kept for reads
+        let watermark = {
+            let ts = self.ts.lock();
+            ts.1.watermark().unwrap_or(ts.0)
+        };
+        while let Some(entry) = committed_txns.first_entry() {
+            if *entry.key() < watermark {
+                entry.remove();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Whether the key is in the write set of a transaction or a write committed after `ts`. The
+    /// commits are kept down to the read ts of the oldest active transaction, so `ts` should be
+    /// no smaller than the read ts of the caller.
+    pub(crate) fn is_committed_after(&self, key: &[u8], ts: u64) -> bool {
+        self.committed_txns
+            .lock()
+            .range((ts + 1)..)
+            .any(|(_, txn_data)| txn_data.write_set.contains(key))
+    }
+
     /// All ts (strictly) below this ts can be garbage collected.
     pub fn watermark(&self) -> u64 {
         let ts = self.ts.lock();
-        ts.1.watermark().unwrap_or(ts.0)
+        self.watermark_inner(&ts)
+    }
+
+    fn watermark_inner(&self, ts: &(u64, Watermark)) -> u64 {
+        let watermark = ts.1.watermark().unwrap_or(ts.0);
+        let watermark = watermark.min(ts.0.saturating_sub(self.history_retention));
+        match *self.safe_point.lock() {
+            Some(safe_point) => watermark.min(safe_point),
+            None => watermark,
+        }
     }
 
-    pub fn new_txn(&self, inner: Arc<LsmStorageInner>, serializable: bool) -> Arc<Transaction> {
+    /// Set the GC safe point. It cannot move backwards, nor be below the current watermark, as the
+    /// versions below the watermark may have been garbage collected.
+    pub fn set_safe_point(&self, ts: u64) -> Result<()> {
+        let ts_guard = self.ts.lock();
+        if let Some(safe_point) = *self.safe_point.lock() {
+            if ts < safe_point {
+                return Err(Error::InvalidArgument(format!(
+                    "cannot move the safe point back from {} to {}",
+                    safe_point, ts
+                )));
+            }
+        }
+        let watermark = self.watermark_inner(&ts_guard);
+        if ts < watermark {
+            return Err(Error::InvalidArgument(format!(
+                "cannot set the safe point to {}, which is below the watermark {}",
+                ts, watermark
+            )));
+        }
+        *self.safe_point.lock() = Some(ts);
+        Ok(())
+    }
+
+    pub fn new_snapshot(&self, inner: Arc<LsmStorageInner>) -> Arc<Snapshot> {
+        let mut ts = self.ts.lock();
+        let read_ts = ts.0;
+        ts.1.add_reader(read_ts);
+        Arc::new(Snapshot { inner, read_ts })
+    }
+
+    /// Create a snapshot at a caller-provided timestamp. The timestamp must not have been garbage
+    /// collected, i.e., it should be no smaller than the current watermark. A timestamp above the
+    /// latest commit ts becomes the latest commit ts, so that nothing is committed below it later.
+    pub fn new_snapshot_at(
+        &self,
+        inner: Arc<LsmStorageInner>,
+        read_ts: u64,
+    ) -> Result<Arc<Snapshot>> {
+        if read_ts == crate::key::TS_RANGE_BEGIN {
+            return Err(Error::InvalidArgument(format!(
+                "read ts {} is out of range",
+                read_ts
+            )));
+        }
+        let write_lock = (read_ts > self.latest_commit_ts()).then(|| self.write_lock.lock());
+        if write_lock.is_some() {
+            inner.reserve_ts(read_ts, false)?;
+        }
+        let mut ts = self.ts.lock();
+        if read_ts > ts.0 {
+            ts.0 = read_ts;
+        }
+        let watermark = self.watermark_inner(&ts);
+        if read_ts < watermark {
+            return Err(Error::InvalidArgument(format!(
+                "cannot read at ts={}, versions below watermark {} may have been garbage collected",
+                read_ts, watermark
+            )));
+        }
+        ts.1.add_reader(read_ts);
+        Ok(Arc::new(Snapshot { inner, read_ts }))
+    }
+
+    pub fn new_txn(
+        &self,
+        inner: Arc<LsmStorageInner>,
+        isolation: IsolationLevel,
+    ) -> Arc<Transaction> {
         let mut ts = self.ts.lock();
         let read_ts = ts.0;
         ts.1.add_reader(read_ts);
         Arc::new(Transaction {
             inner,
             read_ts,
+            isolation,
             local_storage: Arc::new(SkipMap::new()),
             committed: Arc::new(AtomicBool::new(false)),
-            key_hashes: if serializable {
-                Some(Mutex::new((HashSet::new(), HashSet::new())))
-            } else {
-                None
-            },
+            rw_set: Mutex::new(ReadWriteSet::default()),
+            savepoints: Mutex::new(Savepoints::default()),
+            prepared_name: Mutex::new(None),
+        })
+    }
+
+    pub fn new_pessimistic_txn(
+        &self,
+        inner: Arc<LsmStorageInner>,
+        lock_timeout: Duration,
+    ) -> Arc<PessimisticTransaction> {
+        // conflicts are prevented by locks, so there is nothing to validate on commit
+        let txn = self.new_txn(inner, IsolationLevel::SnapshotRead);
+        Arc::new(PessimisticTransaction {
+            id: self.lock_manager.next_txn_id(),
+            txn,
+            lock_timeout,
+            locked_keys: Mutex::new(Vec::new()),
+            read_for_update: Mutex::new(HashMap::new()),
         })
     }
 }
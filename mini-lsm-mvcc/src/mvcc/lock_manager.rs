@@ -0,0 +1,112 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use parking_lot::{Condvar, Mutex};
+
+use crate::error::{Error, Result};
+
+#[derive(Default)]
+struct LockTable {
+    /// Key -> the transaction holding the lock.
+    holders: HashMap<Bytes, u64>,
+    /// The wait-for graph. A transaction waits for at most one lock at a time, so there is at most
+    /// one outgoing edge for each transaction: waiting transaction -> lock holder.
+    waits_for: HashMap<u64, u64>,
+}
+
+impl LockTable {
+    /// Check if `txn_id` waiting for `holder` would close a cycle in the wait-for graph.
+    fn would_deadlock(&self, txn_id: u64, holder: u64) -> bool {
+        let mut current = holder;
+        loop {
+            if current == txn_id {
+                return true;
+            }
+            match self.waits_for.get(&current) {
+                Some(next) => current = *next,
+                None => return false,
+            }
+        }
+    }
+}
+
+/// Exclusive per-key locks for pessimistic transactions.
+pub(crate) struct LockManager {
+    table: Mutex<LockTable>,
+    released: Condvar,
+    next_txn_id: AtomicU64,
+}
+
+impl LockManager {
+    pub fn new() -> Self {
+        Self {
+            table: Mutex::new(LockTable::default()),
+            released: Condvar::new(),
+            next_txn_id: AtomicU64::new(1),
+        }
+    }
+
+    pub fn next_txn_id(&self) -> u64 {
+        self.next_txn_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Lock a key for the transaction, waiting for at most `timeout` if the key is locked by
+    /// another transaction. Locking a key that is already held by the transaction is a no-op.
+    /// Returns whether the lock was newly acquired.
+    pub fn lock(&self, txn_id: u64, key: &[u8], timeout: Duration) -> Result<bool> {
+        let deadline = Instant::now() + timeout;
+        let mut table = self.table.lock();
+        loop {
+            match table.holders.get(key).copied() {
+                None => {
+                    table.waits_for.remove(&txn_id);
+                    table.holders.insert(Bytes::copy_from_slice(key), txn_id);
+                    return Ok(true);
+                }
+                Some(holder) if holder == txn_id => return Ok(false),
+                Some(holder) => {
+                    if table.would_deadlock(txn_id, holder) {
+                        table.waits_for.remove(&txn_id);
+                        return Err(Error::Busy(format!(
+                            "deadlock detected when locking key {:?}",
+                            Bytes::copy_from_slice(key)
+                        )));
+                    }
+                    if Instant::now() >= deadline {
+                        table.waits_for.remove(&txn_id);
+                        return Err(Error::Busy(format!(
+                            "lock wait timeout on key {:?}",
+                            Bytes::copy_from_slice(key)
+                        )));
+                    }
+                    table.waits_for.insert(txn_id, holder);
+                    self.released.wait_until(&mut table, deadline);
+                }
+            }
+        }
+    }
+
+    /// Number of transactions waiting for a lock.
+    pub fn num_waiters(&self) -> usize {
+        self.table.lock().waits_for.len()
+    }
+
+    /// Release the locks held by the transaction and wake up the waiters.
+    pub fn unlock_all(&self, txn_id: u64, keys: impl IntoIterator<Item = Bytes>) {
+        let mut table = self.table.lock();
+        for key in keys {
+            if table.holders.get(&key) == Some(&txn_id) {
+                table.holders.remove(&key);
+            }
+        }
+        table.waits_for.remove(&txn_id);
+        // the transaction no longer holds any lock, so nobody is waiting for it
+        table.waits_for.retain(|_, holder| *holder != txn_id);
+        drop(table);
+        self.released.notify_all();
+    }
+}
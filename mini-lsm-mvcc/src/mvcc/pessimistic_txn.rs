@@ -0,0 +1,138 @@
+use std::{collections::HashMap, ops::Bound, sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+
+use super::txn::{Transaction, TxnIterator};
+use crate::error::{Error, Result};
+use crate::lsm_storage::check_key_value;
+
+/// A transaction that takes row locks before writing instead of validating on commit. Plain reads
+/// are still snapshot reads at `read_ts`, while `get_for_update` locks the key and reads its latest
+/// committed value. Locks are held until the transaction commits or is dropped. A write fails
+/// with a conflict if the key was committed after the transaction last read it, at `read_ts` or
+/// by `get_for_update`.
+///
+/// Only pessimistic transactions take locks, so writes from optimistic transactions or the
+/// non-transactional APIs are not blocked by them.
+pub struct PessimisticTransaction {
+    pub(crate) id: u64,
+    pub(crate) txn: Arc<Transaction>,
+    pub(crate) lock_timeout: Duration,
+    pub(crate) locked_keys: Mutex<Vec<Bytes>>,
+    /// The ts at which `get_for_update` read the latest value of the keys.
+    pub(crate) read_for_update: Mutex<HashMap<Bytes, u64>>,
+}
+
+impl PessimisticTransaction {
+    pub fn read_ts(&self) -> u64 {
+        self.txn.read_ts
+    }
+
+    fn lock(&self, key: &[u8]) -> Result<()> {
+        if self
+            .txn
+            .inner
+            .mvcc()
+            .lock_manager
+            .lock(self.id, key, self.lock_timeout)?
+        {
+            self.locked_keys.lock().push(Bytes::copy_from_slice(key));
+        }
+        Ok(())
+    }
+
+    /// Lock the key for a write, and check that it was not committed after this transaction last
+    /// read it, so that the write does not overwrite a version the transaction never saw.
+    fn lock_for_write(&self, key: &[u8]) -> Result<()> {
+        self.lock(key)?;
+        let read_ts = self
+            .read_for_update
+            .lock()
+            .get(key)
+            .copied()
+            .unwrap_or(self.txn.read_ts);
+        if self.txn.inner.mvcc().is_committed_after(key, read_ts) {
+            return Err(Error::Conflict(format!(
+                "write-write conflict with a commit after ts {}",
+                read_ts
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        self.txn.get(key)
+    }
+
+    /// Lock the key and read its latest committed value, or the value written by this transaction.
+    pub fn get_for_update(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        self.lock(key)?;
+        if let Some(entry) = self.txn.local_storage.get(key) {
+            if entry.value().is_empty() {
+                return Ok(None);
+            } else {
+                return Ok(Some(entry.value().clone()));
+            }
+        }
+        let inner = &self.txn.inner;
+        let read_ts = inner.mvcc().latest_commit_ts();
+        self.read_for_update
+            .lock()
+            .insert(Bytes::copy_from_slice(key), read_ts);
+        inner.get_with_ts(key, read_ts)
+    }
+
+    pub fn scan(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<TxnIterator> {
+        self.txn.scan(lower, upper)
+    }
+
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        check_key_value(key, Some(value))?;
+        self.lock_for_write(key)?;
+        self.txn.put(key, value)
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        check_key_value(key, None)?;
+        self.lock_for_write(key)?;
+        self.txn.delete(key)
+    }
+
+    /// Set a savepoint. Locks taken after the savepoint are kept when rolling back to it.
+    pub fn set_savepoint(&self) -> Result<()> {
+        self.txn.set_savepoint()
+    }
+
+    pub fn rollback_to_savepoint(&self) -> Result<()> {
+        self.txn.rollback_to_savepoint()
+    }
+
+    /// Undo all writes of the transaction. Locks are kept until commit or drop.
+    pub fn rollback(&self) -> Result<()> {
+        self.txn.rollback()
+    }
+
+    /// Prepare the transaction for two-phase commit. Locks are kept until the transaction is
+    /// committed or dropped.
+    pub fn prepare(&self, name: &str) -> Result<()> {
+        self.txn.prepare(name)
+    }
+
+    pub fn commit(&self) -> Result<()> {
+        let result = self.txn.commit();
+        self.unlock_all();
+        result
+    }
+
+    fn unlock_all(&self) {
+        let keys = std::mem::take(&mut *self.locked_keys.lock());
+        self.txn.inner.mvcc().lock_manager.unlock_all(self.id, keys);
+    }
+}
+
+impl Drop for PessimisticTransaction {
+    fn drop(&mut self) {
+        self.unlock_all();
+    }
+}
@@ -0,0 +1,79 @@
+use std::{ops::Bound, sync::Arc};
+
+use bytes::Bytes;
+
+use crate::{
+    error::Result,
+    iterators::StorageIterator,
+    lsm_iterator::{FusedIterator, LsmIterator},
+    lsm_storage::LsmStorageInner,
+};
+
+/// A read-only view of the storage at a fixed timestamp. Unlike `Transaction`, a snapshot does not
+/// buffer any writes. It is registered with the watermark so that the versions it can see are not
+/// garbage collected until it is dropped.
+pub struct Snapshot {
+    pub(crate) read_ts: u64,
+    pub(crate) inner: Arc<LsmStorageInner>,
+}
+
+impl Snapshot {
+    pub fn read_ts(&self) -> u64 {
+        self.read_ts
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        self.inner.get_with_ts(key, self.read_ts)
+    }
+
+    pub fn scan(
+        self: &Arc<Self>,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+    ) -> Result<SnapshotIterator> {
+        Ok(SnapshotIterator {
+            _snapshot: self.clone(),
+            iter: self.inner.scan_with_ts(lower, upper, self.read_ts)?,
+        })
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        self.inner.mvcc().ts.lock().1.remove_reader(self.read_ts)
+    }
+}
+
+/// An iterator over a snapshot. It holds a reference to the snapshot so that the read timestamp
+/// stays registered with the watermark while iterating.
+pub struct SnapshotIterator {
+    _snapshot: Arc<Snapshot>,
+    iter: FusedIterator<LsmIterator>,
+}
+
+impl StorageIterator for SnapshotIterator {
+    type KeyType<'a>
+        = &'a [u8]
+    where
+        Self: 'a;
+
+    fn value(&self) -> &[u8] {
+        self.iter.value()
+    }
+
+    fn key(&self) -> Self::KeyType<'_> {
+        self.iter.key()
+    }
+
+    fn is_valid(&self) -> bool {
+        self.iter.is_valid()
+    }
+
+    fn next(&mut self) -> anyhow::Result<()> {
+        self.iter.next()
+    }
+
+    fn num_active_iterators(&self) -> usize {
+        self.iter.num_active_iterators()
+    }
+}
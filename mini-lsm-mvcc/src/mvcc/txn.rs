@@ -1,5 +1,5 @@
 use std::{
-    collections::HashSet,
+    collections::BTreeSet,
     ops::Bound,
     sync::{
         atomic::{AtomicBool, Ordering},
@@ -7,39 +7,115 @@ use std::{
     },
 };
 
-use anyhow::{bail, Result};
 use bytes::Bytes;
 use crossbeam_skiplist::{map::Entry, SkipMap};
 use ouroboros::self_referencing;
 use parking_lot::Mutex;
 
 use crate::{
+    error::{Error, Result},
     iterators::{two_merge_iterator::TwoMergeIterator, StorageIterator},
     lsm_iterator::{FusedIterator, LsmIterator},
-    lsm_storage::{LsmStorageInner, WriteBatchRecord},
+    lsm_storage::{check_key_value, LsmStorageInner, WriteBatchRecord},
     mem_table::map_bound,
-    mvcc::CommittedTxnData,
+    mvcc::IsolationLevel,
 };
 
+/// The keys written by a transaction, and the keys and key ranges read by it.
+#[derive(Default, Debug)]
+pub(crate) struct ReadWriteSet {
+    pub(crate) write_set: BTreeSet<Bytes>,
+    pub(crate) read_set: BTreeSet<Bytes>,
+    pub(crate) read_ranges: Vec<(Bound<Bytes>, Bound<Bytes>)>,
+}
+
+/// Check if any key in `keys` falls into the range.
+pub(crate) fn range_contains_any(
+    keys: &BTreeSet<Bytes>,
+    lower: &Bound<Bytes>,
+    upper: &Bound<Bytes>,
+) -> bool {
+    // `BTreeSet::range` panics on an empty range, which users can pass in to `scan`
+    match (lower, upper) {
+        (Bound::Included(l), Bound::Included(u)) if l > u => return false,
+        (Bound::Included(_), Bound::Included(_)) => {}
+        (Bound::Included(l) | Bound::Excluded(l), Bound::Included(u) | Bound::Excluded(u))
+            if l >= u =>
+        {
+            return false
+        }
+        _ => {}
+    }
+    keys.range::<Bytes, _>((lower.clone(), upper.clone()))
+        .next()
+        .is_some()
+}
+
+/// A change to the transaction state that can be undone by rolling back to a savepoint.
+enum UndoRecord {
+    /// A write to the local storage, with the previous local value of the key.
+    LocalStorage(Bytes, Option<Bytes>),
+    /// A key newly added to the write set.
+    WriteSet(Bytes),
+    /// A key newly added to the read set.
+    ReadSet(Bytes),
+}
+
+struct Savepoint {
+    undo_log_len: usize,
+    read_ranges_len: usize,
+}
+
+/// Savepoints of a transaction, and the undo log recorded while any savepoint is set.
+#[derive(Default)]
+pub(crate) struct Savepoints {
+    stack: Vec<Savepoint>,
+    undo_log: Vec<UndoRecord>,
+}
+
+impl Savepoints {
+    fn record(&mut self, undo: UndoRecord) {
+        if !self.stack.is_empty() {
+            self.undo_log.push(undo);
+        }
+    }
+}
+
 pub struct Transaction {
     pub(crate) read_ts: u64,
     pub(crate) inner: Arc<LsmStorageInner>,
+    pub(crate) isolation: IsolationLevel,
     pub(crate) local_storage: Arc<SkipMap<Bytes, Bytes>>,
     pub(crate) committed: Arc<AtomicBool>,
-    /// Write set and read set
-    pub(crate) key_hashes: Option<Mutex<(HashSet<u32>, HashSet<u32>)>>,
+    /// Write set and read set. The write set is always tracked so that other transactions can
+    /// check against it, while the read set is only tracked for serializable transactions.
+    pub(crate) rw_set: Mutex<ReadWriteSet>,
+    pub(crate) savepoints: Mutex<Savepoints>,
+    /// The name of the transaction if it is prepared and not decided yet.
+    pub(crate) prepared_name: Mutex<Option<String>>,
 }
 
 impl Transaction {
-    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+    fn check_active(&self) -> Result<()> {
         if self.committed.load(Ordering::SeqCst) {
-            panic!("cannot operate on committed txn!");
-        }
-        if let Some(guard) = &self.key_hashes {
-            let mut guard = guard.lock();
-            let (_, read_set) = &mut *guard;
-            read_set.insert(farmhash::hash32(key));
+            return Err(Error::InvalidArgument(
+                "cannot operate on committed txn".to_string(),
+            ));
         }
+        Ok(())
+    }
+
+    /// Mark the transaction as committed before it is committed or prepared.
+    fn set_committed(&self) -> Result<()> {
+        self.committed
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .map_err(|_| Error::InvalidArgument("cannot operate on committed txn".to_string()))?;
+        Ok(())
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        self.check_active()?;
+        self.add_to_read_set(key);
         if let Some(entry) = self.local_storage.get(key) {
             if entry.value().is_empty() {
                 return Ok(None);
@@ -51,9 +127,7 @@ impl Transaction {
     }
 
     pub fn scan(self: &Arc<Self>, lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> Result<TxnIterator> {
-        if self.committed.load(Ordering::SeqCst) {
-            panic!("cannot operate on committed txn!");
-        }
+        self.check_active()?;
         let mut local_iter = TxnLocalIteratorBuilder {
             map: self.local_storage.clone(),
             iter_builder: |map| map.range((map_bound(lower), map_bound(upper))),
@@ -62,6 +136,7 @@ impl Transaction {
         .build();
         let entry = local_iter.with_iter_mut(|iter| TxnLocalIterator::entry_to_item(iter.next()));
         local_iter.with_mut(|x| *x.item = entry);
+        self.add_range_to_read_set(lower, upper);
 
         TxnIterator::create(
             self.clone(),
@@ -72,59 +147,187 @@ impl Transaction {
         )
     }
 
-    pub fn put(&self, key: &[u8], value: &[u8]) {
-        if self.committed.load(Ordering::SeqCst) {
-            panic!("cannot operate on committed txn!");
+    pub fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.check_active()?;
+        check_key_value(key, Some(value))?;
+        self.write(Bytes::copy_from_slice(key), Bytes::copy_from_slice(value));
+        Ok(())
+    }
+
+    pub fn delete(&self, key: &[u8]) -> Result<()> {
+        self.check_active()?;
+        check_key_value(key, None)?;
+        self.write(Bytes::copy_from_slice(key), Bytes::new());
+        Ok(())
+    }
+
+    fn write(&self, key: Bytes, value: Bytes) {
+        let mut savepoints = self.savepoints.lock();
+        let prev_value = self.local_storage.get(&key).map(|x| x.value().clone());
+        self.local_storage.insert(key.clone(), value);
+        savepoints.record(UndoRecord::LocalStorage(key.clone(), prev_value));
+        if self.rw_set.lock().write_set.insert(key.clone()) {
+            savepoints.record(UndoRecord::WriteSet(key));
         }
-        self.local_storage
-            .insert(Bytes::copy_from_slice(key), Bytes::copy_from_slice(value));
-        if let Some(key_hashes) = &self.key_hashes {
-            let mut key_hashes = key_hashes.lock();
-            let (write_hashes, _) = &mut *key_hashes;
-            write_hashes.insert(farmhash::hash32(key));
+    }
+
+    /// Set a savepoint. Writes made after it, and the keys they add to the read and write sets,
+    /// can be undone with `rollback_to_savepoint`.
+    pub fn set_savepoint(&self) -> Result<()> {
+        self.check_active()?;
+        let mut savepoints = self.savepoints.lock();
+        let read_ranges_len = self.rw_set.lock().read_ranges.len();
+        let undo_log_len = savepoints.undo_log.len();
+        savepoints.stack.push(Savepoint {
+            undo_log_len,
+            read_ranges_len,
+        });
+        Ok(())
+    }
+
+    /// Undo everything done after the most recent savepoint and remove the savepoint. The
+    /// transaction can still be used afterwards.
+    pub fn rollback_to_savepoint(&self) -> Result<()> {
+        self.check_active()?;
+        let mut savepoints = self.savepoints.lock();
+        let Some(savepoint) = savepoints.stack.pop() else {
+            return Err(Error::InvalidArgument(
+                "no savepoint to roll back to".to_string(),
+            ));
+        };
+        let mut rw_set = self.rw_set.lock();
+        while savepoints.undo_log.len() > savepoint.undo_log_len {
+            match savepoints.undo_log.pop().unwrap() {
+                UndoRecord::LocalStorage(key, Some(value)) => {
+                    self.local_storage.insert(key, value);
+                }
+                UndoRecord::LocalStorage(key, None) => {
+                    self.local_storage.remove(&key);
+                }
+                UndoRecord::WriteSet(key) => {
+                    rw_set.write_set.remove(&key);
+                }
+                UndoRecord::ReadSet(key) => {
+                    rw_set.read_set.remove(&key);
+                }
+            }
         }
+        rw_set.read_ranges.truncate(savepoint.read_ranges_len);
+        Ok(())
     }
 
-    pub fn delete(&self, key: &[u8]) {
-        if self.committed.load(Ordering::SeqCst) {
-            panic!("cannot operate on committed txn!");
+    /// Undo all writes and reads of the transaction and remove all savepoints. The transaction
+    /// can still be used afterwards, and keeps reading at the same `read_ts`.
+    pub fn rollback(&self) -> Result<()> {
+        self.check_active()?;
+        let mut savepoints = self.savepoints.lock();
+        *savepoints = Savepoints::default();
+        *self.rw_set.lock() = ReadWriteSet::default();
+        self.local_storage.clear();
+        Ok(())
+    }
+
+    pub fn isolation(&self) -> IsolationLevel {
+        self.isolation
+    }
+
+    fn add_to_read_set(&self, key: &[u8]) {
+        if self.isolation == IsolationLevel::Serializable {
+            let mut savepoints = self.savepoints.lock();
+            let key = Bytes::copy_from_slice(key);
+            if self.rw_set.lock().read_set.insert(key.clone()) {
+                savepoints.record(UndoRecord::ReadSet(key));
+            }
         }
-        self.local_storage
-            .insert(Bytes::copy_from_slice(key), Bytes::new());
-        if let Some(key_hashes) = &self.key_hashes {
-            let mut key_hashes = key_hashes.lock();
-            let (write_hashes, _) = &mut *key_hashes;
-            write_hashes.insert(farmhash::hash32(key));
+    }
+
+    /// Records the whole scanned range, so that a key inserted into the range by another
+    /// transaction (a phantom) is detected on commit, even if it was never returned by the scan.
+    fn add_range_to_read_set(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>) {
+        if self.isolation == IsolationLevel::Serializable {
+            self.rw_set
+                .lock()
+                .read_ranges
+                .push((map_bound(lower), map_bound(upper)));
         }
     }
 
-    pub fn commit(&self) -> Result<()> {
-        self.committed
-            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
-            .expect("cannot operate on committed txn!");
-        let _commit_lock = self.inner.mvcc().commit_lock.lock();
-        let serializability_check;
-        if let Some(guard) = &self.key_hashes {
-            let guard = guard.lock();
-            let (write_set, read_set) = &*guard;
+    /// Check the transaction against the transactions committed after `read_ts` and the prepared
+    /// transactions. Should be called with the commit lock held.
+    fn validate(&self) -> Result<()> {
+        let rw_set = self.rw_set.lock();
+        if self.isolation == IsolationLevel::Serializable {
             println!(
-                "commit txn: write_set: {:?}, read_set: {:?}",
-                write_set, read_set
+                "commit txn: write_set: {:?}, read_set: {:?}, read_ranges: {:?}",
+                rw_set.write_set, rw_set.read_set, rw_set.read_ranges
             );
-            if !write_set.is_empty() {
-                let committed_txns = self.inner.mvcc().committed_txns.lock();
-                for (_, txn_data) in committed_txns.range((self.read_ts + 1)..) {
-                    for key_hash in read_set {
-                        if txn_data.key_hashes.contains(key_hash) {
-                            bail!("serializable check failed");
+        }
+        if !rw_set.write_set.is_empty() && self.isolation != IsolationLevel::SnapshotRead {
+            let committed_txns = self.inner.mvcc().committed_txns.lock();
+            for (_, txn_data) in committed_txns.range((self.read_ts + 1)..) {
+                match self.isolation {
+                    IsolationLevel::SnapshotRead => {}
+                    IsolationLevel::SnapshotIsolation => {
+                        if !rw_set.write_set.is_disjoint(&txn_data.write_set) {
+                            return Err(Error::Conflict(
+                                "write-write conflict detected".to_string(),
+                            ));
+                        }
+                    }
+                    IsolationLevel::Serializable => {
+                        if !rw_set.read_set.is_disjoint(&txn_data.write_set)
+                            || rw_set.read_ranges.iter().any(|(lower, upper)| {
+                                range_contains_any(&txn_data.write_set, lower, upper)
+                            })
+                        {
+                            return Err(Error::Conflict("serializable check failed".to_string()));
                         }
                     }
                 }
             }
-            serializability_check = true;
-        } else {
-            serializability_check = false;
+            // a prepared transaction will be committed later, so it conflicts with any write to
+            // the same keys
+            let prepared_txns = self.inner.mvcc().prepared_txns.lock();
+            for (name, txn_data) in prepared_txns.iter() {
+                if txn_data
+                    .batch
+                    .iter()
+                    .any(|(key, _)| rw_set.write_set.contains(key))
+                {
+                    return Err(Error::Conflict(format!(
+                        "write-write conflict with prepared transaction {}",
+                        name
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Prepare the transaction for two-phase commit: validate it, and persist its write batch to
+    /// the WAL under `name`. The prepared transaction survives restarts, and is decided later by
+    /// `commit` or by `MiniLsm::commit_prepared` / `MiniLsm::rollback_prepared` with the name.
+    pub fn prepare(&self, name: &str) -> Result<()> {
+        self.set_committed()?;
+        let _commit_lock = self.inner.mvcc().commit_lock.lock();
+        self.validate()?;
+        let batch = self
+            .local_storage
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect::<Vec<_>>();
+        self.inner.prepare_batch(name, self.read_ts, batch)?;
+        *self.prepared_name.lock() = Some(name.to_string());
+        Ok(())
+    }
+
+    pub fn commit(&self) -> Result<()> {
+        if let Some(name) = self.prepared_name.lock().take() {
+            return self.inner.commit_prepared(&name);
         }
+        self.set_committed()?;
+        let _commit_lock = self.inner.mvcc().commit_lock.lock();
+        self.validate()?;
         let batch = self
             .local_storage
             .iter()
@@ -137,31 +340,10 @@ impl Transaction {
             })
             .collect::<Vec<_>>();
         let ts = self.inner.write_batch_inner(&batch)?;
-        if serializability_check {
-            let mut committed_txns = self.inner.mvcc().committed_txns.lock();
-            let mut key_hashes = self.key_hashes.as_ref().unwrap().lock();
-            let (write_set, _) = &mut *key_hashes;
-
-            let old_data = committed_txns.insert(
-                ts,
-                CommittedTxnData {
-                    key_hashes: std::mem::take(write_set),
-                    read_ts: self.read_ts,
-                    commit_ts: ts,
-                },
-            );
-            assert!(old_data.is_none());
-
-            // remove unneeded txn data
-            let watermark = self.inner.mvcc().watermark();
-            while let Some(entry) = committed_txns.first_entry() {
-                if *entry.key() < watermark {
-                    entry.remove();
-                } else {
-                    break;
-                }
-            }
-        }
+        let write_set = std::mem::take(&mut self.rw_set.lock().write_set);
+        self.inner
+            .mvcc()
+            .record_committed_txn(write_set, self.read_ts, ts);
         Ok(())
     }
 }
@@ -210,7 +392,7 @@ impl StorageIterator for TxnLocalIterator {
         !self.borrow_item().0.is_empty()
     }
 
-    fn next(&mut self) -> Result<()> {
+    fn next(&mut self) -> anyhow::Result<()> {
         let entry = self.with_iter_mut(|iter| TxnLocalIterator::entry_to_item(iter.next()));
         self.with_mut(|x| *x.item = entry);
         Ok(())
@@ -229,9 +411,6 @@ impl TxnIterator {
     ) -> Result<Self> {
         let mut iter = Self { txn, iter };
         iter.skip_deletes()?;
-        if iter.is_valid() {
-            iter.add_to_read_set(iter.key());
-        }
         Ok(iter)
     }
 
@@ -241,14 +420,6 @@ impl TxnIterator {
         }
         Ok(())
     }
-
-    fn add_to_read_set(&self, key: &[u8]) {
-        if let Some(guard) = &self.txn.key_hashes {
-            let mut guard = guard.lock();
-            let (_, read_set) = &mut *guard;
-            read_set.insert(farmhash::hash32(key));
-        }
-    }
 }
 
 impl StorageIterator for TxnIterator {
@@ -269,12 +440,9 @@ impl StorageIterator for TxnIterator {
         self.iter.is_valid()
     }
 
-    fn next(&mut self) -> Result<()> {
+    fn next(&mut self) -> anyhow::Result<()> {
         self.iter.next()?;
         self.skip_deletes()?;
-        if self.is_valid() {
-            self.add_to_read_set(self.key());
-        }
         Ok(())
     }
 
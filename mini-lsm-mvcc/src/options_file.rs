@@ -0,0 +1,123 @@
+//! The OPTIONS file in the DB dir. It records the options that decide how the files in the dir
+//! are laid out, so that reopening the DB with incompatible options fails with a clear error
+//! instead of misreading the files.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::compact::{CompactionOptions, LeveledCompactionOptions, SimpleLeveledCompactionOptions};
+use crate::error::{Error, IoResultExt, Result};
+use crate::fs::{write_file_atomic, FileSystem};
+use crate::lsm_storage::LsmStorageOptions;
+
+/// The part of `LsmStorageOptions` persisted in the OPTIONS file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PersistedOptions {
+    pub block_size: usize,
+    pub target_sst_size: usize,
+    pub num_memtable_limit: usize,
+    pub compaction_options: CompactionOptions,
+    pub enable_wal: bool,
+    pub serializable: bool,
+    pub history_retention: u64,
+    pub wal_ttl_seconds: u64,
+    pub wal_size_limit_mb: u64,
+}
+
+fn strategy_name(compaction_options: &CompactionOptions) -> &'static str {
+    match compaction_options {
+        CompactionOptions::Leveled(_) => "leveled",
+        CompactionOptions::Tiered(_) => "tiered",
+        CompactionOptions::Simple(_) => "simple leveled",
+        CompactionOptions::NoCompaction => "no compaction",
+    }
+}
+
+fn max_levels(compaction_options: &CompactionOptions) -> Option<usize> {
+    match compaction_options {
+        CompactionOptions::Leveled(LeveledCompactionOptions { max_levels, .. })
+        | CompactionOptions::Simple(SimpleLeveledCompactionOptions { max_levels, .. }) => {
+            Some(*max_levels)
+        }
+        _ => None,
+    }
+}
+
+impl PersistedOptions {
+    pub fn new(options: &LsmStorageOptions) -> Self {
+        Self {
+            block_size: options.block_size,
+            target_sst_size: options.target_sst_size,
+            num_memtable_limit: options.num_memtable_limit,
+            compaction_options: options.compaction_options.clone(),
+            enable_wal: options.enable_wal,
+            serializable: options.serializable,
+            history_retention: options.history_retention,
+            wal_ttl_seconds: options.wal_ttl_seconds,
+            wal_size_limit_mb: options.wal_size_limit_mb,
+        }
+    }
+
+    pub fn path(dir: impl AsRef<Path>) -> PathBuf {
+        dir.as_ref().join("OPTIONS")
+    }
+
+    /// Read the OPTIONS file in the DB dir, `None` if the DB is created before OPTIONS files.
+    pub fn read(fs: &dyn FileSystem, dir: &Path) -> Result<Option<Self>> {
+        let path = Self::path(dir);
+        if !fs.exists(&path) {
+            return Ok(None);
+        }
+        let data = fs.read(&path).context("failed to read OPTIONS")?;
+        let options = serde_json::from_slice(&data)
+            .map_err(|e| Error::corruption(&path, 0, format!("invalid OPTIONS file: {}", e)))?;
+        Ok(Some(options))
+    }
+
+    pub fn write(&self, fs: &dyn FileSystem, dir: &Path) -> Result<()> {
+        let data = serde_json::to_vec_pretty(self).map_err(|e| Error::Other(e.into()))?;
+        write_file_atomic(fs, &Self::path(dir), &data).context("failed to write OPTIONS")?;
+        Ok(())
+    }
+
+    /// Check that a DB created with these options can be opened with `options`.
+    /// `has_compaction_records` is whether the manifest records compactions, which are replayed
+    /// with the compaction strategy in `options`. Options that only affect new files or the
+    /// runtime can change freely.
+    pub fn check_compatible(
+        &self,
+        options: &PersistedOptions,
+        has_compaction_records: bool,
+    ) -> Result<()> {
+        let incompatible = |message: String| {
+            Err(Error::InvalidArgument(format!(
+                "incompatible options: {}",
+                message
+            )))
+        };
+        let (old, new) = (&self.compaction_options, &options.compaction_options);
+        if has_compaction_records {
+            if std::mem::discriminant(old) != std::mem::discriminant(new) {
+                return incompatible(format!(
+                    "the DB is compacted with the {} strategy, and cannot be opened with the {} strategy; change it with `MiniLsm::change_compaction_strategy` instead",
+                    strategy_name(old),
+                    strategy_name(new)
+                ));
+            }
+            if let (Some(old_levels), Some(new_levels)) = (max_levels(old), max_levels(new)) {
+                if new_levels < old_levels {
+                    return incompatible(format!(
+                        "max_levels cannot be decreased from {} to {} after compaction; change it with `MiniLsm::change_compaction_strategy` instead",
+                        old_levels, new_levels
+                    ));
+                }
+            }
+        }
+        // the memtables not flushed are only in the WALs
+        if self.enable_wal && !options.enable_wal {
+            return incompatible("the WAL cannot be disabled once enabled".to_string());
+        }
+        Ok(())
+    }
+}
@@ -1,21 +1,25 @@
 pub(crate) mod bloom;
 mod builder;
+pub(crate) mod filter;
+pub(crate) mod index;
 mod iterator;
 
-use std::fs::File;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use anyhow::{anyhow, bail, Result};
 pub use builder::SsTableBuilder;
 use bytes::{Buf, BufMut};
+pub use filter::{FilterPolicy, FilterType};
 pub use iterator::SsTableIterator;
 
-use crate::block::Block;
+use crate::block::{Block, BlockFormat};
+use crate::error::{Error, IoResultExt, Result};
+use crate::fs::{write_file_atomic, FileSystem, PosixFs, RandomAccessFile};
 use crate::key::{KeyBytes, KeySlice};
-use crate::lsm_storage::BlockCache;
+use crate::lsm_storage::{BlockCache, CachedBlock};
 
-use self::bloom::Bloom;
+use self::filter::Filter;
+use self::index::{IndexPartition, PartitionedIndex, TopLevelIndex, PARTITIONED_INDEX_FLAG};
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct BlockMeta {
@@ -28,22 +32,50 @@ pub struct BlockMeta {
 }
 
 impl BlockMeta {
+    /// The size of the encoded block meta.
+    fn encoded_len(&self) -> usize {
+        // The size of offset
+        std::mem::size_of::<u32>()
+            // The size of key length, actual key and timestamp
+            + encoded_key_len(self.first_key.as_key_slice())
+            // The size of key length, actual key and timestamp
+            + encoded_key_len(self.last_key.as_key_slice())
+    }
+
+    /// Encode one block meta to a buffer.
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.put_u32(self.offset as u32);
+        encode_key(self.first_key.as_key_slice(), buf);
+        encode_key(self.last_key.as_key_slice(), buf);
+    }
+
+    /// Decode one block meta from a buffer.
+    fn decode(buf: &mut &[u8]) -> Self {
+        let offset = buf.get_u32() as usize;
+        let first_key = decode_key(buf);
+        let last_key = decode_key(buf);
+        BlockMeta {
+            offset,
+            first_key,
+            last_key,
+        }
+    }
+
     /// Encode block meta to a buffer.
-    pub fn encode_block_meta(block_meta: &[BlockMeta], max_ts: u64, buf: &mut Vec<u8>) {
+    pub fn encode_block_meta(
+        block_meta: &[BlockMeta],
+        max_ts: u64,
+        format: BlockFormat,
+        buf: &mut Vec<u8>,
+    ) {
         let mut estimated_size = std::mem::size_of::<u32>(); // number of blocks
         for meta in block_meta {
-            // The size of offset
-            estimated_size += std::mem::size_of::<u32>();
-            // The size of key length
-            estimated_size += std::mem::size_of::<u16>();
-            // The size of actual key
-            estimated_size += meta.first_key.raw_len();
-            // The size of key length
-            estimated_size += std::mem::size_of::<u16>();
-            // The size of actual key
-            estimated_size += meta.last_key.raw_len();
+            estimated_size += meta.encoded_len();
         }
         estimated_size += std::mem::size_of::<u64>(); // max timestamp
+        if format != BlockFormat::Legacy {
+            estimated_size += std::mem::size_of::<u8>(); // block format version
+        }
         estimated_size += std::mem::size_of::<u32>(); // checksum
 
         // Reserve the space to improve performance, especially when the size of incoming data is
@@ -52,58 +84,95 @@ impl BlockMeta {
         let original_len = buf.len();
         buf.put_u32(block_meta.len() as u32);
         for meta in block_meta {
-            buf.put_u32(meta.offset as u32);
-            buf.put_u16(meta.first_key.key_len() as u16);
-            buf.put_slice(meta.first_key.key_ref());
-            buf.put_u64(meta.first_key.ts());
-            buf.put_u16(meta.last_key.key_len() as u16);
-            buf.put_slice(meta.last_key.key_ref());
-            buf.put_u64(meta.last_key.ts());
+            meta.encode(buf);
         }
         buf.put_u64(max_ts);
+        if format != BlockFormat::Legacy {
+            buf.put_u8(format.version());
+        }
         buf.put_u32(crc32fast::hash(&buf[original_len + 4..]));
         assert_eq!(estimated_size, buf.len() - original_len);
     }
 
-    /// Decode block meta from a buffer.
-    pub fn decode_block_meta(mut buf: &[u8]) -> Result<(Vec<BlockMeta>, u64)> {
+    /// Decode block meta from a buffer. The meta written before the block format version was
+    /// added has no version, and its blocks are in the legacy format.
+    pub fn decode_block_meta(mut buf: &[u8]) -> Result<(Vec<BlockMeta>, u64, BlockFormat)> {
         let mut block_meta = Vec::new();
         let num = buf.get_u32() as usize;
         let checksum = crc32fast::hash(&buf[..buf.remaining() - 4]);
         for _ in 0..num {
-            let offset = buf.get_u32() as usize;
-            let first_key_len = buf.get_u16() as usize;
-            let first_key =
-                KeyBytes::from_bytes_with_ts(buf.copy_to_bytes(first_key_len), buf.get_u64());
-            let last_key_len: usize = buf.get_u16() as usize;
-            let last_key =
-                KeyBytes::from_bytes_with_ts(buf.copy_to_bytes(last_key_len), buf.get_u64());
-            block_meta.push(BlockMeta {
-                offset,
-                first_key,
-                last_key,
-            });
+            block_meta.push(BlockMeta::decode(&mut buf));
         }
         let max_ts = buf.get_u64();
+        let version = if buf.remaining() > 4 {
+            Some(buf.get_u8())
+        } else {
+            None
+        };
         if buf.get_u32() != checksum {
-            bail!("meta checksum mismatched");
+            return Err(Error::corruption("", 0, "meta checksum mismatched"));
         }
+        let format = match version {
+            None => BlockFormat::Legacy,
+            Some(version) => decode_block_format(version)?,
+        };
 
-        Ok((block_meta, max_ts))
+        Ok((block_meta, max_ts, format))
     }
 }
 
-/// A file object.
-pub struct FileObject(Option<File>, u64);
+/// The memory of the block meta.
+fn block_meta_memory_size(block_meta: &[BlockMeta]) -> usize {
+    block_meta
+        .iter()
+        .map(|meta| {
+            std::mem::size_of::<BlockMeta>() + meta.first_key.raw_len() + meta.last_key.raw_len()
+        })
+        .sum()
+}
+
+/// The size of a key encoded by `encode_key`.
+fn encoded_key_len(key: KeySlice) -> usize {
+    std::mem::size_of::<u16>() + key.key_len() + std::mem::size_of::<u64>()
+}
+
+/// Encode a key with its length and timestamp to a buffer.
+fn encode_key(key: KeySlice, buf: &mut Vec<u8>) {
+    buf.put_u16(key.key_len() as u16);
+    buf.put_slice(key.key_ref());
+    buf.put_u64(key.ts());
+}
+
+/// Decode a key encoded by `encode_key` from a buffer.
+fn decode_key(buf: &mut &[u8]) -> KeyBytes {
+    let key_len = buf.get_u16() as usize;
+    KeyBytes::from_bytes_with_ts(buf.copy_to_bytes(key_len), buf.get_u64())
+}
+
+fn decode_block_format(version: u8) -> Result<BlockFormat> {
+    BlockFormat::from_version(version).ok_or_else(|| {
+        Error::corruption(
+            "",
+            0,
+            format!("unsupported block format version {}", version),
+        )
+    })
+}
+
+/// A file object, with the path of the file for error reporting.
+pub struct FileObject(Option<Box<dyn RandomAccessFile>>, u64, PathBuf);
 
 impl FileObject {
     pub fn read(&self, offset: u64, len: u64) -> Result<Vec<u8>> {
-        use std::os::unix::fs::FileExt;
+        if offset + len > self.1 {
+            return Err(self.corruption(offset, "read beyond the end of file"));
+        }
         let mut data = vec![0; len as usize];
         self.0
             .as_ref()
             .unwrap()
-            .read_exact_at(&mut data[..], offset)?;
+            .read_exact_at(&mut data[..], offset)
+            .context(&format!("failed to read {}", self.2.display()))?;
         Ok(data)
     }
 
@@ -111,37 +180,79 @@ impl FileObject {
         self.1
     }
 
+    pub fn path(&self) -> &Path {
+        &self.2
+    }
+
+    fn corruption(&self, offset: u64, message: &str) -> Error {
+        Error::corruption(&self.2, offset, message)
+    }
+
     /// Create a new file object (day 2) and write the file to the disk (day 4).
     pub fn create(path: &Path, data: Vec<u8>) -> Result<Self> {
-        std::fs::write(path, &data)?;
-        File::open(path)?.sync_all()?;
-        Ok(FileObject(
-            Some(File::options().read(true).write(false).open(path)?),
-            data.len() as u64,
-        ))
+        Self::create_in(&PosixFs, path, data)
+    }
+
+    /// Write the file through the file system and open it. The file is written atomically, so
+    /// that a crash does not leave a truncated file at `path`.
+    pub fn create_in(fs: &dyn FileSystem, path: &Path, data: Vec<u8>) -> Result<Self> {
+        write_file_atomic(fs, path, &data)
+            .context(&format!("failed to write {}", path.display()))?;
+        Self::open_in(fs, path)
     }
 
     pub fn open(path: &Path) -> Result<Self> {
-        let file = File::options().read(true).write(false).open(path)?;
-        let size = file.metadata()?.len();
-        Ok(FileObject(Some(file), size))
+        Self::open_in(&PosixFs, path)
+    }
+
+    pub fn open_in(fs: &dyn FileSystem, path: &Path) -> Result<Self> {
+        let context = format!("failed to open {}", path.display());
+        let file = fs.open_random_access(path).context(&context)?;
+        let size = fs.metadata(path).context(&context)?.len;
+        Ok(FileObject(Some(file), size, path.to_path_buf()))
     }
 }
 
+/// The parts of the SST meta that are kept in memory for as long as the SST is open, instead of
+/// loaded on demand through the block cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SstPinning {
+    /// Keep the top-level index of a partitioned index.
+    pub top_level_index: bool,
+    /// Keep the filter.
+    pub filter: bool,
+}
+
+impl SstPinning {
+    pub const ALL: SstPinning = SstPinning {
+        top_level_index: true,
+        filter: true,
+    };
+}
+
 /// An SSTable.
 pub struct SsTable {
     /// The actual storage unit of SsTable, the format is as above.
     pub(crate) file: FileObject,
-    /// The meta blocks that hold info for data blocks.
+    /// The meta blocks that hold info for data blocks, empty if the SST has a partitioned index.
     pub(crate) block_meta: Vec<BlockMeta>,
     /// The offset that indicates the start point of meta blocks in `file`.
     pub(crate) block_meta_offset: usize,
+    /// The top-level index, if the block meta is split into index partitions that are loaded on
+    /// demand.
+    pub(crate) partitioned_index: Option<PartitionedIndex>,
     id: usize,
     block_cache: Option<Arc<BlockCache>>,
     first_key: KeyBytes,
     last_key: KeyBytes,
-    pub(crate) bloom: Option<Bloom>,
+    /// The filter, if it is pinned.
+    pub(crate) bloom: Option<Filter>,
+    /// The offset and the end offset of the filter, which is loaded on demand through the block
+    /// cache if it is not pinned. `None` if the SST has no filter.
+    filter_range: Option<(usize, usize)>,
     max_ts: u64,
+    /// The encoding of the entries in the data blocks.
+    block_format: BlockFormat,
 }
 impl SsTable {
     #[cfg(test)]
@@ -151,25 +262,73 @@ impl SsTable {
 
     /// Open SSTable from a file.
     pub fn open(id: usize, block_cache: Option<Arc<BlockCache>>, file: FileObject) -> Result<Self> {
+        Self::open_with(id, block_cache, file, SstPinning::ALL)
+    }
+
+    /// Open SSTable from a file. The parts of the meta that are not pinned are loaded on demand
+    /// through the block cache.
+    pub fn open_with(
+        id: usize,
+        block_cache: Option<Arc<BlockCache>>,
+        file: FileObject,
+        pinning: SstPinning,
+    ) -> Result<Self> {
         let len = file.size();
         let raw_bloom_offset = file.read(len - 4, 4)?;
         let bloom_offset = (&raw_bloom_offset[..]).get_u32() as u64;
-        let raw_bloom = file.read(bloom_offset, len - 4 - bloom_offset)?;
-        let bloom_filter = Bloom::decode(&raw_bloom)?;
+        // an SST without a filter has an empty filter section
+        let filter_range =
+            (bloom_offset < len - 4).then_some((bloom_offset as usize, len as usize - 4));
+        let bloom_filter = if pinning.filter && filter_range.is_some() {
+            let raw_bloom = file.read(bloom_offset, len - 4 - bloom_offset)?;
+            Some(Filter::decode(&raw_bloom).map_err(|e| e.in_file(file.path(), bloom_offset))?)
+        } else {
+            None
+        };
         let raw_meta_offset = file.read(bloom_offset - 4, 4)?;
-        let block_meta_offset = (&raw_meta_offset[..]).get_u32() as u64;
+        let meta_offset = (&raw_meta_offset[..]).get_u32();
+        if meta_offset & PARTITIONED_INDEX_FLAG != 0 {
+            let top_level_offset = (meta_offset & !PARTITIONED_INDEX_FLAG) as u64;
+            let raw_meta = file.read(top_level_offset, bloom_offset - 4 - top_level_offset)?;
+            let meta = TopLevelIndex::decode(&raw_meta, top_level_offset as usize)
+                .map_err(|e| e.in_file(file.path(), top_level_offset))?;
+            let block_meta_offset = meta.index.partitions[0].offset;
+            return Ok(Self {
+                file,
+                block_meta: Vec::new(),
+                block_meta_offset,
+                partitioned_index: Some(PartitionedIndex {
+                    range: (top_level_offset as usize, bloom_offset as usize - 4),
+                    num_blocks: meta.num_blocks,
+                    pinned: pinning.top_level_index.then(|| Arc::new(meta.index)),
+                }),
+                id,
+                block_cache,
+                first_key: meta.first_key,
+                last_key: meta.last_key,
+                bloom: bloom_filter,
+                filter_range,
+                max_ts: meta.max_ts,
+                block_format: meta.block_format,
+            });
+        }
+        let block_meta_offset = meta_offset as u64;
         let raw_meta = file.read(block_meta_offset, bloom_offset - 4 - block_meta_offset)?;
-        let (block_meta, max_ts) = BlockMeta::decode_block_meta(&raw_meta[..])?;
+        let (block_meta, max_ts, block_format) = BlockMeta::decode_block_meta(&raw_meta[..])
+            .map_err(|e| e.in_file(file.path(), block_meta_offset))?;
         Ok(Self {
             file,
             first_key: block_meta.first().unwrap().first_key.clone(),
             last_key: block_meta.last().unwrap().last_key.clone(),
             block_meta,
             block_meta_offset: block_meta_offset as usize,
+            partitioned_index: None,
             id,
             block_cache,
-            bloom: Some(bloom_filter),
+            bloom: bloom_filter,
+            filter_range,
             max_ts,
+            block_format,
         })
     }
 
@@ -181,25 +340,107 @@ impl SsTable {
         last_key: KeyBytes,
     ) -> Self {
         Self {
-            file: FileObject(None, file_size),
+            file: FileObject(None, file_size, PathBuf::new()),
             block_meta: vec![],
             block_meta_offset: 0,
+            partitioned_index: None,
             id,
             block_cache: None,
             first_key,
             last_key,
             bloom: None,
+            filter_range: None,
             max_ts: 0,
+            block_format: BlockFormat::Fixed,
+        }
+    }
+
+    /// Read the block at the offset through the block cache, or from the disk if there is none.
+    fn read_cached(
+        &self,
+        offset: usize,
+        read: impl FnOnce() -> Result<CachedBlock>,
+    ) -> Result<CachedBlock> {
+        if let Some(ref block_cache) = self.block_cache {
+            block_cache
+                .try_get_with((self.id, offset), read)
+                .map_err(|e| e.duplicate())
+        } else {
+            read()
         }
     }
 
+    /// Get the top-level index of a partitioned index, with the block cache if it is not
+    /// pinned.
+    fn top_level_index(&self, index: &PartitionedIndex) -> Result<Arc<TopLevelIndex>> {
+        if let Some(pinned) = &index.pinned {
+            return Ok(pinned.clone());
+        }
+        let (offset, offset_end) = index.range;
+        let cached = self.read_cached(offset, || {
+            let data = self
+                .file
+                .read(offset as u64, (offset_end - offset) as u64)?;
+            let meta = TopLevelIndex::decode(&data, offset)
+                .map_err(|e| e.in_file(self.file.path(), offset as u64))?;
+            Ok(CachedBlock::TopLevelIndex(Arc::new(meta.index)))
+        })?;
+        match cached {
+            CachedBlock::TopLevelIndex(index) => Ok(index),
+            _ => unreachable!("not a top-level index at {}", offset),
+        }
+    }
+
+    /// Read an index partition of a partitioned index, with the block cache.
+    fn read_index_partition(
+        &self,
+        index: &TopLevelIndex,
+        partition_idx: usize,
+    ) -> Result<Arc<IndexPartition>> {
+        let (offset, offset_end) = index.partition_range(partition_idx);
+        let cached = self.read_cached(offset, || {
+            let data = self
+                .file
+                .read(offset as u64, (offset_end - offset) as u64)?;
+            let partition = IndexPartition::decode(&data)
+                .map_err(|e| e.in_file(self.file.path(), offset as u64))?;
+            Ok(CachedBlock::IndexPartition(Arc::new(partition)))
+        })?;
+        match cached {
+            CachedBlock::IndexPartition(partition) => Ok(partition),
+            _ => unreachable!("not an index partition at {}", offset),
+        }
+    }
+
+    /// Get the offset and the end offset of a block.
+    fn block_range(&self, block_idx: usize) -> Result<(usize, usize)> {
+        let Some(index) = &self.partitioned_index else {
+            let offset = self.block_meta[block_idx].offset;
+            let offset_end = self
+                .block_meta
+                .get(block_idx + 1)
+                .map_or(self.block_meta_offset, |x| x.offset);
+            return Ok((offset, offset_end));
+        };
+        let top_level_index = self.top_level_index(index)?;
+        let partition_idx = top_level_index.find_partition_of_block(block_idx);
+        let partition = self.read_index_partition(&top_level_index, partition_idx)?;
+        let idx = block_idx - top_level_index.partitions[partition_idx].first_block_idx;
+        let offset = partition.block_meta[idx].offset;
+        let offset_end = partition
+            .block_meta
+            .get(idx + 1)
+            .map_or(partition.end_offset, |x| x.offset);
+        Ok((offset, offset_end))
+    }
+
     /// Read a block from the disk.
     pub fn read_block(&self, block_idx: usize) -> Result<Arc<Block>> {
-        let offset = self.block_meta[block_idx].offset;
-        let offset_end = self
-            .block_meta
-            .get(block_idx + 1)
-            .map_or(self.block_meta_offset, |x| x.offset);
+        let (offset, offset_end) = self.block_range(block_idx)?;
+        self.read_block_at(offset, offset_end)
+    }
+
+    fn read_block_at(&self, offset: usize, offset_end: usize) -> Result<Arc<Block>> {
         let block_len = offset_end - offset - 4;
         let block_data_with_chksum: Vec<u8> = self
             .file
@@ -207,33 +448,91 @@ impl SsTable {
         let block_data = &block_data_with_chksum[..block_len];
         let checksum = (&block_data_with_chksum[block_len..]).get_u32();
         if checksum != crc32fast::hash(block_data) {
-            bail!("block checksum mismatched");
+            return Err(self
+                .file
+                .corruption(offset as u64, "block checksum mismatched"));
         }
-        Ok(Arc::new(Block::decode(block_data)))
+        Ok(Arc::new(Block::decode_with_format(
+            block_data,
+            self.block_format,
+        )))
     }
 
     /// Read a block from disk, with block cache.
     pub fn read_block_cached(&self, block_idx: usize) -> Result<Arc<Block>> {
-        if let Some(ref block_cache) = self.block_cache {
-            let blk = block_cache
-                .try_get_with((self.id, block_idx), || self.read_block(block_idx))
-                .map_err(|e| anyhow!("{}", e))?;
-            Ok(blk)
-        } else {
-            self.read_block(block_idx)
+        let (offset, offset_end) = self.block_range(block_idx)?;
+        let cached = self.read_cached(offset, || {
+            Ok(CachedBlock::Data(self.read_block_at(offset, offset_end)?))
+        })?;
+        match cached {
+            CachedBlock::Data(block) => Ok(block),
+            _ => unreachable!("not a data block at {}", offset),
+        }
+    }
+
+    /// Check if the SST may contain the key with the filter, which is loaded through the block
+    /// cache if it is not pinned.
+    pub fn may_contain(&self, key: &[u8]) -> Result<bool> {
+        if let Some(bloom) = &self.bloom {
+            return Ok(bloom.may_contain(key));
         }
+        let Some((offset, offset_end)) = self.filter_range else {
+            return Ok(true);
+        };
+        let cached = self.read_cached(offset, || {
+            let data = self
+                .file
+                .read(offset as u64, (offset_end - offset) as u64)?;
+            let bloom =
+                Filter::decode(&data).map_err(|e| e.in_file(self.file.path(), offset as u64))?;
+            Ok(CachedBlock::Filter(Arc::new(bloom)))
+        })?;
+        match cached {
+            CachedBlock::Filter(bloom) => Ok(bloom.may_contain(key)),
+            _ => unreachable!("not a filter at {}", offset),
+        }
+    }
+
+    /// The memory of the pinned filter.
+    pub fn pinned_filter_size(&self) -> usize {
+        self.bloom.as_ref().map_or(0, |bloom| bloom.memory_size())
+    }
+
+    /// The memory of the pinned index, which is the flat block meta or the pinned top-level
+    /// index.
+    pub fn pinned_index_size(&self) -> usize {
+        let top_level_index = self
+            .partitioned_index
+            .as_ref()
+            .and_then(|index| index.pinned.as_ref())
+            .map_or(0, |index| index.memory_size());
+        block_meta_memory_size(&self.block_meta) + top_level_index
     }
 
     /// Find the block that may contain `key`.
-    pub fn find_block_idx(&self, key: KeySlice) -> usize {
-        self.block_meta
-            .partition_point(|meta| meta.first_key.as_key_slice() <= key)
-            .saturating_sub(1)
+    pub fn find_block_idx(&self, key: KeySlice) -> Result<usize> {
+        let Some(index) = &self.partitioned_index else {
+            return Ok(self
+                .block_meta
+                .partition_point(|meta| meta.first_key.as_key_slice() <= key)
+                .saturating_sub(1));
+        };
+        let top_level_index = self.top_level_index(index)?;
+        let partition_idx = top_level_index.find_partition_idx(key);
+        let partition = self.read_index_partition(&top_level_index, partition_idx)?;
+        Ok(top_level_index.partitions[partition_idx].first_block_idx
+            + partition
+                .block_meta
+                .partition_point(|meta| meta.first_key.as_key_slice() <= key)
+                .saturating_sub(1))
     }
 
     /// Get number of data blocks.
     pub fn num_of_blocks(&self) -> usize {
-        self.block_meta.len()
+        match &self.partitioned_index {
+            Some(index) => index.num_blocks,
+            None => self.block_meta.len(),
+        }
     }
 
     pub fn first_key(&self) -> &KeyBytes {
@@ -255,4 +554,8 @@ impl SsTable {
     pub fn max_ts(&self) -> u64 {
         self.max_ts
     }
+
+    pub fn block_format(&self) -> BlockFormat {
+        self.block_format
+    }
 }
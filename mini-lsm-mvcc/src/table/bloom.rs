@@ -1,8 +1,9 @@
 // Copyright 2021 TiKV Project Authors. Licensed under Apache-2.0.
 
-use anyhow::{bail, Result};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 
+use crate::error::{Error, Result};
+
 /// Implements a bloom filter
 pub struct Bloom {
     /// data of filter in bits
@@ -49,7 +50,11 @@ impl Bloom {
     pub fn decode(buf: &[u8]) -> Result<Self> {
         let checksum = (&buf[buf.len() - 4..buf.len()]).get_u32();
         if checksum != crc32fast::hash(&buf[..buf.len() - 4]) {
-            bail!("checksum mismatched for bloom filters");
+            return Err(Error::corruption(
+                "",
+                0,
+                "checksum mismatched for bloom filters",
+            ));
         }
         let filter = &buf[..buf.len() - 5];
         let k = buf[buf.len() - 5];
@@ -101,16 +106,21 @@ impl Bloom {
     }
 
     /// Check if a bloom filter may contain some data
-    pub fn may_contain(&self, mut h: u32) -> bool {
-        if self.k > 30 {
+    pub fn may_contain(&self, h: u32) -> bool {
+        Self::may_contain_in(&self.filter, self.k, h)
+    }
+
+    /// Check if the bits of a bloom filter with `k` hash functions may contain some data
+    pub(crate) fn may_contain_in(filter: &[u8], k: u8, mut h: u32) -> bool {
+        if k > 30 {
             // potential new encoding for short bloom filters
             true
         } else {
-            let nbits = self.filter.bit_len();
+            let nbits = filter.bit_len();
             let delta = h.rotate_left(15);
-            for _ in 0..self.k {
+            for _ in 0..k {
                 let bit_pos = h % (nbits as u32);
-                if !self.filter.get_bit(bit_pos as usize) {
+                if !filter.get_bit(bit_pos as usize) {
                     return false;
                 }
                 h = h.wrapping_add(delta);
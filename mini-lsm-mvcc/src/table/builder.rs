@@ -1,42 +1,157 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use anyhow::Result;
 use bytes::BufMut;
 
-use super::bloom::Bloom;
-use super::{BlockMeta, FileObject, SsTable};
-use crate::block::BlockBuilder;
+use super::filter::FilterPolicy;
+use super::index::{PartitionedIndex, TopLevelIndex, PARTITIONED_INDEX_FLAG};
+use super::{BlockMeta, FileObject, SsTable, SstPinning};
+use crate::block::{BlockBuilder, BlockFormat, DEFAULT_RESTART_INTERVAL};
+use crate::error::{IoResultExt, Result};
+use crate::fs::{rename_temp_file, temp_path, FileSystem, PosixFs, WritableFile};
 use crate::key::{KeySlice, KeyVec};
 use crate::lsm_storage::BlockCache;
 
+/// Where the finished blocks of an SSTable go.
+enum SstSink {
+    /// Buffered in memory, and written to the file at `build`.
+    Memory(Vec<u8>),
+    /// Appended to the temporary file of the SSTable, which is renamed to `path` at `build`.
+    File {
+        fs: Arc<dyn FileSystem>,
+        path: PathBuf,
+        file: Box<dyn WritableFile>,
+    },
+}
+
 /// Builds an SSTable from key-value pairs.
 pub struct SsTableBuilder {
     builder: BlockBuilder,
     first_key: KeyVec,
     last_key: KeyVec,
-    data: Vec<u8>,
+    sink: SstSink,
+    /// The size of the blocks finished so far.
+    data_len: usize,
+    /// The first error in writing the blocks to the file, returned by `build`.
+    write_error: Option<std::io::Error>,
     pub(crate) meta: Vec<BlockMeta>,
     block_size: usize,
-    key_hashes: Vec<u32>,
+    restart_interval: usize,
+    block_format: BlockFormat,
+    hash_index: bool,
+    /// The target size of the index partitions, 0 for a flat index.
+    index_partition_size: usize,
+    pinning: SstPinning,
+    filter_policy: FilterPolicy,
+    /// The hashes of the user keys for the filter, by the type of the filter policy.
+    key_hashes: Vec<u64>,
     max_ts: u64,
 }
 
 impl SsTableBuilder {
     /// Create a builder based on target block size.
     pub fn new(block_size: usize) -> Self {
+        Self::with_sink(SstSink::Memory(Vec::new()), block_size)
+    }
+
+    /// Create a builder that appends the blocks to the file as they are finished, so that only
+    /// one block and the index are held in memory. Build it with the same `path`.
+    pub fn new_streaming(
+        fs: Arc<dyn FileSystem>,
+        path: impl AsRef<Path>,
+        block_size: usize,
+    ) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = fs
+            .create(&temp_path(&path))
+            .context(&format!("failed to create {}", path.display()))?;
+        Ok(Self::with_sink(
+            SstSink::File { fs, path, file },
+            block_size,
+        ))
+    }
+
+    fn with_sink(sink: SstSink, block_size: usize) -> Self {
         Self {
-            data: Vec::new(),
+            sink,
+            data_len: 0,
+            write_error: None,
             meta: Vec::new(),
             first_key: KeyVec::new(),
             last_key: KeyVec::new(),
             block_size,
+            restart_interval: DEFAULT_RESTART_INTERVAL,
+            block_format: BlockFormat::Fixed,
+            hash_index: false,
+            index_partition_size: 0,
+            pinning: SstPinning::ALL,
+            filter_policy: FilterPolicy::default(),
             builder: BlockBuilder::new(block_size),
             key_hashes: Vec::new(),
             max_ts: 0,
         }
     }
 
+    /// Put a restart point every `restart_interval` entries in the blocks.
+    pub fn with_restart_interval(mut self, restart_interval: usize) -> Self {
+        assert!(
+            self.builder.is_empty(),
+            "restart interval set after adding keys"
+        );
+        self.restart_interval = restart_interval;
+        self.builder = self.new_block_builder();
+        self
+    }
+
+    /// Encode the entries of the blocks in `format`.
+    pub fn with_block_format(mut self, format: BlockFormat) -> Self {
+        assert!(
+            self.builder.is_empty(),
+            "block format set after adding keys"
+        );
+        self.block_format = format;
+        self.builder = self.new_block_builder();
+        self
+    }
+
+    /// Append a hash index of the user keys to the blocks.
+    pub fn with_hash_index(mut self, hash_index: bool) -> Self {
+        assert!(self.builder.is_empty(), "hash index set after adding keys");
+        self.hash_index = hash_index;
+        self.builder = self.new_block_builder();
+        self
+    }
+
+    /// Split the block meta into index partitions of about `partition_size` bytes, which are
+    /// loaded on demand, with a top-level index that points to them. 0 for a flat index.
+    pub fn with_partitioned_index(mut self, partition_size: usize) -> Self {
+        self.index_partition_size = partition_size;
+        self
+    }
+
+    /// Keep the parts of the meta in memory in the built SSTable, and load the others on demand
+    /// through the block cache.
+    pub fn with_pinning(mut self, pinning: SstPinning) -> Self {
+        self.pinning = pinning;
+        self
+    }
+
+    /// Build the filter of the SSTable with the policy.
+    pub fn with_filter_policy(mut self, filter_policy: FilterPolicy) -> Self {
+        assert!(
+            self.key_hashes.is_empty(),
+            "filter policy set after adding keys"
+        );
+        self.filter_policy = filter_policy;
+        self
+    }
+
+    fn new_block_builder(&self) -> BlockBuilder {
+        BlockBuilder::with_restart_interval(self.block_size, self.restart_interval)
+            .with_format(self.block_format)
+            .with_hash_index(self.hash_index)
+    }
+
     /// Adds a key-value pair to SSTable
     pub fn add(&mut self, key: KeySlice, value: &[u8]) {
         if self.first_key.is_empty() {
@@ -46,7 +161,13 @@ impl SsTableBuilder {
         if key.ts() > self.max_ts {
             self.max_ts = key.ts();
         }
-        self.key_hashes.push(farmhash::fingerprint32(key.key_ref()));
+        if let Some(filter_type) = self.filter_policy.filter_type() {
+            let key_hash = filter_type.key_hash(key.key_ref());
+            // the versions of a key are added together
+            if self.key_hashes.last() != Some(&key_hash) {
+                self.key_hashes.push(key_hash);
+            }
+        }
 
         if self.builder.add(key, value) {
             self.last_key.set_from_slice(key);
@@ -64,52 +185,131 @@ impl SsTableBuilder {
 
     /// Get the estimated size of the SSTable.
     pub fn estimated_size(&self) -> usize {
-        self.data.len()
+        self.data_len
     }
 
     fn finish_block(&mut self) {
-        let builder = std::mem::replace(&mut self.builder, BlockBuilder::new(self.block_size));
-        let encoded_block = builder.build().encode();
+        let new_builder = self.new_block_builder();
+        let builder = std::mem::replace(&mut self.builder, new_builder);
+        let mut encoded_block = builder.build().encode().to_vec();
         self.meta.push(BlockMeta {
-            offset: self.data.len(),
+            offset: self.data_len,
             first_key: std::mem::take(&mut self.first_key).into_key_bytes(),
             last_key: std::mem::take(&mut self.last_key).into_key_bytes(),
         });
         let checksum = crc32fast::hash(&encoded_block);
-        self.data.extend(encoded_block);
-        self.data.put_u32(checksum);
+        encoded_block.put_u32(checksum);
+        self.data_len += encoded_block.len();
+        self.write(&encoded_block);
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        match &mut self.sink {
+            SstSink::Memory(buf) => buf.extend_from_slice(data),
+            SstSink::File { file, .. } => {
+                // the blocks after a failed write are dropped, as the file is discarded anyway
+                if self.write_error.is_none() {
+                    if let Err(e) = file.append(data) {
+                        self.write_error = Some(e);
+                    }
+                }
+            }
+        }
     }
 
     /// Builds the SSTable and writes it to the given path. Use the `FileObject` structure to manipulate the disk objects.
     pub fn build(
+        self,
+        id: usize,
+        block_cache: Option<Arc<BlockCache>>,
+        path: impl AsRef<Path>,
+    ) -> Result<SsTable> {
+        self.build_in(&PosixFs, id, block_cache, path)
+    }
+
+    /// Builds the SSTable and writes it to the given path through the file system. A streaming
+    /// builder writes the rest of the file and renames it to the path it is created with.
+    pub fn build_in(
         mut self,
+        fs: &dyn FileSystem,
         id: usize,
         block_cache: Option<Arc<BlockCache>>,
         path: impl AsRef<Path>,
     ) -> Result<SsTable> {
         self.finish_block();
-        let mut buf = self.data;
-        let meta_offset = buf.len();
-        BlockMeta::encode_block_meta(&self.meta, self.max_ts, &mut buf);
-        buf.put_u32(meta_offset as u32);
-        let bloom = Bloom::build_from_key_hashes(
-            &self.key_hashes,
-            Bloom::bloom_bits_per_key(self.key_hashes.len(), 0.01),
-        );
-        let bloom_offset = buf.len();
-        bloom.encode(&mut buf);
+        let meta_offset = self.data_len;
+        let first_key = self.meta.first().unwrap().first_key.clone();
+        let last_key = self.meta.last().unwrap().last_key.clone();
+        let mut buf = Vec::new();
+        let partitioned_index = if self.index_partition_size > 0 {
+            let index = TopLevelIndex::build(
+                &self.meta,
+                meta_offset,
+                self.index_partition_size,
+                self.max_ts,
+                self.block_format,
+                &mut buf,
+            );
+            let top_level_offset = index.offset;
+            buf.put_u32(top_level_offset as u32 | PARTITIONED_INDEX_FLAG);
+            Some(PartitionedIndex {
+                range: (top_level_offset, meta_offset + buf.len() - 4),
+                num_blocks: self.meta.len(),
+                pinned: self.pinning.top_level_index.then(|| Arc::new(index)),
+            })
+        } else {
+            BlockMeta::encode_block_meta(&self.meta, self.max_ts, self.block_format, &mut buf);
+            buf.put_u32(meta_offset as u32);
+            None
+        };
+        let block_meta = if partitioned_index.is_some() {
+            Vec::new()
+        } else {
+            self.meta
+        };
+        // the filter section is empty without a filter
+        let bloom = self.filter_policy.build(&self.key_hashes);
+        let bloom_offset = meta_offset + buf.len();
+        let filter_range = bloom.as_ref().map(|bloom| {
+            bloom.encode(&mut buf);
+            (bloom_offset, meta_offset + buf.len())
+        });
         buf.put_u32(bloom_offset as u32);
-        let file = FileObject::create(path.as_ref(), buf)?;
+        let file = match self.sink {
+            SstSink::Memory(mut data) => {
+                data.extend(buf);
+                FileObject::create_in(fs, path.as_ref(), data)?
+            }
+            SstSink::File {
+                fs,
+                path: sst_path,
+                mut file,
+            } => {
+                debug_assert_eq!(sst_path, path.as_ref());
+                let context = format!("failed to write {}", sst_path.display());
+                if let Some(e) = self.write_error {
+                    return Err(e).context(&context);
+                }
+                file.append(&buf).context(&context)?;
+                file.sync().context(&context)?;
+                drop(file);
+                rename_temp_file(fs.as_ref(), &sst_path).context(&context)?;
+                FileObject::open_in(fs.as_ref(), &sst_path)?
+            }
+        };
         Ok(SsTable {
             id,
             file,
-            first_key: self.meta.first().unwrap().first_key.clone(),
-            last_key: self.meta.last().unwrap().last_key.clone(),
-            block_meta: self.meta,
+            first_key,
+            last_key,
+            block_meta,
             block_meta_offset: meta_offset,
+            partitioned_index,
             block_cache,
-            bloom: Some(bloom),
+            bloom: bloom.filter(|_| self.pinning.filter),
+            filter_range,
             max_ts: self.max_ts,
+            block_format: self.block_format,
         })
     }
 
@@ -0,0 +1,48 @@
+//! A bloom filter split into cache lines, where all the bits of a key are in the cache line chosen
+//! by the upper half of its hash, so that a probe touches one cache line.
+
+use bytes::BytesMut;
+
+use super::{Filter, FilterType};
+use crate::table::bloom::{BitSlice, BitSliceMut};
+
+/// The bits in a cache line.
+const LINE_BITS: usize = 512;
+
+/// The bits of a key, in the cache line chosen by the upper half of the hash and at the positions
+/// from the lower half, which is remixed for each hash function.
+fn bit_positions(h: u64, k: u8, num_lines: usize) -> impl Iterator<Item = usize> {
+    let line = (((h >> 32) * num_lines as u64) >> 32) as usize;
+    let mut h = h as u32;
+    (0..k).map(move |_| {
+        h = h.wrapping_mul(0x9e37_79b9);
+        line * LINE_BITS + (h >> 23) as usize
+    })
+}
+
+/// Build a blocked bloom filter from the 64-bit hashes of the keys.
+pub(super) fn build(key_hashes: &[u64], bits_per_key: usize) -> Filter {
+    let k = ((bits_per_key as f64 * 0.69) as u32).clamp(1, 30) as u8;
+    let num_lines = (key_hashes.len() * bits_per_key).div_ceil(LINE_BITS).max(1);
+    let mut filter = BytesMut::zeroed(num_lines * LINE_BITS / 8);
+    for h in key_hashes {
+        for bit in bit_positions(*h, k, num_lines) {
+            filter.set_bit(bit, true);
+        }
+    }
+    Filter {
+        filter: filter.freeze(),
+        k,
+        filter_type: FilterType::BlockedBloom,
+        seed: 0,
+    }
+}
+
+pub(super) fn is_valid(filter: &Filter) -> bool {
+    !filter.filter.is_empty() && filter.filter.len().is_multiple_of(LINE_BITS / 8)
+}
+
+pub(super) fn may_contain(filter: &Filter, h: u64) -> bool {
+    let num_lines = filter.filter.len() * 8 / LINE_BITS;
+    bit_positions(h, filter.k, num_lines).all(|bit| filter.filter.get_bit(bit))
+}
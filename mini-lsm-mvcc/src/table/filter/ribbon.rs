@@ -0,0 +1,137 @@
+//! A standard Ribbon filter (Dillinger and Walzer, 2021). Each key is an equation over the r-bit
+//! values of the slots: the XOR of the slots selected by a 64-bit coefficient row starting at a
+//! slot from the hash of the key is equal to an r-bit result from the hash. The values of the
+//! slots are a solution of the equations of all the keys, so that a key not in the filter matches
+//! with a probability of 2^-r.
+
+use bytes::BytesMut;
+
+use super::{Filter, FilterType};
+use crate::table::bloom::{BitSlice, BitSliceMut};
+
+/// The width of the coefficient rows.
+const COEFF_BITS: usize = 64;
+
+/// The extra slots over the keys, which make the equations unlikely to have no solution.
+const SLOT_OVERHEAD: f64 = 0.1;
+
+/// The number of seeds to try before adding more slots.
+const SEEDS_PER_SIZE: u8 = 4;
+
+/// Remix the hash of the key with the seed.
+fn remix(h: u64, seed: u8) -> u64 {
+    let mut h = h ^ (seed as u64 + 1).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^ (h >> 31)
+}
+
+/// The start slot, the coefficient row and the result of the equation of a key. The lowest bit
+/// of the coefficient row, which is at the start slot, is always set.
+fn equation(h: u64, seed: u8, num_slots: usize, result_bits: u8) -> (usize, u64, u32) {
+    let h = remix(h, seed);
+    let num_starts = (num_slots - COEFF_BITS + 1) as u64;
+    let start = (((h >> 32) * num_starts) >> 32) as usize;
+    let coeff = h.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+    let result = (h.wrapping_mul(0xc2b2_ae3d_27d4_eb4f) >> (64 - result_bits as u32)) as u32;
+    (start, coeff, result)
+}
+
+/// Solve the equations of the keys, `None` if they have no solution.
+fn solve(key_hashes: &[u64], num_slots: usize, result_bits: u8, seed: u8) -> Option<Vec<u32>> {
+    // the banded matrix after Gaussian elimination, where the coefficient row of a slot starts
+    // at the slot
+    let mut coeffs = vec![0u64; num_slots];
+    let mut results = vec![0u32; num_slots];
+    for h in key_hashes {
+        let (mut slot, mut coeff, mut result) = equation(*h, seed, num_slots, result_bits);
+        loop {
+            if coeffs[slot] == 0 {
+                coeffs[slot] = coeff;
+                results[slot] = result;
+                break;
+            }
+            coeff ^= coeffs[slot];
+            result ^= results[slot];
+            if coeff == 0 {
+                // the same equation as the other keys, or a contradiction
+                if result != 0 {
+                    return None;
+                }
+                break;
+            }
+            let shift = coeff.trailing_zeros();
+            slot += shift as usize;
+            coeff >>= shift;
+        }
+    }
+
+    // back substitution, where the slots without a row are free and left 0
+    let mut solution = vec![0u32; num_slots];
+    for slot in (0..num_slots).rev() {
+        let mut result = results[slot];
+        let mut coeff = coeffs[slot] >> 1;
+        while coeff != 0 {
+            result ^= solution[slot + 1 + coeff.trailing_zeros() as usize];
+            coeff &= coeff - 1;
+        }
+        solution[slot] = result;
+    }
+    Some(solution)
+}
+
+/// Build a Ribbon filter from the 64-bit hashes of the keys. The seed is changed until the
+/// equations of the keys have a solution, and more slots are added every few seeds.
+pub(super) fn build(key_hashes: &[u64], result_bits: u8) -> Filter {
+    let result_bits_usize = result_bits as usize;
+    // a multiple of 8, so that the number of slots is the bits of the solution divided by the
+    // result bits
+    let mut num_slots = ((key_hashes.len() as f64 * (1.0 + SLOT_OVERHEAD)) as usize + COEFF_BITS)
+        .next_multiple_of(8);
+    let mut seed = 0u8;
+    let solution = loop {
+        if let Some(solution) = solve(key_hashes, num_slots, result_bits, seed) {
+            break solution;
+        }
+        seed = seed.wrapping_add(1);
+        if seed.is_multiple_of(SEEDS_PER_SIZE) {
+            num_slots = (num_slots + num_slots / 8).next_multiple_of(8);
+        }
+    };
+
+    let mut filter = BytesMut::zeroed(num_slots * result_bits_usize / 8);
+    for (slot, value) in solution.into_iter().enumerate() {
+        for bit in 0..result_bits_usize {
+            if value & (1 << bit) != 0 {
+                filter.set_bit(slot * result_bits_usize + bit, true);
+            }
+        }
+    }
+    Filter {
+        filter: filter.freeze(),
+        k: result_bits,
+        filter_type: FilterType::Ribbon,
+        seed,
+    }
+}
+
+pub(super) fn is_valid(filter: &Filter) -> bool {
+    (1..=30).contains(&filter.k) && filter.filter.len() * 8 / filter.k as usize >= COEFF_BITS
+}
+
+pub(super) fn may_contain(filter: &Filter, h: u64) -> bool {
+    let result_bits = filter.k as usize;
+    let num_slots = filter.filter.len() * 8 / result_bits;
+    let (start, mut coeff, expected) = equation(h, filter.seed, num_slots, filter.k);
+    let mut result = 0;
+    while coeff != 0 {
+        let slot = start + coeff.trailing_zeros() as usize;
+        for bit in 0..result_bits {
+            if filter.filter.get_bit(slot * result_bits + bit) {
+                result ^= 1 << bit;
+            }
+        }
+        coeff &= coeff - 1;
+    }
+    result == expected
+}
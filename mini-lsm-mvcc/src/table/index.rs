@@ -0,0 +1,216 @@
+use std::sync::Arc;
+
+use bytes::{Buf, BufMut};
+
+use super::{block_meta_memory_size, decode_block_format, decode_key, encode_key, BlockMeta};
+use crate::block::BlockFormat;
+use crate::error::{Error, Result};
+use crate::key::{KeyBytes, KeySlice};
+
+/// The bit in the meta offset at the end of an SST that marks a partitioned index.
+pub(crate) const PARTITIONED_INDEX_FLAG: u32 = 1 << 31;
+
+/// A partition of the block meta of an SST, loaded on demand through the block cache.
+pub struct IndexPartition {
+    /// The meta of the data blocks in the partition.
+    pub(crate) block_meta: Vec<BlockMeta>,
+    /// The end offset of the last data block in the partition.
+    pub(crate) end_offset: usize,
+}
+
+impl IndexPartition {
+    /// Encode an index partition to a buffer.
+    fn encode(block_meta: &[BlockMeta], end_offset: usize, buf: &mut Vec<u8>) {
+        let original_len = buf.len();
+        buf.put_u32(block_meta.len() as u32);
+        for meta in block_meta {
+            meta.encode(buf);
+        }
+        buf.put_u32(end_offset as u32);
+        buf.put_u32(crc32fast::hash(&buf[original_len..]));
+    }
+
+    /// The memory of the index partition.
+    pub(crate) fn memory_size(&self) -> usize {
+        block_meta_memory_size(&self.block_meta)
+    }
+
+    /// Decode an index partition from a buffer.
+    pub(crate) fn decode(buf: &[u8]) -> Result<Self> {
+        let mut buf = split_checksum(buf, "index partition checksum mismatched")?;
+        let num = buf.get_u32() as usize;
+        let block_meta = (0..num).map(|_| BlockMeta::decode(&mut buf)).collect();
+        let end_offset = buf.get_u32() as usize;
+        Ok(Self {
+            block_meta,
+            end_offset,
+        })
+    }
+}
+
+/// The location of an index partition in the top-level index.
+pub struct PartitionHandle {
+    /// The offset of the partition.
+    pub(crate) offset: usize,
+    /// The index of the first data block in the partition.
+    pub(crate) first_block_idx: usize,
+    /// The first key of the first data block in the partition.
+    pub(crate) first_key: KeyBytes,
+}
+
+/// The top-level index of a partitioned index, which points to the index partitions. It is
+/// pinned in the SST, or loaded on demand through the block cache.
+pub struct TopLevelIndex {
+    pub(crate) partitions: Vec<PartitionHandle>,
+    /// The offset of the top-level index, which is the end of the last partition.
+    pub(crate) offset: usize,
+}
+
+/// The meta of an SST with a partitioned index, as decoded from the top-level index.
+pub(crate) struct PartitionedMeta {
+    pub(crate) index: TopLevelIndex,
+    pub(crate) num_blocks: usize,
+    pub(crate) first_key: KeyBytes,
+    pub(crate) last_key: KeyBytes,
+    pub(crate) max_ts: u64,
+    pub(crate) block_format: BlockFormat,
+}
+
+impl TopLevelIndex {
+    /// Split the block meta into partitions of about `partition_size` bytes, and encode the
+    /// partitions followed by the top-level index to a buffer. `offset` is the offset of the
+    /// buffer in the file. Returns the top-level index.
+    pub(crate) fn build(
+        block_meta: &[BlockMeta],
+        offset: usize,
+        partition_size: usize,
+        max_ts: u64,
+        format: BlockFormat,
+        buf: &mut Vec<u8>,
+    ) -> Self {
+        let original_len = buf.len();
+        let mut partitions = Vec::new();
+        let mut first_block_idx = 0;
+        while first_block_idx < block_meta.len() {
+            let mut end_block_idx = first_block_idx;
+            let mut size = 0;
+            while end_block_idx < block_meta.len() && (size == 0 || size < partition_size) {
+                size += block_meta[end_block_idx].encoded_len();
+                end_block_idx += 1;
+            }
+            // the data blocks end where the first partition starts
+            let end_offset = block_meta
+                .get(end_block_idx)
+                .map_or(offset, |meta| meta.offset);
+            partitions.push(PartitionHandle {
+                offset: offset + buf.len() - original_len,
+                first_block_idx,
+                first_key: block_meta[first_block_idx].first_key.clone(),
+            });
+            IndexPartition::encode(&block_meta[first_block_idx..end_block_idx], end_offset, buf);
+            first_block_idx = end_block_idx;
+        }
+
+        let index = Self {
+            partitions,
+            offset: offset + buf.len() - original_len,
+        };
+        let top_level_start = buf.len();
+        buf.put_u32(index.partitions.len() as u32);
+        for partition in &index.partitions {
+            buf.put_u32(partition.offset as u32);
+            buf.put_u32(partition.first_block_idx as u32);
+            encode_key(partition.first_key.as_key_slice(), buf);
+        }
+        buf.put_u32(block_meta.len() as u32);
+        encode_key(block_meta.last().unwrap().last_key.as_key_slice(), buf);
+        buf.put_u64(max_ts);
+        buf.put_u8(format.version());
+        buf.put_u32(crc32fast::hash(&buf[top_level_start..]));
+        index
+    }
+
+    /// Decode the top-level index at `offset` from a buffer.
+    pub(crate) fn decode(buf: &[u8], offset: usize) -> Result<PartitionedMeta> {
+        let mut buf = split_checksum(buf, "top-level index checksum mismatched")?;
+        let num = buf.get_u32() as usize;
+        let partitions = (0..num)
+            .map(|_| {
+                let offset = buf.get_u32() as usize;
+                let first_block_idx = buf.get_u32() as usize;
+                let first_key = decode_key(&mut buf);
+                PartitionHandle {
+                    offset,
+                    first_block_idx,
+                    first_key,
+                }
+            })
+            .collect::<Vec<_>>();
+        let num_blocks = buf.get_u32() as usize;
+        let last_key = decode_key(&mut buf);
+        let max_ts = buf.get_u64();
+        let block_format = decode_block_format(buf.get_u8())?;
+        let first_key = partitions
+            .first()
+            .ok_or_else(|| Error::corruption("", 0, "empty top-level index"))?
+            .first_key
+            .clone();
+        Ok(PartitionedMeta {
+            index: TopLevelIndex { partitions, offset },
+            num_blocks,
+            first_key,
+            last_key,
+            max_ts,
+            block_format,
+        })
+    }
+
+    /// The memory of the top-level index.
+    pub(crate) fn memory_size(&self) -> usize {
+        self.partitions
+            .iter()
+            .map(|partition| std::mem::size_of::<PartitionHandle>() + partition.first_key.raw_len())
+            .sum()
+    }
+
+    /// Find the partition that may contain `key`.
+    pub(crate) fn find_partition_idx(&self, key: KeySlice) -> usize {
+        self.partitions
+            .partition_point(|partition| partition.first_key.as_key_slice() <= key)
+            .saturating_sub(1)
+    }
+
+    /// Find the partition that contains the block.
+    pub(crate) fn find_partition_of_block(&self, block_idx: usize) -> usize {
+        self.partitions
+            .partition_point(|partition| partition.first_block_idx <= block_idx)
+            - 1
+    }
+
+    /// The offset and the end offset of the partition.
+    pub(crate) fn partition_range(&self, partition_idx: usize) -> (usize, usize) {
+        let end = self
+            .partitions
+            .get(partition_idx + 1)
+            .map_or(self.offset, |partition| partition.offset);
+        (self.partitions[partition_idx].offset, end)
+    }
+}
+
+/// The pinned or on-demand top-level index of an SST.
+pub(crate) struct PartitionedIndex {
+    /// The offset and the end offset of the top-level index.
+    pub(crate) range: (usize, usize),
+    pub(crate) num_blocks: usize,
+    /// The top-level index, if it is pinned in memory instead of loaded through the block cache.
+    pub(crate) pinned: Option<Arc<TopLevelIndex>>,
+}
+
+/// Check the checksum at the end of a buffer, and return the buffer without it.
+fn split_checksum<'a>(buf: &'a [u8], message: &str) -> Result<&'a [u8]> {
+    let (buf, mut checksum) = buf.split_at(buf.len() - 4);
+    if checksum.get_u32() != crc32fast::hash(buf) {
+        return Err(Error::corruption("", 0, message));
+    }
+    Ok(buf)
+}
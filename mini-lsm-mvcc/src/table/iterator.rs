@@ -1,9 +1,8 @@
 use std::sync::Arc;
 
-use anyhow::Result;
-
 use super::SsTable;
 use crate::block::BlockIterator;
+use crate::error::Result;
 use crate::iterators::StorageIterator;
 use crate::key::KeySlice;
 
@@ -41,10 +40,18 @@ impl SsTableIterator {
         Ok(())
     }
 
-    fn seek_to_key_inner(table: &Arc<SsTable>, key: KeySlice) -> Result<(usize, BlockIterator)> {
-        let mut blk_idx = table.find_block_idx(key);
-        let mut blk_iter =
-            BlockIterator::create_and_seek_to_key(table.read_block_cached(blk_idx)?, key);
+    fn seek_to_key_inner(
+        table: &Arc<SsTable>,
+        key: KeySlice,
+        for_get: bool,
+    ) -> Result<(usize, BlockIterator)> {
+        let mut blk_idx = table.find_block_idx(key)?;
+        let block = table.read_block_cached(blk_idx)?;
+        let mut blk_iter = if for_get {
+            BlockIterator::create_and_seek_for_get(block, key)
+        } else {
+            BlockIterator::create_and_seek_to_key(block, key)
+        };
         if !blk_iter.is_valid() {
             blk_idx += 1;
             if blk_idx < table.num_of_blocks() {
@@ -57,7 +64,19 @@ impl SsTableIterator {
 
     /// Create a new iterator and seek to the first key-value pair which >= `key`.
     pub fn create_and_seek_to_key(table: Arc<SsTable>, key: KeySlice) -> Result<Self> {
-        let (blk_idx, blk_iter) = Self::seek_to_key_inner(&table, key)?;
+        let (blk_idx, blk_iter) = Self::seek_to_key_inner(&table, key, false)?;
+        let iter = Self {
+            blk_iter,
+            table,
+            blk_idx,
+        };
+        Ok(iter)
+    }
+
+    /// Create a new iterator and seek to the first key-value pair which >= `key` for a point
+    /// lookup of the user key, with the hash index of the block.
+    pub fn create_and_seek_for_get(table: Arc<SsTable>, key: KeySlice) -> Result<Self> {
+        let (blk_idx, blk_iter) = Self::seek_to_key_inner(&table, key, true)?;
         let iter = Self {
             blk_iter,
             table,
@@ -68,7 +87,7 @@ impl SsTableIterator {
 
     /// Seek to the first key-value pair which >= `key`.
     pub fn seek_to_key(&mut self, key: KeySlice) -> Result<()> {
-        let (blk_idx, blk_iter) = Self::seek_to_key_inner(&self.table, key)?;
+        let (blk_idx, blk_iter) = Self::seek_to_key_inner(&self.table, key, false)?;
         self.blk_iter = blk_iter;
         self.blk_idx = blk_idx;
         Ok(())
@@ -90,7 +109,7 @@ impl StorageIterator for SsTableIterator {
         self.blk_iter.is_valid()
     }
 
-    fn next(&mut self) -> Result<()> {
+    fn next(&mut self) -> anyhow::Result<()> {
         self.blk_iter.next();
         if !self.blk_iter.is_valid() {
             self.blk_idx += 1;
@@ -1,4 +1,28 @@
+mod block_format;
+mod block_hash_index;
+mod block_restart;
+mod cdc;
+mod change_compaction;
+mod crash_recovery;
+mod db_lock;
+mod error;
+mod external_ts;
+mod filter_cache;
+mod filter_policy;
 mod harness;
+mod isolation;
+mod misuse;
+mod model_check;
+mod options_file;
+mod orphan_files;
+mod partitioned_index;
+mod pessimistic_txn;
+mod recover_ts;
+mod savepoint;
+mod snapshot;
+mod sst_builder;
+mod two_phase_commit;
+mod wal_format;
 mod week1_day1;
 mod week1_day2;
 mod week1_day3;
@@ -0,0 +1,271 @@
+use std::sync::Arc;
+
+use bytes::{BufMut, Bytes};
+use tempfile::tempdir;
+
+use crate::{
+    block::{Block, BlockBuilder, BlockFormat, BlockIterator},
+    compact::CompactionOptions,
+    fs::MemFs,
+    iterators::StorageIterator,
+    key::{KeyBytes, KeySlice},
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    table::{BlockMeta, FileObject, SsTable, SsTableBuilder, SsTableIterator},
+};
+
+fn key_of(i: usize) -> Vec<u8> {
+    format!("key_{:05}", i).into_bytes()
+}
+
+fn value_of(i: usize) -> Vec<u8> {
+    format!("v{}", i).into_bytes()
+}
+
+/// The ts of the entries go up and down around the ts of the first entry, including the
+/// extremes.
+fn ts_of(i: usize) -> u64 {
+    match i % 7 {
+        0 => 1000 + i as u64,
+        1 => 1000 - i as u64,
+        2 => 0,
+        3 => u64::MAX,
+        4 => u64::MAX / 2,
+        _ => 1000,
+    }
+}
+
+fn build_block(format: BlockFormat, restart_interval: usize) -> Block {
+    let mut builder =
+        BlockBuilder::with_restart_interval(1 << 16, restart_interval).with_format(format);
+    for i in 0..300 {
+        assert!(builder.add(
+            KeySlice::for_testing_from_slice_with_ts(&key_of(i), ts_of(i)),
+            &value_of(i),
+        ));
+    }
+    builder.build()
+}
+
+#[test]
+fn test_varint_block_round_trip() {
+    for restart_interval in [1, 4, 16] {
+        let block = build_block(BlockFormat::Varint, restart_interval);
+        let block = Arc::new(Block::decode_with_format(
+            &block.encode(),
+            BlockFormat::Varint,
+        ));
+        let mut iter = BlockIterator::create_and_seek_to_first(block.clone());
+        for i in 0..300 {
+            assert!(iter.is_valid());
+            assert_eq!(iter.key().key_ref(), key_of(i));
+            assert_eq!(iter.key().ts(), ts_of(i));
+            assert_eq!(iter.value(), value_of(i));
+            iter.next();
+        }
+        assert!(!iter.is_valid());
+
+        for i in 0..300 {
+            let mut iter = BlockIterator::create_and_seek_to_key(
+                block.clone(),
+                KeySlice::for_testing_from_slice_with_ts(&key_of(i), ts_of(i)),
+            );
+            assert_eq!(iter.key().key_ref(), key_of(i));
+            assert_eq!(iter.key().ts(), ts_of(i));
+            assert_eq!(iter.value(), value_of(i));
+            iter.seek_to_first();
+            assert_eq!(iter.key().key_ref(), key_of(0));
+            assert_eq!(iter.key().ts(), ts_of(0));
+        }
+    }
+}
+
+#[test]
+fn test_varint_block_smaller() {
+    let fixed = build_block(BlockFormat::Fixed, 16).encode().len();
+    let varint = build_block(BlockFormat::Varint, 16).encode().len();
+    assert!(varint < fixed, "{} {}", varint, fixed);
+
+    // the size limit holds with the varint encoding
+    let mut builder = BlockBuilder::with_restart_interval(128, 4).with_format(BlockFormat::Varint);
+    let mut num_keys = 0;
+    while builder.add(
+        KeySlice::for_testing_from_slice_with_ts(&key_of(num_keys), ts_of(num_keys)),
+        &value_of(num_keys),
+    ) {
+        num_keys += 1;
+    }
+    assert!(num_keys > 1);
+    assert!(builder.build().encode().len() <= 128);
+}
+
+#[test]
+fn test_block_format_in_sst_meta() {
+    let dir = tempdir().unwrap();
+    for format in [BlockFormat::Fixed, BlockFormat::Varint, BlockFormat::Legacy] {
+        let mut builder = SsTableBuilder::new(128).with_block_format(format);
+        for i in 0..300 {
+            builder.add(
+                KeySlice::for_testing_from_slice_with_ts(&key_of(i), ts_of(i)),
+                &value_of(i),
+            );
+        }
+        let path = dir.path().join(format!("{}.sst", format.version()));
+        builder.build_for_test(&path).unwrap();
+        let sst = Arc::new(SsTable::open_for_test(FileObject::open(&path).unwrap()).unwrap());
+        assert_eq!(sst.block_format(), format);
+        let mut iter = SsTableIterator::create_and_seek_to_first(sst).unwrap();
+        for i in 0..300 {
+            assert_eq!(iter.key().key_ref(), key_of(i));
+            assert_eq!(iter.key().ts(), ts_of(i));
+            assert_eq!(iter.value(), value_of(i));
+            iter.next().unwrap();
+        }
+        assert!(!iter.is_valid());
+        for i in (0..300).rev() {
+            let key_bytes = key_of(i);
+            let key = KeySlice::for_testing_from_slice_with_ts(&key_bytes, ts_of(i));
+            iter.seek_to_key(key).unwrap();
+            assert_eq!(iter.key(), key);
+            assert_eq!(iter.value(), value_of(i));
+        }
+    }
+}
+
+#[test]
+fn test_meta_without_format_version() {
+    let meta = vec![BlockMeta {
+        offset: 0,
+        first_key: KeyBytes::from_bytes_with_ts(Bytes::from_static(b"a"), 1),
+        last_key: KeyBytes::from_bytes_with_ts(Bytes::from_static(b"b"), 2),
+    }];
+    let mut buf = Vec::new();
+    BlockMeta::encode_block_meta(&meta, 2, BlockFormat::Varint, &mut buf);
+    assert_eq!(
+        BlockMeta::decode_block_meta(&buf).unwrap(),
+        (meta.clone(), 2, BlockFormat::Varint)
+    );
+
+    // the meta written before the format version was added
+    buf.truncate(buf.len() - 5);
+    let checksum = crc32fast::hash(&buf[4..]);
+    buf.put_u32(checksum);
+    assert_eq!(
+        BlockMeta::decode_block_meta(&buf).unwrap(),
+        (meta.clone(), 2, BlockFormat::Legacy)
+    );
+    let mut legacy_buf = Vec::new();
+    BlockMeta::encode_block_meta(&meta, 2, BlockFormat::Legacy, &mut legacy_buf);
+    assert_eq!(legacy_buf, buf);
+}
+
+/// Encode a block the way the blocks were written before the format version was added to the SST
+/// meta: an offset for every entry, and the keys delta-encoded against the first key.
+fn encode_legacy_block(entries: &[(Vec<u8>, u64, Vec<u8>)]) -> Vec<u8> {
+    let mut first_key: &[u8] = &[];
+    let mut data = Vec::new();
+    let mut offsets = Vec::new();
+    for (key, ts, value) in entries {
+        offsets.push(data.len() as u16);
+        let overlap = key
+            .iter()
+            .zip(first_key)
+            .take_while(|(a, b)| a == b)
+            .count();
+        if first_key.is_empty() {
+            first_key = key;
+        }
+        data.put_u16(overlap as u16);
+        data.put_u16((key.len() - overlap) as u16);
+        data.put_slice(&key[overlap..]);
+        data.put_u64(*ts);
+        data.put_u16(value.len() as u16);
+        data.put_slice(value);
+    }
+    for offset in &offsets {
+        data.put_u16(*offset);
+    }
+    data.put_u16(offsets.len() as u16);
+    data
+}
+
+#[test]
+fn test_legacy_block() {
+    // keys whose prefix shared with the first key is shorter than the one with the previous key
+    let entries = (0..100)
+        .map(|i| (key_of(i * 7), ts_of(i), value_of(i)))
+        .collect::<Vec<_>>();
+    let encoded = encode_legacy_block(&entries);
+    let block = Arc::new(Block::decode_with_format(&encoded, BlockFormat::Legacy));
+    let mut iter = BlockIterator::create_and_seek_to_first(block.clone());
+    for (key, ts, value) in &entries {
+        assert_eq!(
+            iter.key(),
+            KeySlice::for_testing_from_slice_with_ts(key, *ts)
+        );
+        assert_eq!(iter.value(), value);
+        iter.next();
+    }
+    assert!(!iter.is_valid());
+
+    // every seek lands on a restart point, whose key is rebuilt from the first key
+    for (idx, (key, ts, value)) in entries.iter().enumerate().rev() {
+        let key = KeySlice::for_testing_from_slice_with_ts(key, *ts);
+        iter.seek_to_key(key);
+        assert_eq!(iter.key(), key);
+        assert_eq!(iter.value(), value);
+        let mut iter = BlockIterator::create_and_seek_for_get(block.clone(), key);
+        assert_eq!(iter.key(), key);
+        if let Some((next_key, next_ts, _)) = entries.get(idx + 1) {
+            iter.next();
+            assert_eq!(
+                iter.key(),
+                KeySlice::for_testing_from_slice_with_ts(next_key, *next_ts)
+            );
+        }
+    }
+
+    // the legacy format is written the same way
+    let mut builder = BlockBuilder::new(1 << 16)
+        .with_format(BlockFormat::Legacy)
+        .with_hash_index(true);
+    for (key, ts, value) in &entries {
+        assert!(builder.add(KeySlice::for_testing_from_slice_with_ts(key, *ts), value));
+    }
+    assert_eq!(builder.build().encode(), encoded);
+}
+
+#[test]
+fn test_change_block_format() {
+    let fs = Arc::new(MemFs::new());
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.fs = fs.clone();
+    options.enable_wal = true;
+    for (round, format) in [BlockFormat::Varint, BlockFormat::Fixed, BlockFormat::Varint]
+        .into_iter()
+        .enumerate()
+    {
+        options.block_format = format;
+        let storage = MiniLsm::open("/db", options.clone()).unwrap();
+        for i in 0..100 {
+            storage
+                .put(&key_of(i * 3 + round), &value_of(round))
+                .unwrap();
+        }
+        storage.force_flush().unwrap();
+        for i in 0..300 {
+            let expected = (i % 3 <= round).then(|| Bytes::from(value_of(i % 3)));
+            assert_eq!(storage.get(&key_of(i)).unwrap(), expected);
+        }
+        storage.close().unwrap();
+    }
+
+    options.block_format = BlockFormat::Fixed;
+    let storage = MiniLsm::open("/db", options).unwrap();
+    storage.force_full_compaction().unwrap();
+    for i in 0..300 {
+        assert_eq!(
+            storage.get(&key_of(i)).unwrap(),
+            Some(Bytes::from(value_of(i % 3)))
+        );
+    }
+}
@@ -0,0 +1,169 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    block::{Block, BlockBuilder, BlockFormat, BlockIterator},
+    compact::CompactionOptions,
+    key::KeySlice,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+fn key_of(i: usize) -> Vec<u8> {
+    format!("key_{:05}", i * 2).into_bytes()
+}
+
+fn value_of(i: usize, ts: u64) -> Vec<u8> {
+    format!("value_{}_{}", i, ts).into_bytes()
+}
+
+/// The number of versions of the i-th key, so that the versions of some keys span several
+/// restart points.
+fn num_versions(i: usize) -> u64 {
+    if i.is_multiple_of(5) {
+        6
+    } else {
+        1
+    }
+}
+
+fn build_block(restart_interval: usize, format: BlockFormat, hash_index: bool) -> Block {
+    let mut builder = BlockBuilder::with_restart_interval(1 << 16, restart_interval)
+        .with_format(format)
+        .with_hash_index(hash_index);
+    for i in 0..200 {
+        for ts in (1..=num_versions(i)).rev() {
+            assert!(builder.add(
+                KeySlice::for_testing_from_slice_with_ts(&key_of(i), ts * 10),
+                &value_of(i, ts * 10),
+            ));
+        }
+    }
+    builder.build()
+}
+
+fn check_seek_for_get(block: Arc<Block>, key: &[u8], ts: u64) {
+    let key = KeySlice::for_testing_from_slice_with_ts(key, ts);
+    let expected = BlockIterator::create_and_seek_to_key(block.clone(), key);
+    let iter = BlockIterator::create_and_seek_for_get(block, key);
+    assert_eq!(iter.is_valid(), expected.is_valid());
+    if expected.is_valid() {
+        assert_eq!(iter.key(), expected.key());
+        assert_eq!(iter.value(), expected.value());
+    }
+}
+
+#[test]
+fn test_block_hash_index_seek() {
+    for restart_interval in [2, 4, 16] {
+        for format in [BlockFormat::Fixed, BlockFormat::Varint] {
+            let block = build_block(restart_interval, format, true);
+            assert!(block.hash_index.is_some());
+            let block = Arc::new(Block::decode_with_format(&block.encode(), format));
+            let buckets = block.hash_index.as_ref().unwrap();
+            assert_eq!(buckets.len(), 200 * 4 / 3 + 1);
+
+            let mut hits = 0;
+            for i in 0..200 {
+                hits += block.probe_hash_index(&key_of(i)).is_some() as usize;
+                for ts in [u64::MAX, 65, 60, 35, 10, 5, 0] {
+                    check_seek_for_get(block.clone(), &key_of(i), ts);
+                }
+                // keys not in the block
+                check_seek_for_get(
+                    block.clone(),
+                    &format!("key_{:05}", i * 2 + 1).into_bytes(),
+                    0,
+                );
+            }
+            assert!(hits > 50, "{}", hits);
+            check_seek_for_get(block.clone(), b"a", u64::MAX);
+            check_seek_for_get(block, b"z", u64::MAX);
+        }
+    }
+}
+
+#[test]
+fn test_block_without_hash_index() {
+    let block = build_block(16, BlockFormat::Fixed, false);
+    let encoded = block.encode();
+    let block = Block::decode(&encoded);
+    assert!(block.hash_index.is_none());
+    assert!(block.probe_hash_index(&key_of(0)).is_none());
+    // the hash index is appended to a block in the same layout
+    let indexed = build_block(16, BlockFormat::Fixed, true);
+    assert_eq!(indexed.data, block.data);
+    assert_eq!(indexed.offsets, block.offsets);
+    assert!(indexed.encode().len() > encoded.len());
+
+    // too many restart points for the index to refer to
+    let block = build_block(1, BlockFormat::Fixed, true);
+    assert!(block.offsets.len() > 254);
+    assert!(block.hash_index.is_none());
+    let block = Arc::new(Block::decode(&block.encode()));
+    check_seek_for_get(block, &key_of(100), u64::MAX);
+}
+
+#[test]
+fn test_block_hash_index_size_limit() {
+    let mut builder = BlockBuilder::new(256).with_hash_index(true);
+    let mut num_keys = 0;
+    while builder.add(
+        KeySlice::for_testing_from_slice_with_ts(&key_of(num_keys), 1),
+        &value_of(num_keys, 1),
+    ) {
+        num_keys += 1;
+    }
+    assert!(num_keys > 1);
+    let block = builder.build();
+    assert!(block.hash_index.is_some());
+    assert!(block.encode().len() <= 256);
+}
+
+#[test]
+fn test_get_with_block_hash_index() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.block_size = 256;
+    options.block_hash_index = true;
+    options.history_retention = u64::MAX;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    let mut commit_ts = Vec::new();
+    for round in 0..3 {
+        for i in 0..300 {
+            if i % 3 >= round {
+                storage.put(&key_of(i), &value_of(i, round as u64)).unwrap();
+            }
+        }
+        commit_ts.push(storage.inner.mvcc().latest_commit_ts());
+        storage.force_flush().unwrap();
+    }
+    storage.force_full_compaction().unwrap();
+    storage.put(&key_of(0), b"new").unwrap();
+    storage.force_flush().unwrap();
+
+    assert_eq!(
+        storage.get(&key_of(0)).unwrap(),
+        Some(Bytes::from_static(b"new"))
+    );
+    for i in 1..300 {
+        let round = (i % 3) as u64;
+        assert_eq!(
+            storage.get(&key_of(i)).unwrap(),
+            Some(Bytes::from(value_of(i, round)))
+        );
+        assert_eq!(
+            storage
+                .get(&format!("key_{:05}", i * 2 + 1).into_bytes())
+                .unwrap(),
+            None
+        );
+        for (round, ts) in commit_ts.iter().enumerate() {
+            assert_eq!(
+                storage.get_at(&key_of(i), *ts).unwrap(),
+                Some(Bytes::from(value_of(i, round.min(i % 3) as u64)))
+            );
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use std::sync::Arc;
+
+use crate::{
+    block::{Block, BlockBuilder, BlockIterator},
+    key::KeySlice,
+};
+
+fn key_of(i: usize) -> Vec<u8> {
+    format!("a_rather_long_shared_key_prefix_{:05}", i).into_bytes()
+}
+
+fn value_of(i: usize) -> Vec<u8> {
+    format!("value_{}", i).into_bytes()
+}
+
+/// Build a block of keys 0, 2, 4, ... with two versions each, at ts 2 and 1.
+fn build_block(restart_interval: usize, num_keys: usize) -> Block {
+    let mut builder = BlockBuilder::with_restart_interval(1 << 16, restart_interval);
+    for i in 0..num_keys {
+        for ts in [2, 1] {
+            assert!(builder.add(
+                KeySlice::for_testing_from_slice_with_ts(&key_of(i * 2), ts),
+                &value_of(i * 2 + ts as usize),
+            ));
+        }
+    }
+    builder.build()
+}
+
+#[test]
+fn test_block_restart_interval_round_trip() {
+    for restart_interval in [1, 2, 3, 16, 1000] {
+        let block = build_block(restart_interval, 100);
+        assert_eq!(block.offsets.len(), 200usize.div_ceil(restart_interval));
+        let block = Arc::new(Block::decode(&block.encode()));
+        let mut iter = BlockIterator::create_and_seek_to_first(block.clone());
+        for i in 0..100 {
+            for ts in [2, 1] {
+                assert!(iter.is_valid());
+                assert_eq!(iter.key().key_ref(), key_of(i * 2));
+                assert_eq!(iter.key().ts(), ts);
+                assert_eq!(iter.value(), value_of(i * 2 + ts as usize));
+                iter.next();
+            }
+        }
+        assert!(!iter.is_valid());
+
+        for i in 0..201 {
+            for ts in [3, 2, 1, 0] {
+                let iter = BlockIterator::create_and_seek_to_key(
+                    block.clone(),
+                    KeySlice::for_testing_from_slice_with_ts(&key_of(i), ts),
+                );
+                // keys are ordered by key, then by ts from the latest
+                let expected = match (i % 2, ts) {
+                    (0, 0) => (i + 2, 2),
+                    (0, ts) => (i, ts.min(2)),
+                    _ => (i + 1, 2),
+                };
+                let expected = Some(expected).filter(|(key, _)| *key < 200);
+                match expected {
+                    Some((key, ts)) => {
+                        assert!(iter.is_valid(), "seek {} {}", i, ts);
+                        assert_eq!(iter.key().key_ref(), key_of(key));
+                        assert_eq!(iter.key().ts(), ts);
+                        assert_eq!(iter.value(), value_of(key + ts as usize));
+                    }
+                    None => assert!(!iter.is_valid()),
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_block_restart_interval_compression() {
+    let full = build_block(1, 100).encode().len();
+    let delta = build_block(16, 100).encode().len();
+    // the shared prefix is only stored at the restart points
+    assert!(delta * 2 < full, "{} {}", delta, full);
+}
+
+#[test]
+fn test_block_restart_interval_size_limit() {
+    for restart_interval in [1, 4, 16] {
+        let mut builder = BlockBuilder::with_restart_interval(256, restart_interval);
+        let mut num_keys = 0;
+        while builder.add(
+            KeySlice::for_testing_from_slice_with_ts(&key_of(num_keys), 1),
+            &value_of(num_keys),
+        ) {
+            num_keys += 1;
+        }
+        let block = builder.build();
+        assert!(block.encode().len() <= 256);
+        let mut iter = BlockIterator::create_and_seek_to_first(Arc::new(block));
+        for i in 0..num_keys {
+            assert_eq!(iter.key().key_ref(), key_of(i));
+            iter.next();
+        }
+        assert!(!iter.is_valid());
+    }
+}
@@ -0,0 +1,189 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    fs::{FaultInjectionFs, MemFs},
+    lsm_storage::{LsmStorageOptions, MiniLsm, WriteBatchRecord},
+};
+
+fn put(key: &str, value: &str) -> WriteBatchRecord<Bytes> {
+    WriteBatchRecord::Put(Bytes::from(key.to_string()), Bytes::from(value.to_string()))
+}
+
+fn del(key: &str) -> WriteBatchRecord<Bytes> {
+    WriteBatchRecord::Del(Bytes::from(key.to_string()))
+}
+
+#[test]
+fn test_updates_since() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    options.wal_ttl_seconds = 3600;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"key1", b"1").unwrap();
+    storage
+        .write_batch(&[
+            WriteBatchRecord::Put(&b"key3"[..], &b"3"[..]),
+            WriteBatchRecord::Put(&b"key2"[..], &b"2"[..]),
+            WriteBatchRecord::Del(&b"key1"[..]),
+        ])
+        .unwrap();
+    storage.force_flush().unwrap();
+    let txn = storage.new_txn().unwrap();
+    txn.put(b"key4", b"4").unwrap();
+    txn.delete(b"key2").unwrap();
+    txn.commit().unwrap();
+    let expected = vec![
+        (1, vec![put("key1", "1")]),
+        (2, vec![del("key1"), put("key2", "2"), put("key3", "3")]),
+        (3, vec![del("key2"), put("key4", "4")]),
+    ];
+    assert_eq!(
+        storage.updates_since(0).unwrap().collect::<Vec<_>>(),
+        expected
+    );
+    assert_eq!(
+        storage.updates_since(2).unwrap().collect::<Vec<_>>(),
+        expected[2..]
+    );
+    assert_eq!(storage.updates_since(3).unwrap().count(), 0);
+    storage.close().unwrap();
+    drop(storage);
+
+    // the archived WAL is still retained after restart
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    assert_eq!(
+        storage.updates_since(0).unwrap().collect::<Vec<_>>(),
+        expected
+    );
+}
+
+#[test]
+fn test_updates_since_batch_across_memtables() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    options.wal_ttl_seconds = 3600;
+    options.target_sst_size = 4096;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    let value = "v".repeat(1000);
+    // the memtable is frozen partway through the batch, which ends with a rewrite of its first key
+    let mut batch = (0..10)
+        .map(|i| put(&format!("key{}", i), &value))
+        .collect::<Vec<_>>();
+    batch.push(put("key0", "0"));
+    storage.write_batch(&batch).unwrap();
+    assert!(!storage.inner.state.read().imm_memtables.is_empty());
+    let mut expected = batch[1..10].to_vec();
+    expected.insert(0, put("key0", "0"));
+    assert_eq!(
+        storage.updates_since(0).unwrap().collect::<Vec<_>>(),
+        vec![(1, expected.clone())]
+    );
+    // and the same when a part of the batch is in an archived WAL
+    storage.force_flush().unwrap();
+    assert_eq!(
+        storage.updates_since(0).unwrap().collect::<Vec<_>>(),
+        vec![(1, expected)]
+    );
+}
+
+#[test]
+fn test_archived_wal_survives_crash() {
+    let fs = Arc::new(FaultInjectionFs::new(Arc::new(MemFs::new())));
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    options.wal_ttl_seconds = 3600;
+    options.fs = fs.clone();
+    let storage = MiniLsm::open("/db", options.clone()).unwrap();
+    storage.put(b"key1", b"1").unwrap();
+    // the flush archives the WAL, which must not be undone by a crash afterwards
+    storage.force_flush().unwrap();
+    let fs = fs.crash().unwrap();
+    drop(storage);
+
+    options.fs = fs;
+    let storage = MiniLsm::open("/db", options).unwrap();
+    assert_eq!(
+        storage.updates_since(0).unwrap().collect::<Vec<_>>(),
+        vec![(1, vec![put("key1", "1")])]
+    );
+}
+
+#[test]
+fn test_updates_since_not_retained() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"key1", b"1").unwrap();
+    storage.put(b"key2", b"2").unwrap();
+    assert_eq!(storage.updates_since(0).unwrap().count(), 2);
+    // the WAL is removed on flush without a retention limit
+    storage.force_flush().unwrap();
+    storage.put(b"key3", b"3").unwrap();
+    assert!(storage.updates_since(0).is_err());
+    assert!(storage.updates_since(1).is_err());
+    assert_eq!(
+        storage.updates_since(2).unwrap().collect::<Vec<_>>(),
+        vec![(3, vec![put("key3", "3")])]
+    );
+}
+
+#[test]
+fn test_wal_size_limit() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    options.wal_size_limit_mb = 1;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    let value = "v".repeat(10000);
+    let mut ts = Vec::new();
+    for i in 0..3 {
+        for j in 0..60 {
+            storage
+                .put(format!("key{}_{}", i, j).as_bytes(), value.as_bytes())
+                .unwrap();
+        }
+        ts.push(storage.inner.mvcc().latest_commit_ts());
+        storage.force_flush().unwrap();
+    }
+    // about 600KB per WAL, so only the last one is kept under the 1MB limit
+    assert!(storage.updates_since(0).is_err());
+    assert!(storage.updates_since(ts[0]).is_err());
+    assert_eq!(storage.updates_since(ts[1]).unwrap().count(), 60);
+}
+
+#[test]
+fn test_subscribe() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"key1", b"1").unwrap();
+    let rx = storage.subscribe();
+    storage.put(b"key2", b"2").unwrap();
+    storage
+        .write_batch(&[
+            WriteBatchRecord::Put(&b"key3"[..], &b"3"[..]),
+            WriteBatchRecord::Put(&b"key3"[..], &b"4"[..]),
+            WriteBatchRecord::Del(&b"key2"[..]),
+        ])
+        .unwrap();
+    let txn = storage.new_txn().unwrap();
+    txn.put(b"key1", b"5").unwrap();
+    txn.commit().unwrap();
+    assert_eq!(
+        rx.try_iter().collect::<Vec<_>>(),
+        vec![
+            (2, vec![put("key2", "2")]),
+            (3, vec![del("key2"), put("key3", "4")]),
+            (4, vec![put("key1", "5")]),
+        ]
+    );
+    drop(rx);
+    storage.put(b"key1", b"6").unwrap();
+}
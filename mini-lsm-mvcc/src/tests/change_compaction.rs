@@ -0,0 +1,180 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use crate::{
+    compact::{
+        CompactionOptions, LeveledCompactionOptions, SimpleLeveledCompactionOptions,
+        TieredCompactionOptions,
+    },
+    error::Error,
+    fs::{FaultInjectionFs, FileSystem, MemFs},
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    options_file::PersistedOptions,
+};
+
+const DB_PATH: &str = "/db";
+
+fn tiered() -> CompactionOptions {
+    CompactionOptions::Tiered(TieredCompactionOptions {
+        num_tiers: 3,
+        max_size_amplification_percent: 200,
+        size_ratio: 1,
+        min_merge_width: 2,
+        max_merge_width: None,
+    })
+}
+
+fn leveled() -> CompactionOptions {
+    CompactionOptions::Leveled(LeveledCompactionOptions {
+        level_size_multiplier: 2,
+        level0_file_num_compaction_trigger: 2,
+        max_levels: 4,
+        base_level_size_mb: 1,
+    })
+}
+
+fn options_with_fs(
+    fs: Arc<FaultInjectionFs>,
+    compaction_options: CompactionOptions,
+) -> LsmStorageOptions {
+    let mut options = LsmStorageOptions::default_for_week2_test(compaction_options);
+    options.enable_wal = true;
+    options.fs = fs;
+    options
+}
+
+fn key_of(i: usize) -> Vec<u8> {
+    format!("key_{:05}", i).into_bytes()
+}
+
+fn value_of(i: usize, round: usize) -> Vec<u8> {
+    format!("value_{:05}_{}", i, round).into_bytes()
+}
+
+/// Write a few rounds of overlapping keys, flushing each round into its own SST.
+fn fill(storage: &MiniLsm) {
+    for round in 0..4 {
+        for i in 0..200 {
+            storage.put(&key_of(i), &value_of(i, round)).unwrap();
+        }
+        for i in 0..20 {
+            storage.delete(&key_of(i * 10 + round)).unwrap();
+        }
+        storage.force_flush().unwrap();
+    }
+}
+
+fn check(storage: &MiniLsm) {
+    for i in 0..200 {
+        let expected = (i % 10 != 3).then(|| Bytes::from(value_of(i, 3)));
+        assert_eq!(storage.get(&key_of(i)).unwrap(), expected, "key {}", i);
+    }
+}
+
+#[test]
+fn test_change_tiered_to_leveled() {
+    let fs = Arc::new(FaultInjectionFs::new(Arc::new(MemFs::new())));
+    let storage = MiniLsm::open(DB_PATH, options_with_fs(fs.clone(), tiered())).unwrap();
+    fill(&storage);
+    storage.change_compaction_strategy(leveled()).unwrap();
+    check(&storage);
+    {
+        let state = storage.inner.state.read();
+        assert!(state.l0_sstables.is_empty());
+        assert_eq!(state.levels.len(), 4);
+        assert!(state.levels[..3].iter().all(|(_, files)| files.is_empty()));
+        assert!(!state.levels[3].1.is_empty());
+    }
+    // flushes go to L0 after the change
+    storage.put(b"key_00003", b"new").unwrap();
+    storage.force_flush().unwrap();
+    assert_eq!(storage.inner.state.read().l0_sstables.len(), 1);
+    storage.close().unwrap();
+    drop(storage);
+
+    assert!(matches!(
+        MiniLsm::open(DB_PATH, options_with_fs(fs.clone(), tiered())),
+        Err(Error::InvalidArgument(_))
+    ));
+    let storage = MiniLsm::open(DB_PATH, options_with_fs(fs, leveled())).unwrap();
+    assert_eq!(
+        storage.get(b"key_00003").unwrap(),
+        Some(Bytes::from_static(b"new"))
+    );
+    for i in 4..200 {
+        let expected = (i % 10 != 3).then(|| Bytes::from(value_of(i, 3)));
+        assert_eq!(storage.get(&key_of(i)).unwrap(), expected, "key {}", i);
+    }
+}
+
+#[test]
+fn test_change_strategy_crash() {
+    let fs = Arc::new(FaultInjectionFs::new(Arc::new(MemFs::new())));
+    let storage = MiniLsm::open(DB_PATH, options_with_fs(fs.clone(), leveled())).unwrap();
+    let old_options = fs.read(&PersistedOptions::path(DB_PATH)).unwrap();
+    fill(&storage);
+    storage.change_compaction_strategy(tiered()).unwrap();
+    let levels = storage.inner.state.read().levels.clone();
+    assert_eq!(levels.len(), 1);
+    // crash after the change is recorded, but before the OPTIONS file is rewritten
+    let mut file = fs.create(&PersistedOptions::path(DB_PATH)).unwrap();
+    file.append(&old_options).unwrap();
+    file.sync().unwrap();
+    let fs = fs.crash().unwrap();
+    drop(storage);
+
+    // the manifest decides the strategy
+    assert!(matches!(
+        MiniLsm::open(DB_PATH, options_with_fs(fs.clone(), leveled())),
+        Err(Error::InvalidArgument(_))
+    ));
+    let storage = MiniLsm::open(DB_PATH, options_with_fs(fs, tiered())).unwrap();
+    assert_eq!(storage.inner.state.read().levels, levels);
+    check(&storage);
+}
+
+#[test]
+fn test_change_strategy_from_no_compaction() {
+    let dir = tempfile::tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    fill(&storage);
+    let simple = CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+        size_ratio_percent: 200,
+        level0_file_num_compaction_trigger: 2,
+        max_levels: 3,
+    });
+    storage.change_compaction_strategy(simple.clone()).unwrap();
+    check(&storage);
+    assert!(matches!(
+        storage.force_full_compaction(),
+        Err(Error::InvalidArgument(_))
+    ));
+
+    // the background compaction picks up the new strategy
+    for i in 0..3 {
+        storage.put(&key_of(i), b"new").unwrap();
+        storage.force_flush().unwrap();
+    }
+    for _ in 0..100 {
+        if storage.inner.state.read().l0_sstables.len() < 2 {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    }
+    assert!(storage.inner.state.read().l0_sstables.len() < 2);
+    for i in 0..3 {
+        assert_eq!(
+            storage.get(&key_of(i)).unwrap(),
+            Some(Bytes::from_static(b"new"))
+        );
+    }
+    storage.close().unwrap();
+    drop(storage);
+
+    let persisted = PersistedOptions::read(&crate::fs::PosixFs, dir.path())
+        .unwrap()
+        .unwrap();
+    assert_eq!(persisted.compaction_options, simple);
+}
@@ -0,0 +1,166 @@
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use crate::{
+    compact::CompactionOptions,
+    error::Error,
+    fs::{rename_temp_file, temp_path, write_file_atomic, FaultInjectionFs, FileSystem, MemFs},
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+const DB_PATH: &str = "/db";
+
+fn options_with_fs(fs: Arc<FaultInjectionFs>) -> LsmStorageOptions {
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    options.fs = fs;
+    options
+}
+
+fn key_of(i: usize) -> Vec<u8> {
+    format!("key_{:05}", i).into_bytes()
+}
+
+fn value_of(i: usize) -> Vec<u8> {
+    format!("value_{:05}", i).into_bytes()
+}
+
+#[test]
+fn test_crash_drops_unsynced_writes() {
+    let fs = Arc::new(FaultInjectionFs::new(Arc::new(MemFs::new())));
+    let storage = MiniLsm::open(DB_PATH, options_with_fs(fs.clone())).unwrap();
+    storage.put(b"key1", b"1").unwrap();
+    storage.sync().unwrap();
+    storage.put(b"key2", b"2").unwrap();
+    let fs = fs.crash().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(DB_PATH, options_with_fs(fs.clone())).unwrap();
+    assert_eq!(
+        storage.get(b"key1").unwrap(),
+        Some(Bytes::from_static(b"1"))
+    );
+    assert_eq!(storage.get(b"key2").unwrap(), None);
+    // the recovered storage keeps working and crashes again
+    storage.put(b"key2", b"2").unwrap();
+    storage.sync().unwrap();
+    let fs = fs.crash().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(DB_PATH, options_with_fs(fs)).unwrap();
+    assert_eq!(
+        storage.get(b"key1").unwrap(),
+        Some(Bytes::from_static(b"1"))
+    );
+    assert_eq!(
+        storage.get(b"key2").unwrap(),
+        Some(Bytes::from_static(b"2"))
+    );
+}
+
+#[test]
+fn test_crash_after_flush() {
+    let fs = Arc::new(FaultInjectionFs::new(Arc::new(MemFs::new())));
+    let storage = MiniLsm::open(DB_PATH, options_with_fs(fs.clone())).unwrap();
+    for i in 0..100 {
+        storage.put(&key_of(i), &value_of(i)).unwrap();
+    }
+    storage.force_flush().unwrap();
+    for i in 0..100 {
+        storage.delete(&key_of(i * 2)).unwrap();
+    }
+    storage.sync().unwrap();
+    for i in 100..200 {
+        storage.put(&key_of(i), &value_of(i)).unwrap();
+    }
+    let fs = fs.crash().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(DB_PATH, options_with_fs(fs)).unwrap();
+    for i in 0..200 {
+        let expected = (i < 100 && i % 2 == 1).then(|| Bytes::from(value_of(i)));
+        assert_eq!(storage.get(&key_of(i)).unwrap(), expected, "key {}", i);
+    }
+}
+
+#[test]
+fn test_failed_write_is_not_visible() {
+    let fs = Arc::new(FaultInjectionFs::new(Arc::new(MemFs::new())));
+    let storage = MiniLsm::open(DB_PATH, options_with_fs(fs.clone())).unwrap();
+    storage.put(b"key1", b"1").unwrap();
+    fs.fill_disk_after_writes(0);
+    let err = storage.put(b"key2", b"2").unwrap_err();
+    assert!(matches!(err, Error::Io(_)), "{}", err);
+    assert_eq!(storage.get(b"key2").unwrap(), None);
+    assert!(storage.force_flush().is_err());
+
+    fs.clear_write_failures();
+    storage.put(b"key3", b"3").unwrap();
+    storage.sync().unwrap();
+    let fs = fs.crash().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(DB_PATH, options_with_fs(fs)).unwrap();
+    assert_eq!(
+        storage.get(b"key1").unwrap(),
+        Some(Bytes::from_static(b"1"))
+    );
+    assert_eq!(storage.get(b"key2").unwrap(), None);
+    assert_eq!(
+        storage.get(b"key3").unwrap(),
+        Some(Bytes::from_static(b"3"))
+    );
+}
+
+#[test]
+fn test_crash_undoes_unsynced_renames_and_removes() {
+    let fs = Arc::new(FaultInjectionFs::new(Arc::new(MemFs::new())));
+    let dir = Path::new(DB_PATH);
+    fs.create_dir_all(dir).unwrap();
+    let path = dir.join("file");
+    write_file_atomic(fs.as_ref(), &path, b"1").unwrap();
+    let removed = dir.join("removed");
+    write_file_atomic(fs.as_ref(), &removed, b"removed").unwrap();
+
+    // a new version renamed over the file, and a removal, without syncing the directory
+    let mut file = fs.create(&temp_path(&path)).unwrap();
+    file.append(b"2").unwrap();
+    file.sync().unwrap();
+    drop(file);
+    fs.rename(&temp_path(&path), &path).unwrap();
+    fs.remove_file(&removed).unwrap();
+    assert_eq!(fs.read(&path).unwrap(), b"2");
+    assert!(!fs.exists(&removed));
+    let fs = fs.crash().unwrap();
+    assert_eq!(fs.read(&path).unwrap(), b"1");
+    assert_eq!(fs.read(&temp_path(&path)).unwrap(), b"2");
+    assert_eq!(fs.read(&removed).unwrap(), b"removed");
+
+    // they are persisted once the directory is synced
+    rename_temp_file(fs.as_ref(), &path).unwrap();
+    fs.remove_file(&removed).unwrap();
+    fs.sync_dir(dir).unwrap();
+    let fs = fs.crash().unwrap();
+    assert_eq!(fs.read(&path).unwrap(), b"2");
+    assert!(!fs.exists(&temp_path(&path)));
+    assert!(!fs.exists(&removed));
+}
+
+#[test]
+fn test_disk_full_fails_all_later_writes() {
+    let fs = Arc::new(FaultInjectionFs::new(Arc::new(MemFs::new())));
+    fs.create_dir_all(Path::new(DB_PATH)).unwrap();
+    let mut file = fs.create(&Path::new(DB_PATH).join("file")).unwrap();
+    fs.fill_disk_after_writes(2);
+    file.append(b"1").unwrap();
+    file.append(b"2").unwrap();
+    for _ in 0..3 {
+        let err = file.append(b"3").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+    }
+    fs.clear_write_failures();
+    file.append(b"3").unwrap();
+}
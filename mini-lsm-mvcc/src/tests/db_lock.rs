@@ -0,0 +1,55 @@
+use std::sync::Arc;
+
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    error::Error,
+    fs::{FaultInjectionFs, MemFs},
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+#[test]
+fn test_open_locked_dir() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"key1", b"1").unwrap();
+    match MiniLsm::open(&dir, options.clone()) {
+        Err(Error::Locked(path)) => assert_eq!(path, dir.path()),
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("a locked dir is opened"),
+    }
+    // the failed open does not affect the storage holding the lock
+    storage.put(b"key2", b"2").unwrap();
+
+    // released on close
+    storage.close().unwrap();
+    let storage2 = MiniLsm::open(&dir, options.clone()).unwrap();
+    assert_eq!(&storage2.get(b"key2").unwrap().unwrap()[..], b"2");
+    drop(storage);
+    assert!(matches!(
+        MiniLsm::open(&dir, options.clone()),
+        Err(Error::Locked(_))
+    ));
+
+    // released on drop
+    drop(storage2);
+    MiniLsm::open(&dir, options).unwrap();
+}
+
+#[test]
+fn test_crash_releases_lock() {
+    let fs = Arc::new(FaultInjectionFs::new(Arc::new(MemFs::new())));
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    options.fs = fs.clone();
+    let storage = MiniLsm::open("/db", options.clone()).unwrap();
+    assert!(matches!(
+        MiniLsm::open("/db", options.clone()),
+        Err(Error::Locked(_))
+    ));
+    options.fs = fs.crash().unwrap();
+    MiniLsm::open("/db", options).unwrap();
+    drop(storage);
+}
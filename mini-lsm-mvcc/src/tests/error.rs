@@ -0,0 +1,107 @@
+use std::{io::Write, time::Duration};
+
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    error::Error,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    mvcc::IsolationLevel,
+};
+
+#[test]
+fn test_conflict_and_busy() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.lock_timeout = Duration::from_millis(50);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    let txn1 = storage
+        .new_txn_with_isolation(IsolationLevel::SnapshotIsolation)
+        .unwrap();
+    let txn2 = storage
+        .new_txn_with_isolation(IsolationLevel::SnapshotIsolation)
+        .unwrap();
+    txn1.put(b"key1", b"1").unwrap();
+    txn2.put(b"key1", b"2").unwrap();
+    txn1.commit().unwrap();
+    let err = txn2.commit().unwrap_err();
+    assert!(matches!(err, Error::Conflict(_)), "{}", err);
+    assert!(err.is_retryable());
+
+    let txn1 = storage.new_pessimistic_txn().unwrap();
+    let txn2 = storage.new_pessimistic_txn().unwrap();
+    txn1.put(b"key1", b"3").unwrap();
+    let err = txn2.put(b"key1", b"4").unwrap_err();
+    assert!(matches!(err, Error::Busy(_)), "{}", err);
+    assert!(err.is_retryable());
+}
+
+#[test]
+fn test_invalid_argument() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put_with_ts(b"key1", b"1", 10).unwrap();
+    let err = storage.put_with_ts(b"key1", b"2", 5).unwrap_err();
+    assert!(matches!(err, Error::InvalidArgument(_)), "{}", err);
+    assert!(!err.is_retryable());
+    let err = storage
+        .new_txn()
+        .unwrap()
+        .rollback_to_savepoint()
+        .unwrap_err();
+    assert!(matches!(err, Error::InvalidArgument(_)), "{}", err);
+    let err = storage.commit_prepared("txn").unwrap_err();
+    assert!(matches!(err, Error::InvalidArgument(_)), "{}", err);
+}
+
+#[test]
+fn test_corrupted_wal() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"key1", b"1").unwrap();
+    storage.close().unwrap();
+    drop(storage);
+
+    let wal_path = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| {
+            path.extension().is_some_and(|ext| ext == "wal")
+                && std::fs::metadata(path).unwrap().len() > 0
+        })
+        .unwrap();
+    let wal_len = std::fs::metadata(&wal_path).unwrap().len();
+    // a record header claiming more data than written
+    std::fs::OpenOptions::new()
+        .append(true)
+        .open(&wal_path)
+        .unwrap()
+        .write_all(&[0, 0, 0, 100, 0])
+        .unwrap();
+    match MiniLsm::open(&dir, options.clone()) {
+        Err(Error::Corruption { file, offset, .. }) => {
+            assert_eq!(file, wal_path);
+            assert_eq!(offset, wal_len);
+        }
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("a corrupted WAL is recovered"),
+    }
+}
+
+#[test]
+fn test_closed() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"key1", b"1").unwrap();
+    let txn = storage.new_txn().unwrap();
+    txn.put(b"key2", b"2").unwrap();
+    storage.close().unwrap();
+    assert!(matches!(storage.put(b"key1", b"2"), Err(Error::Closed)));
+    assert!(matches!(storage.get(b"key1"), Err(Error::Closed)));
+    assert!(matches!(storage.new_txn(), Err(Error::Closed)));
+    assert!(matches!(txn.commit(), Err(Error::Closed)));
+}
@@ -0,0 +1,142 @@
+use std::ops::Bound;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    error::Error,
+    lsm_storage::{LsmStorageOptions, MiniLsm, WriteBatchRecord},
+    mvcc::IsolationLevel,
+    tests::harness::check_lsm_iter_result_by_key,
+};
+
+#[test]
+fn test_write_with_ts() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.set_safe_point(0).unwrap();
+    storage.put_with_ts(b"key1", b"1", 100).unwrap();
+    storage
+        .write_batch_with_ts(
+            &[
+                WriteBatchRecord::Put(&b"key1"[..], &b"2"[..]),
+                WriteBatchRecord::Put(&b"key2"[..], &b"2"[..]),
+            ],
+            200,
+        )
+        .unwrap();
+    assert_eq!(storage.inner.mvcc().latest_commit_ts(), 200);
+    // commit timestamps must be increasing
+    assert!(storage.put_with_ts(b"key1", b"3", 200).is_err());
+    assert!(storage.put_with_ts(b"key1", b"3", 150).is_err());
+    // local writes continue after the caller-provided ts
+    storage.put(b"key3", b"3").unwrap();
+    assert_eq!(storage.inner.mvcc().latest_commit_ts(), 201);
+    assert_eq!(storage.get_at(b"key1", 99).unwrap(), None);
+    assert_eq!(
+        storage.get_at(b"key1", 150).unwrap(),
+        Some(Bytes::from("1"))
+    );
+    assert_eq!(
+        storage.get_at(b"key1", 200).unwrap(),
+        Some(Bytes::from("2"))
+    );
+    check_lsm_iter_result_by_key(
+        &mut storage
+            .snapshot_at(200)
+            .unwrap()
+            .scan(Bound::Unbounded, Bound::Unbounded)
+            .unwrap(),
+        vec![
+            (Bytes::from("key1"), Bytes::from("2")),
+            (Bytes::from("key2"), Bytes::from("2")),
+        ],
+    );
+}
+
+#[test]
+fn test_invalid_batch_with_ts() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put_with_ts(b"key1", b"1", 100).unwrap();
+    let reserved_ts = *storage.inner.mvcc().reserved_ts.lock();
+    // a rejected batch neither commits nor reserves its ts
+    assert!(matches!(
+        storage.write_batch_with_ts(
+            &[
+                WriteBatchRecord::Put(&b"key2"[..], &b"2"[..]),
+                WriteBatchRecord::Del(&b""[..]),
+            ],
+            200,
+        ),
+        Err(Error::InvalidArgument(_))
+    ));
+    assert!(storage.put(b"", b"2").is_err());
+    assert_eq!(storage.inner.mvcc().latest_commit_ts(), 100);
+    assert_eq!(*storage.inner.mvcc().reserved_ts.lock(), reserved_ts);
+    assert_eq!(storage.get(b"key2").unwrap(), None);
+}
+
+#[test]
+fn test_read_above_latest_ts() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put_with_ts(b"key1", b"1", 100).unwrap();
+    let snapshot = storage.snapshot_at(300).unwrap();
+    // nothing can be committed at or below a ts that has been read
+    assert!(storage.put_with_ts(b"key1", b"2", 300).is_err());
+    storage.put_with_ts(b"key1", b"2", 301).unwrap();
+    assert_eq!(snapshot.get(b"key1").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(storage.get(b"key1").unwrap(), Some(Bytes::from("2")));
+}
+
+#[test]
+fn test_write_with_ts_conflict() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    let txn = storage
+        .new_txn_with_isolation(IsolationLevel::SnapshotIsolation)
+        .unwrap();
+    txn.put(b"key1", b"1").unwrap();
+    storage.put_with_ts(b"key1", b"2", 10).unwrap();
+    assert!(txn.commit().is_err());
+}
+
+#[test]
+fn test_safe_point() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put_with_ts(b"key1", b"1", 10).unwrap();
+    storage.set_safe_point(10).unwrap();
+    storage.put_with_ts(b"key1", b"2", 20).unwrap();
+    storage.put_with_ts(b"key1", b"3", 30).unwrap();
+    assert_eq!(storage.inner.mvcc().watermark(), 10);
+    storage.force_flush().unwrap();
+    storage.force_full_compaction().unwrap();
+    assert_eq!(storage.get_at(b"key1", 10).unwrap(), Some(Bytes::from("1")));
+    assert_eq!(storage.get_at(b"key1", 25).unwrap(), Some(Bytes::from("2")));
+    storage.set_safe_point(25).unwrap();
+    assert_eq!(storage.inner.mvcc().watermark(), 25);
+    assert!(storage.set_safe_point(20).is_err());
+    assert!(storage.get_at(b"key1", 10).is_err());
+    storage.force_full_compaction().unwrap();
+    assert_eq!(storage.get_at(b"key1", 25).unwrap(), Some(Bytes::from("2")));
+
+    // the safe point cannot move back even when a reader holds the watermark below it
+    let snapshot = storage.snapshot_at(25).unwrap();
+    storage.set_safe_point(30).unwrap();
+    assert_eq!(storage.inner.mvcc().watermark(), 25);
+    assert!(matches!(
+        storage.set_safe_point(28),
+        Err(Error::InvalidArgument(_))
+    ));
+    storage.set_safe_point(30).unwrap();
+    drop(snapshot);
+    assert_eq!(storage.inner.mvcc().watermark(), 30);
+}
@@ -0,0 +1,139 @@
+use bytes::Bytes;
+use moka::sync::ConcurrentCacheExt;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+fn key_of(i: usize) -> Vec<u8> {
+    format!("key_{:05}", i).into_bytes()
+}
+
+fn value_of(i: usize) -> Vec<u8> {
+    format!("value_{:010}", i).into_bytes()
+}
+
+fn options() -> LsmStorageOptions {
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.block_size = 256;
+    options.cache_filters = true;
+    options
+}
+
+/// Write a few SSTs to L0 and compact them to L1, then write one more SST to L0.
+fn fill(storage: &MiniLsm) {
+    for round in 0..3 {
+        for i in (round..300).step_by(3) {
+            storage.put(&key_of(i), &value_of(i)).unwrap();
+        }
+        storage.force_flush().unwrap();
+    }
+    storage.force_full_compaction().unwrap();
+    storage.put(&key_of(300), &value_of(300)).unwrap();
+    storage.force_flush().unwrap();
+}
+
+fn check(storage: &MiniLsm) {
+    for i in 0..=300 {
+        assert_eq!(
+            storage.get(&key_of(i)).unwrap(),
+            Some(Bytes::from(value_of(i)))
+        );
+    }
+    assert_eq!(storage.get(b"key_99999").unwrap(), None);
+}
+
+/// Whether the filters of the SSTs in L0 and L1 are pinned.
+fn pinned_filters(storage: &MiniLsm) -> (Vec<bool>, Vec<bool>) {
+    let state = storage.inner.state.read();
+    let pinned = |ids: &[usize]| {
+        ids.iter()
+            .map(|id| state.sstables[id].bloom.is_some())
+            .collect::<Vec<_>>()
+    };
+    (pinned(&state.l0_sstables), pinned(&state.levels[0].1))
+}
+
+#[test]
+fn test_cached_filters() {
+    let dir = tempdir().unwrap();
+    let mut options = options();
+    options.pinned_filter_levels = 0;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    fill(&storage);
+    let (l0, l1) = pinned_filters(&storage);
+    assert!(l0.iter().chain(&l1).all(|pinned| !pinned));
+    let usage = storage.memory_usage();
+    assert_eq!(usage.pinned_filters, 0);
+    assert_eq!(usage.cached_filters, 0);
+    assert!(usage.pinned_indexes > 0);
+
+    check(&storage);
+    storage.inner.block_cache.sync();
+    let usage = storage.memory_usage();
+    assert_eq!(usage.pinned_filters, 0);
+    assert!(usage.cached_filters > 0);
+    assert!(usage.cached_data_blocks > 0);
+    storage.close().unwrap();
+    drop(storage);
+
+    // filters are pinned again without `cache_filters`
+    options.cache_filters = false;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    let (l0, l1) = pinned_filters(&storage);
+    assert!(l0.iter().chain(&l1).all(|pinned| *pinned));
+    assert!(storage.memory_usage().pinned_filters > 0);
+    check(&storage);
+}
+
+#[test]
+fn test_pinned_filter_levels() {
+    let dir = tempdir().unwrap();
+    let options = options();
+    assert_eq!(options.pinned_filter_levels, 1);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    fill(&storage);
+    let check_pinned = |storage: &MiniLsm| {
+        let (l0, l1) = pinned_filters(storage);
+        assert_eq!(l0, vec![true]);
+        assert!(!l1.is_empty() && l1.iter().all(|pinned| !pinned));
+    };
+    check_pinned(&storage);
+    check(&storage);
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    check_pinned(&storage);
+    check(&storage);
+    storage.close().unwrap();
+    drop(storage);
+
+    let mut options = options;
+    options.pinned_filter_levels = 2;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    let (l0, l1) = pinned_filters(&storage);
+    assert!(l0.iter().chain(&l1).all(|pinned| *pinned));
+}
+
+#[test]
+fn test_filters_evicted_under_memory_pressure() {
+    let dir = tempdir().unwrap();
+    let mut options = options();
+    options.pinned_filter_levels = 0;
+    options.index_partition_size = 64;
+    options.pin_top_level_index = false;
+    options.block_cache_size = 2048;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    fill(&storage);
+    for _ in 0..2 {
+        check(&storage);
+    }
+    storage.inner.block_cache.sync();
+    assert!(storage.inner.block_cache.weighted_size() <= 2048);
+    let usage = storage.memory_usage();
+    assert_eq!(usage.pinned_filters, 0);
+    assert!(usage.cached_filters + usage.cached_indexes + usage.cached_data_blocks <= 2048);
+}
@@ -0,0 +1,282 @@
+use bytes::{BufMut, Bytes};
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    error::Error,
+    key::KeySlice,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    table::{
+        filter::{Filter, MAX_BITS_PER_KEY},
+        FileObject, FilterPolicy, FilterType, SsTable, SsTableBuilder,
+    },
+};
+
+const NUM_KEYS: usize = 10000;
+
+fn key_of(i: usize) -> Vec<u8> {
+    format!("key_{:05}", i).into_bytes()
+}
+
+fn value_of(i: usize) -> Vec<u8> {
+    format!("value_{:010}", i).into_bytes()
+}
+
+fn build_filter(policy: FilterPolicy) -> Filter {
+    let filter_type = policy.filter_type().unwrap();
+    let key_hashes = (0..NUM_KEYS)
+        .map(|i| filter_type.key_hash(&key_of(i)))
+        .collect::<Vec<_>>();
+    policy.build(&key_hashes).unwrap()
+}
+
+fn false_positive_rate(filter: &Filter) -> f64 {
+    let false_positives = (0..NUM_KEYS)
+        .filter(|i| filter.may_contain(format!("absent_{}", i).as_bytes()))
+        .count();
+    false_positives as f64 / NUM_KEYS as f64
+}
+
+#[test]
+fn test_filter_types() {
+    for (policy, max_fpr) in [
+        (
+            FilterPolicy::FalsePositiveRate(FilterType::Bloom, 0.01),
+            0.02,
+        ),
+        (
+            FilterPolicy::FalsePositiveRate(FilterType::BlockedBloom, 0.01),
+            0.03,
+        ),
+        (
+            FilterPolicy::FalsePositiveRate(FilterType::Ribbon, 0.01),
+            0.02,
+        ),
+        (FilterPolicy::BitsPerKey(FilterType::Bloom, 5), 0.15),
+        (FilterPolicy::BitsPerKey(FilterType::BlockedBloom, 5), 0.15),
+        (FilterPolicy::BitsPerKey(FilterType::Ribbon, 5), 0.15),
+        (
+            FilterPolicy::FalsePositiveRate(FilterType::Ribbon, 0.001),
+            0.003,
+        ),
+    ] {
+        let filter = build_filter(policy);
+        assert_eq!(Some(filter.filter_type), policy.filter_type());
+        for i in 0..NUM_KEYS {
+            assert!(
+                filter.may_contain(&key_of(i)),
+                "{:?}: key {} missing",
+                policy,
+                i
+            );
+        }
+        let fpr = false_positive_rate(&filter);
+        assert!(fpr <= max_fpr, "{:?}: fpr {}", policy, fpr);
+        assert!(fpr > 0.0, "{:?}: filter not taking effect?", policy);
+
+        let mut buf = Vec::new();
+        filter.encode(&mut buf);
+        let decoded = Filter::decode(&buf).unwrap();
+        assert_eq!(decoded.filter_type, filter.filter_type);
+        assert_eq!(decoded.k, filter.k);
+        assert_eq!(decoded.filter, filter.filter);
+        assert_eq!(false_positive_rate(&decoded), fpr);
+    }
+}
+
+#[test]
+fn test_ribbon_filter_size() {
+    let bloom = build_filter(FilterPolicy::FalsePositiveRate(FilterType::Bloom, 0.01));
+    let ribbon = build_filter(FilterPolicy::FalsePositiveRate(FilterType::Ribbon, 0.01));
+    assert_eq!(ribbon.k, 7);
+    assert!(
+        ribbon.memory_size() * 10 < bloom.memory_size() * 8,
+        "ribbon {} bytes, bloom {} bytes",
+        ribbon.memory_size(),
+        bloom.memory_size()
+    );
+    let ribbon = build_filter(FilterPolicy::BitsPerKey(FilterType::Ribbon, 10));
+    assert_eq!(ribbon.k, 7);
+
+    // the equations of a few keys have a solution too
+    for num_keys in [1, 2, 10, 100] {
+        let key_hashes = (0..num_keys)
+            .map(|i| FilterType::Ribbon.key_hash(&key_of(i)))
+            .collect::<Vec<_>>();
+        let filter = FilterPolicy::FalsePositiveRate(FilterType::Ribbon, 0.01)
+            .build(&key_hashes)
+            .unwrap();
+        for i in 0..num_keys {
+            assert!(filter.may_contain(&key_of(i)));
+        }
+    }
+}
+
+#[test]
+fn test_unknown_filter_type() {
+    let filter = build_filter(FilterPolicy::default());
+    let mut buf = Vec::new();
+    filter.encode(&mut buf);
+    buf.truncate(buf.len() - 4);
+    // replace k with a marker of no filter type
+    *buf.last_mut().unwrap() = 0x80;
+    let checksum = crc32fast::hash(&buf);
+    buf.put_u32(checksum);
+    assert!(Filter::decode(&buf).is_err());
+}
+
+#[test]
+fn test_filter_type_in_sst() {
+    let dir = tempdir().unwrap();
+    for (idx, policy) in [
+        FilterPolicy::default(),
+        FilterPolicy::BitsPerKey(FilterType::BlockedBloom, 10),
+        FilterPolicy::FalsePositiveRate(FilterType::Ribbon, 0.01),
+        FilterPolicy::None,
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let mut builder = SsTableBuilder::new(128).with_filter_policy(policy);
+        for i in 0..1000 {
+            builder.add(
+                KeySlice::for_testing_from_slice_no_ts(&key_of(i)),
+                &value_of(i),
+            );
+        }
+        let path = dir.path().join(format!("{}.sst", idx));
+        let sst = builder.build_for_test(&path).unwrap();
+        let sst2 = SsTable::open(0, None, FileObject::open(&path).unwrap()).unwrap();
+        for sst in [&sst, &sst2] {
+            assert_eq!(
+                sst.bloom.as_ref().map(|filter| filter.filter_type),
+                policy.filter_type()
+            );
+            for i in 0..1000 {
+                assert!(sst.may_contain(&key_of(i)).unwrap());
+            }
+        }
+        if let (Some(filter), Some(filter2)) = (&sst.bloom, &sst2.bloom) {
+            assert_eq!(filter.k, filter2.k);
+            assert_eq!(filter.filter, filter2.filter);
+        } else {
+            assert!(sst.may_contain(b"absent").unwrap());
+            assert!(sst2.may_contain(b"absent").unwrap());
+            assert_eq!(sst2.pinned_filter_size(), 0);
+        }
+    }
+}
+
+fn filter_types(storage: &MiniLsm) -> (Vec<Option<FilterType>>, Vec<Option<FilterType>>) {
+    let state = storage.inner.state.read();
+    let filter_types = |ids: &[usize]| {
+        ids.iter()
+            .map(|id| {
+                let filter = state.sstables[id].bloom.as_ref();
+                filter.map(|filter| filter.filter_type)
+            })
+            .collect::<Vec<_>>()
+    };
+    (
+        filter_types(&state.l0_sstables),
+        filter_types(&state.levels[0].1),
+    )
+}
+
+#[test]
+fn test_level_filter_policies() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.filter_policy = FilterPolicy::FalsePositiveRate(FilterType::Ribbon, 0.01);
+    options.level_filter_policies = vec![FilterPolicy::BitsPerKey(FilterType::BlockedBloom, 10)];
+    options.skip_bottom_level_filters = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    let check = |storage: &MiniLsm| {
+        for i in 0..=300 {
+            assert_eq!(
+                storage.get(&key_of(i)).unwrap(),
+                Some(Bytes::from(value_of(i)))
+            );
+        }
+        assert_eq!(storage.get(b"absent").unwrap(), None);
+    };
+    for i in 0..300 {
+        storage.put(&key_of(i), &value_of(i)).unwrap();
+    }
+    storage.force_flush().unwrap();
+    storage.force_full_compaction().unwrap();
+    storage.put(&key_of(300), &value_of(300)).unwrap();
+    storage.force_flush().unwrap();
+    let (l0, l1) = filter_types(&storage);
+    assert_eq!(l0, vec![Some(FilterType::BlockedBloom)]);
+    assert!(!l1.is_empty() && l1.iter().all(Option::is_none));
+    check(&storage);
+    storage.close().unwrap();
+    drop(storage);
+
+    // the readers decode the filters by the type recorded in the SSTs
+    options.level_filter_policies = Vec::new();
+    options.skip_bottom_level_filters = false;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    let (l0, l1) = filter_types(&storage);
+    assert_eq!(l0, vec![Some(FilterType::BlockedBloom)]);
+    assert!(l1.iter().all(Option::is_none));
+    check(&storage);
+    storage.force_full_compaction().unwrap();
+    let (l0, l1) = filter_types(&storage);
+    assert!(l0.is_empty());
+    assert!(!l1.is_empty() && l1.iter().all(|t| *t == Some(FilterType::Ribbon)));
+    check(&storage);
+}
+
+#[test]
+fn test_invalid_filter_policies() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    for filter_type in [
+        FilterType::Bloom,
+        FilterType::BlockedBloom,
+        FilterType::Ribbon,
+    ] {
+        for policy in [
+            FilterPolicy::FalsePositiveRate(filter_type, 0.0),
+            FilterPolicy::FalsePositiveRate(filter_type, -0.1),
+            FilterPolicy::FalsePositiveRate(filter_type, 1.0),
+            FilterPolicy::FalsePositiveRate(filter_type, f64::NAN),
+            FilterPolicy::BitsPerKey(filter_type, 0),
+            FilterPolicy::BitsPerKey(filter_type, MAX_BITS_PER_KEY + 1),
+            FilterPolicy::BitsPerKey(filter_type, usize::MAX),
+        ] {
+            let mut options = options.clone();
+            options.filter_policy = policy;
+            let err = MiniLsm::open(&dir, options.clone()).err().unwrap();
+            assert!(
+                matches!(err, Error::InvalidArgument(_)),
+                "{:?}: {}",
+                policy,
+                err
+            );
+            options.filter_policy = FilterPolicy::default();
+            options.level_filter_policies = vec![FilterPolicy::None, policy];
+            let err = MiniLsm::open(&dir, options).err().unwrap();
+            assert!(
+                matches!(err, Error::InvalidArgument(_)),
+                "{:?}: {}",
+                policy,
+                err
+            );
+        }
+    }
+
+    // the bounds are accepted
+    let mut options = options;
+    options.filter_policy = FilterPolicy::BitsPerKey(FilterType::Bloom, MAX_BITS_PER_KEY);
+    options.level_filter_policies = vec![
+        FilterPolicy::BitsPerKey(FilterType::Ribbon, 1),
+        FilterPolicy::FalsePositiveRate(FilterType::BlockedBloom, 1e-9),
+    ];
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    storage.put(b"key1", b"1").unwrap();
+    storage.force_flush().unwrap();
+    assert_eq!(storage.get(b"key1").unwrap(), Some(Bytes::from("1")));
+}
@@ -0,0 +1,170 @@
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    error::Error,
+    lsm_storage::{LsmStorageOptions, MiniLsm, WriteBatchRecord},
+    mvcc::IsolationLevel,
+};
+
+#[test]
+fn test_snapshot_read_lost_update() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"counter", b"1").unwrap();
+    let txn1 = storage
+        .new_txn_with_isolation(IsolationLevel::SnapshotRead)
+        .unwrap();
+    let txn2 = storage
+        .new_txn_with_isolation(IsolationLevel::SnapshotRead)
+        .unwrap();
+    assert_eq!(txn1.get(b"counter").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(txn2.get(b"counter").unwrap(), Some(Bytes::from("1")));
+    txn1.put(b"counter", b"2").unwrap();
+    txn2.put(b"counter", b"2").unwrap();
+    txn1.commit().unwrap();
+    txn2.commit().unwrap();
+    assert_eq!(storage.get(b"counter").unwrap(), Some(Bytes::from("2")));
+}
+
+#[test]
+fn test_snapshot_isolation_write_write_conflict() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"counter", b"1").unwrap();
+    let txn1 = storage
+        .new_txn_with_isolation(IsolationLevel::SnapshotIsolation)
+        .unwrap();
+    let txn2 = storage
+        .new_txn_with_isolation(IsolationLevel::SnapshotIsolation)
+        .unwrap();
+    assert_eq!(txn1.isolation(), IsolationLevel::SnapshotIsolation);
+    assert_eq!(txn1.get(b"counter").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(txn2.get(b"counter").unwrap(), Some(Bytes::from("1")));
+    txn1.put(b"counter", b"2").unwrap();
+    txn2.put(b"counter", b"2").unwrap();
+    txn1.commit().unwrap();
+    assert!(txn2.commit().is_err());
+    drop(txn2);
+    let txn3 = storage
+        .new_txn_with_isolation(IsolationLevel::SnapshotIsolation)
+        .unwrap();
+    txn3.put(b"counter", b"3").unwrap();
+    txn3.commit().unwrap();
+    assert_eq!(storage.get(b"counter").unwrap(), Some(Bytes::from("3")));
+}
+
+#[test]
+fn test_snapshot_isolation_conflict_with_other_levels() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    let txn1 = storage
+        .new_txn_with_isolation(IsolationLevel::SnapshotIsolation)
+        .unwrap();
+    let txn2 = storage.new_txn().unwrap();
+    assert_eq!(txn2.isolation(), IsolationLevel::SnapshotRead);
+    txn1.put(b"key1", b"1").unwrap();
+    txn2.put(b"key1", b"2").unwrap();
+    txn2.commit().unwrap();
+    assert!(txn1.commit().is_err());
+    drop(txn1);
+    assert_eq!(storage.get(b"key1").unwrap(), Some(Bytes::from("2")));
+}
+
+#[test]
+fn test_snapshot_isolation_write_skew() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"key1", b"1").unwrap();
+    storage.put(b"key2", b"2").unwrap();
+    let txn1 = storage
+        .new_txn_with_isolation(IsolationLevel::SnapshotIsolation)
+        .unwrap();
+    let txn2 = storage
+        .new_txn_with_isolation(IsolationLevel::SnapshotIsolation)
+        .unwrap();
+    txn1.put(b"key1", &txn1.get(b"key2").unwrap().unwrap())
+        .unwrap();
+    txn2.put(b"key2", &txn2.get(b"key1").unwrap().unwrap())
+        .unwrap();
+    // disjoint write sets are allowed under snapshot isolation, but not under serializable
+    txn1.commit().unwrap();
+    txn2.commit().unwrap();
+    assert_eq!(storage.get(b"key1").unwrap(), Some(Bytes::from("2")));
+    assert_eq!(storage.get(b"key2").unwrap(), Some(Bytes::from("1")));
+}
+
+#[test]
+fn test_serializable_per_txn() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"key1", b"1").unwrap();
+    storage.put(b"key2", b"2").unwrap();
+    let txn1 = storage
+        .new_txn_with_isolation(IsolationLevel::Serializable)
+        .unwrap();
+    let txn2 = storage
+        .new_txn_with_isolation(IsolationLevel::Serializable)
+        .unwrap();
+    txn1.put(b"key1", &txn1.get(b"key2").unwrap().unwrap())
+        .unwrap();
+    txn2.put(b"key2", &txn2.get(b"key1").unwrap().unwrap())
+        .unwrap();
+    txn1.commit().unwrap();
+    assert!(txn2.commit().is_err());
+}
+
+#[test]
+fn test_conflict_with_plain_writes() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.serializable = false;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"counter", b"1").unwrap();
+
+    // write-write conflict with a put
+    let txn = storage
+        .new_txn_with_isolation(IsolationLevel::SnapshotIsolation)
+        .unwrap();
+    assert_eq!(txn.get(b"counter").unwrap(), Some(Bytes::from("1")));
+    txn.put(b"counter", b"2").unwrap();
+    storage.put(b"counter", b"10").unwrap();
+    assert!(matches!(txn.commit(), Err(Error::Conflict(_))));
+    assert_eq!(storage.get(b"counter").unwrap(), Some(Bytes::from("10")));
+
+    // read-write conflict with a delete
+    let txn = storage
+        .new_txn_with_isolation(IsolationLevel::Serializable)
+        .unwrap();
+    assert_eq!(txn.get(b"counter").unwrap(), Some(Bytes::from("10")));
+    txn.put(b"other", b"1").unwrap();
+    storage.delete(b"counter").unwrap();
+    assert!(matches!(txn.commit(), Err(Error::Conflict(_))));
+    assert_eq!(storage.get(b"other").unwrap(), None);
+
+    // and with a write batch
+    let txn = storage
+        .new_txn_with_isolation(IsolationLevel::SnapshotIsolation)
+        .unwrap();
+    txn.put(b"counter", b"3").unwrap();
+    storage
+        .write_batch(&[WriteBatchRecord::Put(&b"counter"[..], &b"20"[..])])
+        .unwrap();
+    assert!(matches!(txn.commit(), Err(Error::Conflict(_))));
+    assert_eq!(storage.get(b"counter").unwrap(), Some(Bytes::from("20")));
+
+    // a plain write before the transaction starts does not conflict
+    let txn = storage
+        .new_txn_with_isolation(IsolationLevel::Serializable)
+        .unwrap();
+    assert_eq!(txn.get(b"counter").unwrap(), Some(Bytes::from("20")));
+    txn.put(b"counter", b"21").unwrap();
+    txn.commit().unwrap();
+    assert_eq!(storage.get(b"counter").unwrap(), Some(Bytes::from("21")));
+}
@@ -0,0 +1,303 @@
+use std::ops::Bound;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tempfile::tempdir;
+
+use crate::{
+    compact::{
+        CompactionOptions, CompactionTask, SimpleLeveledCompactionOptions,
+        SimpleLeveledCompactionTask,
+    },
+    error::Error,
+    iterators::StorageIterator,
+    lsm_storage::{LsmStorageOptions, MiniLsm, WriteBatchRecord},
+    manifest::ManifestRecord,
+    mvcc::{txn::Transaction, IsolationLevel},
+    table::{FilterPolicy, FilterType},
+};
+
+fn random_key(rng: &mut StdRng) -> Vec<u8> {
+    match rng.gen_range(0..10) {
+        0 => Vec::new(),
+        1 => vec![b'k'; u16::MAX as usize + 1],
+        _ => format!("key{}", rng.gen_range(0..20)).into_bytes(),
+    }
+}
+
+fn random_value(rng: &mut StdRng) -> Vec<u8> {
+    match rng.gen_range(0..10) {
+        0 => Vec::new(),
+        1 => vec![b'v'; u16::MAX as usize + 1],
+        _ => format!("value{}", rng.gen_range(0..100)).into_bytes(),
+    }
+}
+
+fn random_ts(rng: &mut StdRng, storage: &MiniLsm) -> u64 {
+    let latest = storage.inner.mvcc().latest_commit_ts();
+    match rng.gen_range(0..30) {
+        0 => 0,
+        1 => u64::MAX,
+        2 => u64::MAX - 1,
+        _ => rng.gen_range(0..latest.saturating_add(5)),
+    }
+}
+
+fn random_bound(rng: &mut StdRng) -> Bound<Vec<u8>> {
+    match rng.gen_range(0..3) {
+        0 => Bound::Unbounded,
+        1 => Bound::Included(random_key(rng)),
+        _ => Bound::Excluded(random_key(rng)),
+    }
+}
+
+fn as_bound(bound: &Bound<Vec<u8>>) -> Bound<&[u8]> {
+    match bound {
+        Bound::Unbounded => Bound::Unbounded,
+        Bound::Included(key) => Bound::Included(key),
+        Bound::Excluded(key) => Bound::Excluded(key),
+    }
+}
+
+fn random_options(rng: &mut StdRng) -> LsmStorageOptions {
+    let compaction_options = if rng.gen_bool(0.5) {
+        CompactionOptions::NoCompaction
+    } else {
+        CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+            size_ratio_percent: 200,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 3,
+        })
+    };
+    let mut options = LsmStorageOptions::default_for_week2_test(compaction_options);
+    options.target_sst_size = 1 << 12;
+    options.enable_wal = rng.gen_bool(0.5);
+    options.serializable = rng.gen_bool(0.5);
+    options
+}
+
+/// Apply a random operation on a transaction, which may be committed or prepared already.
+fn misuse_txn(rng: &mut StdRng, txn: &Arc<Transaction>) {
+    match rng.gen_range(0..9) {
+        0 => {
+            let _ = txn.put(&random_key(rng), &random_value(rng));
+        }
+        1 => {
+            let _ = txn.delete(&random_key(rng));
+        }
+        2 => {
+            let _ = txn.get(&random_key(rng));
+        }
+        3 => {
+            let (lower, upper) = (random_bound(rng), random_bound(rng));
+            if let Ok(mut iter) = txn.scan(as_bound(&lower), as_bound(&upper)) {
+                while iter.is_valid() && iter.next().is_ok() {}
+            }
+        }
+        4 => {
+            let _ = txn.set_savepoint();
+        }
+        5 => {
+            let _ = txn.rollback_to_savepoint();
+        }
+        6 => {
+            let _ = txn.rollback();
+        }
+        7 => {
+            let _ = txn.prepare(&format!("txn{}", rng.gen_range(0..3)));
+        }
+        _ => {
+            let _ = txn.commit();
+        }
+    }
+}
+
+fn misuse_storage(rng: &mut StdRng, storage: &MiniLsm, txns: &mut Vec<Arc<Transaction>>) {
+    match rng.gen_range(0..18) {
+        0 => {
+            let _ = storage.put(&random_key(rng), &random_value(rng));
+        }
+        1 => {
+            let _ = storage.delete(&random_key(rng));
+        }
+        2 => {
+            let batch = (0..rng.gen_range(0..4))
+                .map(|_| {
+                    if rng.gen_bool(0.5) {
+                        WriteBatchRecord::Put(random_key(rng), random_value(rng))
+                    } else {
+                        WriteBatchRecord::Del(random_key(rng))
+                    }
+                })
+                .collect::<Vec<_>>();
+            let _ = storage.write_batch(&batch);
+        }
+        3 => {
+            let ts = random_ts(rng, storage);
+            let _ = storage.put_with_ts(&random_key(rng), &random_value(rng), ts);
+        }
+        4 => {
+            let _ = storage.get(&random_key(rng));
+        }
+        5 => {
+            let ts = random_ts(rng, storage);
+            let _ = storage.get_at(&random_key(rng), ts);
+        }
+        6 => {
+            let (lower, upper) = (random_bound(rng), random_bound(rng));
+            if let Ok(mut iter) = storage.scan(as_bound(&lower), as_bound(&upper)) {
+                while iter.is_valid() && iter.next().is_ok() {}
+            }
+        }
+        7 => {
+            let ts = random_ts(rng, storage);
+            let _ = storage.set_safe_point(ts);
+        }
+        8 => {
+            let ts = random_ts(rng, storage);
+            if let Ok(updates) = storage.updates_since(ts) {
+                updates.for_each(drop);
+            }
+        }
+        9 => {
+            let name = format!("txn{}", rng.gen_range(0..3));
+            let _ = if rng.gen_bool(0.5) {
+                storage.commit_prepared(&name)
+            } else {
+                storage.rollback_prepared(&name)
+            };
+        }
+        10 => {
+            let isolation = [
+                IsolationLevel::SnapshotRead,
+                IsolationLevel::SnapshotIsolation,
+                IsolationLevel::Serializable,
+            ][rng.gen_range(0..3)];
+            if let Ok(txn) = storage.new_txn_with_isolation(isolation) {
+                txns.push(txn);
+            }
+        }
+        11..=13 => {
+            if !txns.is_empty() {
+                let idx = rng.gen_range(0..txns.len());
+                misuse_txn(rng, &txns[idx]);
+            }
+        }
+        14 => {
+            if !txns.is_empty() {
+                let idx = rng.gen_range(0..txns.len());
+                txns.swap_remove(idx);
+            }
+        }
+        15 => {
+            let _ = storage.force_flush();
+        }
+        16 => {
+            let _ = storage.force_full_compaction();
+        }
+        _ => {
+            let ts = random_ts(rng, storage);
+            if let Ok(snapshot) = storage.snapshot_at(ts) {
+                let _ = snapshot.get(&random_key(rng));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_random_misuse_never_panics() {
+    for seed in 0..32 {
+        println!("seed: {}", seed);
+        let mut rng = StdRng::seed_from_u64(seed);
+        let dir = tempdir().unwrap();
+        let options = random_options(&mut rng);
+        let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+        let mut txns = Vec::new();
+        for _ in 0..300 {
+            misuse_storage(&mut rng, &storage, &mut txns);
+        }
+        storage.close().unwrap();
+        // everything fails after close, without panicking
+        for _ in 0..50 {
+            misuse_storage(&mut rng, &storage, &mut txns);
+        }
+        drop(txns);
+        drop(storage);
+        let storage = MiniLsm::open(&dir, options).unwrap();
+        for _ in 0..50 {
+            misuse_storage(&mut rng, &storage, &mut Vec::new());
+        }
+    }
+}
+
+#[test]
+fn test_compaction_record_of_another_strategy() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"key1", b"1").unwrap();
+    storage.force_flush().unwrap();
+    let task = CompactionTask::Simple(SimpleLeveledCompactionTask {
+        upper_level: None,
+        upper_level_sst_ids: Vec::new(),
+        lower_level: 1,
+        lower_level_sst_ids: Vec::new(),
+        is_lower_level_bottom_level: true,
+    });
+    storage
+        .inner
+        .manifest()
+        .add_record(
+            &storage.inner.state_lock.lock(),
+            ManifestRecord::Compaction(task, Vec::new()),
+        )
+        .unwrap();
+    storage.close().unwrap();
+    drop(storage);
+
+    let err = MiniLsm::open(&dir, options).err().unwrap();
+    assert!(
+        matches!(&err, Error::InvalidArgument(message) if message.contains("compaction task")),
+        "{}",
+        err
+    );
+}
+
+type SetOption = fn(&mut LsmStorageOptions);
+
+#[test]
+fn test_invalid_options() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let invalid_options: [(&str, SetOption); 4] = [
+        ("filter policy", |options| {
+            options.filter_policy = FilterPolicy::FalsePositiveRate(FilterType::Bloom, 0.0)
+        }),
+        ("level filter policy", |options| {
+            options.level_filter_policies = vec![FilterPolicy::BitsPerKey(FilterType::Ribbon, 0)]
+        }),
+        ("block restart interval", |options| {
+            options.block_restart_interval = 0
+        }),
+        ("lock timeout", |options| {
+            options.lock_timeout = Duration::ZERO
+        }),
+    ];
+    for (name, set_invalid) in invalid_options {
+        let mut options = options.clone();
+        set_invalid(&mut options);
+        let err = MiniLsm::open(&dir, options).err().unwrap();
+        assert!(
+            matches!(err, Error::InvalidArgument(_)),
+            "{}: {}",
+            name,
+            err
+        );
+    }
+    // nothing is left behind that keeps valid options from opening the storage
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    storage.put(b"key1", b"1").unwrap();
+    storage.force_flush().unwrap();
+}
@@ -0,0 +1,519 @@
+//! A randomized test that runs long sequences of operations against `MiniLsm` and an in-memory
+//! model, on a file system that simulates crashes. Every read is compared with the model at the
+//! same read ts. A failing seed can be run alone with `MINI_LSM_SEED=<seed>`.
+
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::{
+    block::BlockFormat,
+    compact::{
+        CompactionOptions, LeveledCompactionOptions, SimpleLeveledCompactionOptions,
+        TieredCompactionOptions,
+    },
+    fs::{FaultInjectionFs, MemFs},
+    iterators::StorageIterator,
+    lsm_storage::{LsmStorageOptions, MiniLsm, WriteBatchRecord},
+    table::{FilterPolicy, FilterType},
+};
+
+const DB_PATH: &str = "/db";
+
+/// All versions written to the storage: key -> commit ts -> value, `None` for a delete.
+#[derive(Default)]
+struct Model {
+    versions: BTreeMap<Vec<u8>, BTreeMap<u64, Option<Vec<u8>>>>,
+}
+
+impl Model {
+    fn apply(&mut self, ts: u64, writes: &BTreeMap<Vec<u8>, Option<Vec<u8>>>) {
+        for (key, value) in writes {
+            self.versions
+                .entry(key.clone())
+                .or_default()
+                .insert(ts, value.clone());
+        }
+    }
+
+    fn get(&self, key: &[u8], read_ts: u64) -> Option<Vec<u8>> {
+        self.versions
+            .get(key)?
+            .range(..=read_ts)
+            .next_back()
+            .and_then(|(_, value)| value.clone())
+    }
+
+    fn scan(&self, lower: Bound<&[u8]>, upper: Bound<&[u8]>, read_ts: u64) -> Vec<(Bytes, Bytes)> {
+        self.versions
+            .range::<[u8], _>((lower, upper))
+            .filter_map(|(key, _)| Some((Bytes::from(key.clone()), self.get(key, read_ts)?.into())))
+            .collect()
+    }
+
+    /// The commit ts of the versions in `(lower, upper]`.
+    fn commit_ts_between(&self, lower: u64, upper: u64) -> Vec<u64> {
+        if lower >= upper {
+            return Vec::new();
+        }
+        let mut ts = self
+            .versions
+            .values()
+            .flat_map(|versions| versions.range(lower + 1..=upper).map(|(ts, _)| *ts))
+            .collect::<Vec<_>>();
+        ts.sort_unstable();
+        ts.dedup();
+        ts
+    }
+
+    /// Drop the versions committed after `ts`, which are lost in a crash.
+    fn truncate(&mut self, ts: u64) {
+        for versions in self.versions.values_mut() {
+            versions.split_off(&(ts + 1));
+        }
+        self.versions.retain(|_, versions| !versions.is_empty());
+    }
+}
+
+struct Harness {
+    seed: u64,
+    rng: StdRng,
+    options: LsmStorageOptions,
+    fs: Arc<FaultInjectionFs>,
+    storage: Arc<MiniLsm>,
+    model: Model,
+    /// All writes committed at or below this ts are synced and must survive a crash.
+    synced_ts: u64,
+    step: usize,
+}
+
+fn random_compaction_options(rng: &mut StdRng) -> CompactionOptions {
+    match rng.gen_range(0..4) {
+        0 => CompactionOptions::NoCompaction,
+        1 => CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+            size_ratio_percent: 200,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 3,
+        }),
+        2 => CompactionOptions::Tiered(TieredCompactionOptions {
+            num_tiers: 3,
+            max_size_amplification_percent: 200,
+            size_ratio: 1,
+            min_merge_width: 2,
+            max_merge_width: None,
+        }),
+        _ => CompactionOptions::Leveled(LeveledCompactionOptions {
+            level_size_multiplier: 2,
+            level0_file_num_compaction_trigger: 2,
+            max_levels: 3,
+            base_level_size_mb: 1,
+        }),
+    }
+}
+
+fn random_options(rng: &mut StdRng) -> LsmStorageOptions {
+    let compaction_options = random_compaction_options(rng);
+    let mut options = LsmStorageOptions::default_for_week2_test(compaction_options);
+    options.block_size = 256;
+    options.target_sst_size = 1 << 12;
+    options.enable_wal = rng.gen_bool(0.7);
+    options.serializable = rng.gen_bool(0.5);
+    options.block_restart_interval = rng.gen_range(1..=16);
+    options.block_format = match rng.gen_range(0..3) {
+        0 => BlockFormat::Varint,
+        1 => BlockFormat::Legacy,
+        _ => BlockFormat::Fixed,
+    };
+    options.block_hash_index = rng.gen_bool(0.5);
+    options.index_partition_size = if rng.gen_bool(0.5) { 0 } else { 128 };
+    options.pin_top_level_index = rng.gen_bool(0.5);
+    options.cache_filters = rng.gen_bool(0.5);
+    options.pinned_filter_levels = rng.gen_range(0..3);
+    options.block_cache_size = if rng.gen_bool(0.5) { 1 << 20 } else { 1 << 12 };
+    let mut random_filter_policy = || match rng.gen_range(0..4) {
+        0 => FilterPolicy::None,
+        1 => FilterPolicy::default(),
+        2 => FilterPolicy::BitsPerKey(FilterType::BlockedBloom, 10),
+        _ => FilterPolicy::FalsePositiveRate(FilterType::Ribbon, 0.01),
+    };
+    options.filter_policy = random_filter_policy();
+    options.level_filter_policies = vec![random_filter_policy()];
+    options.skip_bottom_level_filters = rng.gen_bool(0.5);
+    // keep all versions readable, so that reads at any past ts can be checked
+    options.history_retention = u64::MAX;
+    options
+}
+
+impl Harness {
+    fn new(seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut options = random_options(&mut rng);
+        let fs = Arc::new(FaultInjectionFs::new(Arc::new(MemFs::new())));
+        options.fs = fs.clone();
+        let storage = MiniLsm::open(DB_PATH, options.clone()).unwrap();
+        Self {
+            seed,
+            rng,
+            options,
+            fs,
+            storage,
+            model: Model::default(),
+            synced_ts: 0,
+            step: 0,
+        }
+    }
+
+    fn latest_commit_ts(&self) -> u64 {
+        self.storage.inner.mvcc().latest_commit_ts()
+    }
+
+    fn random_key(&mut self) -> Vec<u8> {
+        format!("key_{:03}", self.rng.gen_range(0..64)).into_bytes()
+    }
+
+    fn random_value(&mut self) -> Vec<u8> {
+        let len = self.rng.gen_range(1..64);
+        format!("value_{}_{}", self.step, "x".repeat(len)).into_bytes()
+    }
+
+    fn random_writes(&mut self) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+        (0..self.rng.gen_range(1..8))
+            .map(|_| {
+                let key = self.random_key();
+                let value = self.rng.gen_bool(0.7).then(|| self.random_value());
+                (key, value)
+            })
+            .collect()
+    }
+
+    fn random_bound(&mut self) -> Bound<Vec<u8>> {
+        match self.rng.gen_range(0..3) {
+            0 => Bound::Unbounded,
+            1 => Bound::Included(self.random_key()),
+            _ => Bound::Excluded(self.random_key()),
+        }
+    }
+
+    /// A retained read ts, mostly the commit ts of a version.
+    fn random_read_ts(&mut self) -> u64 {
+        let latest = self.latest_commit_ts();
+        let commit_ts = self.model.commit_ts_between(0, latest);
+        if commit_ts.is_empty() || self.rng.gen_bool(0.2) {
+            self.rng.gen_range(0..=latest)
+        } else {
+            commit_ts[self.rng.gen_range(0..commit_ts.len())]
+        }
+    }
+
+    /// Record writes that are acknowledged by the storage. They are committed at the latest
+    /// commit ts if it has advanced.
+    fn record_writes(&mut self, ts_before: u64, writes: &[(Vec<u8>, Option<Vec<u8>>)]) {
+        let ts = self.latest_commit_ts();
+        assert!(
+            ts > ts_before,
+            "{}: no commit ts for {:?}",
+            self.context(),
+            writes
+        );
+        let writes = writes.iter().cloned().collect::<BTreeMap<_, _>>();
+        self.model.apply(ts, &writes);
+    }
+
+    fn context(&self) -> String {
+        format!("seed {} step {}", self.seed, self.step)
+    }
+
+    fn check_get(&self, key: &[u8], read_ts: Option<u64>) {
+        let (actual, expected) = match read_ts {
+            Some(read_ts) => (
+                self.storage.get_at(key, read_ts).unwrap(),
+                self.model.get(key, read_ts),
+            ),
+            None => (
+                self.storage.get(key).unwrap(),
+                self.model.get(key, self.latest_commit_ts()),
+            ),
+        };
+        assert_eq!(
+            actual,
+            expected.map(Bytes::from),
+            "{}: get {:?} at {:?}",
+            self.context(),
+            String::from_utf8_lossy(key),
+            read_ts
+        );
+    }
+
+    fn scan_at(
+        &self,
+        lower: Bound<&[u8]>,
+        upper: Bound<&[u8]>,
+        read_ts: Option<u64>,
+    ) -> Vec<(Bytes, Bytes)> {
+        let mut result = Vec::new();
+        match read_ts {
+            Some(read_ts) => {
+                let snapshot = self.storage.snapshot_at(read_ts).unwrap();
+                let mut iter = snapshot.scan(lower, upper).unwrap();
+                while iter.is_valid() {
+                    result.push((
+                        Bytes::copy_from_slice(iter.key()),
+                        Bytes::copy_from_slice(iter.value()),
+                    ));
+                    iter.next().unwrap();
+                }
+            }
+            None => {
+                let mut iter = self.storage.scan(lower, upper).unwrap();
+                while iter.is_valid() {
+                    result.push((
+                        Bytes::copy_from_slice(iter.key()),
+                        Bytes::copy_from_slice(iter.value()),
+                    ));
+                    iter.next().unwrap();
+                }
+            }
+        }
+        result
+    }
+
+    fn check_scan(&mut self, read_ts: Option<u64>) {
+        let (lower, upper) = (self.random_bound(), self.random_bound());
+        let (lower, upper) = (as_bound(&lower), as_bound(&upper));
+        if is_empty_range(lower, upper) {
+            return;
+        }
+        let actual = self.scan_at(lower, upper, read_ts);
+        let expected = self
+            .model
+            .scan(lower, upper, read_ts.unwrap_or(self.latest_commit_ts()));
+        assert_eq!(
+            actual,
+            expected,
+            "{}: scan {:?} {:?} at {:?}",
+            self.context(),
+            lower.map(Bytes::copy_from_slice),
+            upper.map(Bytes::copy_from_slice),
+            read_ts
+        );
+    }
+
+    fn write_batch(&mut self) {
+        let writes = self.random_writes();
+        let batch = writes
+            .iter()
+            .map(|(key, value)| match value {
+                Some(value) => WriteBatchRecord::Put(key.as_slice(), value.as_slice()),
+                None => WriteBatchRecord::Del(key.as_slice()),
+            })
+            .collect::<Vec<_>>();
+        let ts_before = self.latest_commit_ts();
+        self.storage.write_batch(&batch).unwrap();
+        self.record_writes(ts_before, &writes);
+    }
+
+    /// Run a transaction that reads its own writes on top of the model at its read ts.
+    fn transaction(&mut self) {
+        let txn = self.storage.new_txn().unwrap();
+        let read_ts = txn.read_ts;
+        let mut local = BTreeMap::new();
+        for _ in 0..self.rng.gen_range(1..10) {
+            let key = self.random_key();
+            match self.rng.gen_range(0..3) {
+                0 => {
+                    let value = self.random_value();
+                    txn.put(&key, &value).unwrap();
+                    local.insert(key, Some(value));
+                }
+                1 => {
+                    txn.delete(&key).unwrap();
+                    local.insert(key, None);
+                }
+                _ => {
+                    let expected = match local.get(&key) {
+                        Some(value) => value.clone(),
+                        None => self.model.get(&key, read_ts),
+                    };
+                    assert_eq!(
+                        txn.get(&key).unwrap(),
+                        expected.map(Bytes::from),
+                        "{}: txn get {:?}",
+                        self.context(),
+                        String::from_utf8_lossy(&key)
+                    );
+                }
+            }
+        }
+        if self.rng.gen_bool(0.2) {
+            txn.rollback().unwrap();
+            return;
+        }
+        let ts_before = self.latest_commit_ts();
+        txn.commit().unwrap();
+        if !local.is_empty() {
+            let writes = local.into_iter().collect::<Vec<_>>();
+            self.record_writes(ts_before, &writes);
+        }
+    }
+
+    fn reopen(&mut self) {
+        self.storage = MiniLsm::open(DB_PATH, self.options.clone()).unwrap();
+    }
+
+    fn close_and_reopen(&mut self) {
+        self.storage.close().unwrap();
+        self.synced_ts = self.latest_commit_ts();
+        self.reopen();
+    }
+
+    /// Crash, and check that the recovered storage has all synced writes and a prefix of the
+    /// writes committed after them.
+    fn crash_and_recover(&mut self) {
+        let fs = self.fs.crash().unwrap();
+        self.fs = fs.clone();
+        self.options.fs = fs;
+        self.reopen();
+        // Commit ts above the recovered latest commit ts are handed out again, so the versions at
+        // them are lost. They cannot be synced, though the ts of an empty commit may be.
+        let latest = self.latest_commit_ts();
+        let lost_synced = self.model.commit_ts_between(latest, self.synced_ts);
+        assert!(
+            lost_synced.is_empty(),
+            "{}: synced versions at {:?} are lost",
+            self.context(),
+            lost_synced
+        );
+        self.synced_ts = self.synced_ts.min(latest);
+        let candidates = self.model.commit_ts_between(self.synced_ts, latest);
+        let recovered = self.scan_at(Bound::Unbounded, Bound::Unbounded, None);
+        // the largest ts at which the model agrees with the recovered storage on every version
+        // committed after the synced ts
+        let recovered_ts = candidates
+            .iter()
+            .rev()
+            .copied()
+            .chain(std::iter::once(self.synced_ts))
+            .find(|&ts| {
+                recovered == self.model.scan(Bound::Unbounded, Bound::Unbounded, ts)
+                    && candidates.iter().take_while(|&&x| x <= ts).all(|&x| {
+                        self.scan_at(Bound::Unbounded, Bound::Unbounded, Some(x))
+                            == self.model.scan(Bound::Unbounded, Bound::Unbounded, x)
+                    })
+            })
+            .unwrap_or_else(|| {
+                panic!(
+                    "{}: recovered data does not match any ts since the synced ts {}",
+                    self.context(),
+                    self.synced_ts
+                )
+            });
+        self.model.truncate(recovered_ts);
+        self.synced_ts = recovered_ts;
+    }
+
+    fn step(&mut self) {
+        self.step += 1;
+        match self.rng.gen_range(0..100) {
+            0..=24 => {
+                let (key, value) = (self.random_key(), self.random_value());
+                let ts_before = self.latest_commit_ts();
+                self.storage.put(&key, &value).unwrap();
+                self.record_writes(ts_before, &[(key, Some(value))]);
+            }
+            25..=29 => {
+                let key = self.random_key();
+                let ts_before = self.latest_commit_ts();
+                self.storage.delete(&key).unwrap();
+                self.record_writes(ts_before, &[(key, None)]);
+            }
+            30..=39 => self.write_batch(),
+            40..=47 => self.transaction(),
+            48..=59 => {
+                let key = self.random_key();
+                self.check_get(&key, None);
+            }
+            60..=69 => {
+                let key = self.random_key();
+                let read_ts = self.random_read_ts();
+                self.check_get(&key, Some(read_ts));
+            }
+            70..=76 => self.check_scan(None),
+            77..=82 => {
+                let read_ts = self.random_read_ts();
+                self.check_scan(Some(read_ts));
+            }
+            83..=87 => {
+                self.storage.sync().unwrap();
+                if self.options.enable_wal {
+                    self.synced_ts = self.latest_commit_ts();
+                }
+            }
+            88..=91 => self.storage.force_flush().unwrap(),
+            92 => {
+                if let CompactionOptions::NoCompaction = self.options.compaction_options {
+                    self.storage.force_full_compaction().unwrap();
+                }
+            }
+            93 => {
+                let compaction_options = random_compaction_options(&mut self.rng);
+                self.storage
+                    .change_compaction_strategy(compaction_options.clone())
+                    .unwrap();
+                self.options.compaction_options = compaction_options;
+            }
+            94..=96 => self.close_and_reopen(),
+            _ => {
+                // without the WAL, writes since the last flush are lost anyway
+                if self.options.enable_wal {
+                    self.crash_and_recover();
+                }
+            }
+        }
+    }
+}
+
+fn as_bound(bound: &Bound<Vec<u8>>) -> Bound<&[u8]> {
+    match bound {
+        Bound::Unbounded => Bound::Unbounded,
+        Bound::Included(key) => Bound::Included(key),
+        Bound::Excluded(key) => Bound::Excluded(key),
+    }
+}
+
+/// Whether the range is empty, in which case `BTreeMap::range` panics on some bounds.
+fn is_empty_range(lower: Bound<&[u8]>, upper: Bound<&[u8]>) -> bool {
+    match (lower, upper) {
+        (Bound::Included(lower), Bound::Included(upper)) => lower > upper,
+        (Bound::Included(lower) | Bound::Excluded(lower), Bound::Excluded(upper))
+        | (Bound::Excluded(lower), Bound::Included(upper)) => lower >= upper,
+        _ => false,
+    }
+}
+
+fn run_seed(seed: u64, steps: usize) {
+    println!("seed: {}", seed);
+    let mut harness = Harness::new(seed);
+    for _ in 0..steps {
+        harness.step();
+    }
+    harness.close_and_reopen();
+    for key in harness.model.versions.keys() {
+        harness.check_get(key, None);
+    }
+}
+
+#[test]
+fn test_model_check() {
+    match std::env::var("MINI_LSM_SEED") {
+        Ok(seed) => run_seed(seed.parse().expect("invalid MINI_LSM_SEED"), 2000),
+        Err(_) => {
+            for seed in 0..16 {
+                run_seed(seed, 400);
+            }
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::{CompactionOptions, SimpleLeveledCompactionOptions, TieredCompactionOptions},
+    error::Error,
+    fs::PosixFs,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    options_file::PersistedOptions,
+};
+
+fn simple_options(max_levels: usize) -> CompactionOptions {
+    CompactionOptions::Simple(SimpleLeveledCompactionOptions {
+        size_ratio_percent: 200,
+        level0_file_num_compaction_trigger: 2,
+        max_levels,
+    })
+}
+
+#[test]
+fn test_options_persisted() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(simple_options(3));
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.close().unwrap();
+    drop(storage);
+    let persisted = PersistedOptions::read(&PosixFs, dir.path())
+        .unwrap()
+        .unwrap();
+    assert_eq!(persisted, PersistedOptions::new(&options));
+
+    // options that do not change the layout are updated on reopen
+    let mut options = options;
+    options.block_size = 1024;
+    options.history_retention = 10;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.close().unwrap();
+    let persisted = PersistedOptions::read(&PosixFs, dir.path())
+        .unwrap()
+        .unwrap();
+    assert_eq!(persisted, PersistedOptions::new(&options));
+}
+
+#[test]
+fn test_change_strategy_after_compaction() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"key1", b"1").unwrap();
+    storage.force_flush().unwrap();
+    storage.force_full_compaction().unwrap();
+    storage.close().unwrap();
+    drop(storage);
+
+    let tiered = LsmStorageOptions::default_for_week2_test(CompactionOptions::Tiered(
+        TieredCompactionOptions {
+            num_tiers: 3,
+            max_size_amplification_percent: 200,
+            size_ratio: 1,
+            min_merge_width: 2,
+            max_merge_width: None,
+        },
+    ));
+    match MiniLsm::open(&dir, tiered) {
+        Err(Error::InvalidArgument(message)) => {
+            assert!(message.contains("no compaction"), "{}", message);
+            assert!(message.contains("tiered"), "{}", message);
+        }
+        Err(err) => panic!("unexpected error: {}", err),
+        Ok(_) => panic!("opened with an incompatible compaction strategy"),
+    }
+    // the failed open leaves the DB as is
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(
+        storage.get(b"key1").unwrap(),
+        Some(Bytes::from_static(b"1"))
+    );
+}
+
+#[test]
+fn test_change_strategy_before_compaction() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    storage.put(b"key1", b"1").unwrap();
+    storage.close().unwrap();
+    drop(storage);
+
+    // only flushes are recorded, which are replayed with any strategy
+    let options = LsmStorageOptions::default_for_week2_test(simple_options(2));
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    assert_eq!(
+        storage.get(b"key1").unwrap(),
+        Some(Bytes::from_static(b"1"))
+    );
+    storage.close().unwrap();
+    drop(storage);
+    let persisted = PersistedOptions::read(&PosixFs, dir.path())
+        .unwrap()
+        .unwrap();
+    assert_eq!(persisted.compaction_options, simple_options(2));
+}
+
+#[test]
+fn test_enable_and_disable_wal() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"key1", b"1").unwrap();
+    storage.close().unwrap();
+    drop(storage);
+
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"key2", b"2").unwrap();
+    storage.close().unwrap();
+    drop(storage);
+
+    options.enable_wal = false;
+    assert!(matches!(
+        MiniLsm::open(&dir, options.clone()),
+        Err(Error::InvalidArgument(_))
+    ));
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(
+        storage.get(b"key1").unwrap(),
+        Some(Bytes::from_static(b"1"))
+    );
+    assert_eq!(
+        storage.get(b"key2").unwrap(),
+        Some(Bytes::from_static(b"2"))
+    );
+}
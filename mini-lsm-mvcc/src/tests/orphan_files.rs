@@ -0,0 +1,154 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use crate::{
+    compact::CompactionOptions,
+    fs::{temp_path, FaultInjectionFs, FileSystem, MemFs},
+    lsm_storage::{LsmStorageInner, LsmStorageOptions, MiniLsm, OrphanFile},
+};
+
+const DB_PATH: &str = "/db";
+
+fn options_with_fs(fs: Arc<dyn FileSystem>) -> LsmStorageOptions {
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    options.fs = fs;
+    options
+}
+
+fn write_file(fs: &MemFs, path: &Path, data: &[u8]) {
+    let mut file = fs.create(path).unwrap();
+    file.append(data).unwrap();
+    file.sync().unwrap();
+}
+
+#[test]
+fn test_orphan_files_dry_run() {
+    let fs = Arc::new(MemFs::new());
+    let storage = MiniLsm::open(DB_PATH, options_with_fs(fs.clone())).unwrap();
+    storage.put(b"key1", b"1").unwrap();
+    storage.force_flush().unwrap();
+    storage.close().unwrap();
+    drop(storage);
+
+    let sst_path = LsmStorageInner::path_of_sst_static(DB_PATH, 999);
+    let wal_path = LsmStorageInner::path_of_wal_static(DB_PATH, 998);
+    let other_path = Path::new(DB_PATH).join("notes.txt");
+    write_file(&fs, &sst_path, b"half-written sst");
+    write_file(&fs, &wal_path, b"stale wal");
+    write_file(&fs, &other_path, b"not ours");
+    let expected = vec![
+        OrphanFile {
+            path: wal_path.clone(),
+            size: 9,
+        },
+        OrphanFile {
+            path: sst_path.clone(),
+            size: 16,
+        },
+    ];
+
+    let mut options = options_with_fs(fs.clone());
+    options.delete_orphan_files = false;
+    let storage = MiniLsm::open(DB_PATH, options).unwrap();
+    assert_eq!(storage.orphan_files(), expected);
+    assert!(fs.exists(&sst_path) && fs.exists(&wal_path));
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(DB_PATH, options_with_fs(fs.clone())).unwrap();
+    assert_eq!(storage.orphan_files(), expected);
+    assert!(!fs.exists(&sst_path) && !fs.exists(&wal_path));
+    assert!(fs.exists(&other_path));
+    assert_eq!(
+        storage.get(b"key1").unwrap(),
+        Some(Bytes::from_static(b"1"))
+    );
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(DB_PATH, options_with_fs(fs)).unwrap();
+    assert!(storage.orphan_files().is_empty());
+}
+
+#[test]
+fn test_compacted_ssts_deleted_on_open() {
+    let fs = Arc::new(MemFs::new());
+    let storage = MiniLsm::open(DB_PATH, options_with_fs(fs.clone())).unwrap();
+    for i in 0..3 {
+        storage.put(b"key1", format!("{}", i).as_bytes()).unwrap();
+        storage.force_flush().unwrap();
+    }
+    let compacted = storage
+        .inner
+        .state
+        .read()
+        .l0_sstables
+        .iter()
+        .map(|id| {
+            let path = LsmStorageInner::path_of_sst_static(DB_PATH, *id);
+            let data = fs.read(&path).unwrap();
+            (path, data)
+        })
+        .collect::<Vec<_>>();
+    storage.force_full_compaction().unwrap();
+    // as if the storage crashed before removing the compacted SSTs
+    for (path, data) in &compacted {
+        assert!(!fs.exists(path));
+        write_file(&fs, path, data);
+    }
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(DB_PATH, options_with_fs(fs.clone())).unwrap();
+    assert_eq!(storage.orphan_files().len(), compacted.len());
+    for (path, _) in &compacted {
+        assert!(!fs.exists(path));
+    }
+    assert_eq!(
+        storage.get(b"key1").unwrap(),
+        Some(Bytes::from_static(b"2"))
+    );
+}
+
+#[test]
+fn test_interrupted_sst_write() {
+    let fs = Arc::new(FaultInjectionFs::new(Arc::new(MemFs::new())));
+    let storage = MiniLsm::open(DB_PATH, options_with_fs(fs.clone())).unwrap();
+    storage.put(b"key1", b"1").unwrap();
+    storage
+        .inner
+        .force_freeze_memtable(&storage.inner.state_lock.lock())
+        .unwrap();
+    let sst_id = storage.inner.state.read().imm_memtables[0].id();
+    // the temporary file is created, but the write to it fails
+    fs.fill_disk_after_writes(1);
+    assert!(storage.inner.force_flush_next_imm_memtable().is_err());
+    fs.clear_write_failures();
+    // the SST is only written to the temporary file
+    let sst_path = LsmStorageInner::path_of_sst_static(DB_PATH, sst_id);
+    assert!(!fs.exists(&sst_path));
+    assert!(fs.exists(&temp_path(&sst_path)));
+    drop(storage);
+
+    let storage = MiniLsm::open(DB_PATH, options_with_fs(fs.clone())).unwrap();
+    assert_eq!(
+        storage.orphan_files(),
+        [OrphanFile {
+            path: temp_path(&sst_path),
+            size: 0,
+        }]
+    );
+    assert!(!fs.exists(&temp_path(&sst_path)));
+    assert_eq!(
+        storage.get(b"key1").unwrap(),
+        Some(Bytes::from_static(b"1"))
+    );
+    storage.force_flush().unwrap();
+    assert_eq!(
+        storage.get(b"key1").unwrap(),
+        Some(Bytes::from_static(b"1"))
+    );
+}
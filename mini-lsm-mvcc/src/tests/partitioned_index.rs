@@ -0,0 +1,186 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use moka::sync::ConcurrentCacheExt;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    iterators::StorageIterator,
+    key::KeySlice,
+    lsm_storage::{new_block_cache, CachedBlock, LsmStorageOptions, MiniLsm},
+    table::{FileObject, SsTable, SsTableBuilder, SsTableIterator, SstPinning},
+};
+
+fn key_of(i: usize) -> Vec<u8> {
+    format!("key_{:05}", i * 2).into_bytes()
+}
+
+fn value_of(i: usize) -> Vec<u8> {
+    format!("value_{:010}", i).into_bytes()
+}
+
+fn build_sst(path: &std::path::Path, partition_size: usize) -> SsTable {
+    let mut builder = SsTableBuilder::new(128).with_partitioned_index(partition_size);
+    for i in 0..1000 {
+        builder.add(
+            KeySlice::for_testing_from_slice_with_ts(&key_of(i), 1),
+            &value_of(i),
+        );
+    }
+    builder.build_for_test(path).unwrap()
+}
+
+fn check_sst(sst: Arc<SsTable>) {
+    assert_eq!(sst.first_key().key_ref(), key_of(0));
+    assert_eq!(sst.last_key().key_ref(), key_of(999));
+    let mut iter = SsTableIterator::create_and_seek_to_first(sst.clone()).unwrap();
+    for i in 0..1000 {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key().key_ref(), key_of(i));
+        assert_eq!(iter.value(), value_of(i));
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+    for i in 0..1000 {
+        for key in [key_of(i), format!("key_{:05}", i * 2 + 1).into_bytes()] {
+            let mut iter = SsTableIterator::create_and_seek_to_key(
+                sst.clone(),
+                KeySlice::for_testing_from_slice_with_ts(&key, 1),
+            )
+            .unwrap();
+            if key > key_of(999) {
+                assert!(!iter.is_valid());
+                continue;
+            }
+            let expected = if key == key_of(i) { i } else { i + 1 };
+            assert_eq!(iter.key().key_ref(), key_of(expected));
+            iter.next().unwrap();
+            if expected + 1 < 1000 {
+                assert_eq!(iter.key().key_ref(), key_of(expected + 1));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_partitioned_index() {
+    let dir = tempdir().unwrap();
+    let flat = build_sst(&dir.path().join("1.sst"), 0);
+    let sst = build_sst(&dir.path().join("2.sst"), 256);
+    assert!(flat.partitioned_index.is_none());
+    assert!(sst.block_meta.is_empty());
+    let index = sst.partitioned_index.as_ref().unwrap();
+    let top_level_index = index.pinned.as_ref().unwrap();
+    assert!(top_level_index.partitions.len() > 10);
+    assert_eq!(sst.num_of_blocks(), flat.num_of_blocks());
+    check_sst(Arc::new(sst));
+
+    // a partition may hold a single block
+    let sst = build_sst(&dir.path().join("3.sst"), 1);
+    let index = sst.partitioned_index.as_ref().unwrap();
+    assert_eq!(
+        index.pinned.as_ref().unwrap().partitions.len(),
+        sst.num_of_blocks()
+    );
+    check_sst(Arc::new(sst));
+}
+
+#[test]
+fn test_partitioned_index_lazy_load() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("1.sst");
+    let built = build_sst(&path, 256);
+    let num_partitions = built
+        .partitioned_index
+        .as_ref()
+        .unwrap()
+        .pinned
+        .as_ref()
+        .unwrap()
+        .partitions
+        .len();
+
+    let pinned = SsTable::open_for_test(FileObject::open(&path).unwrap()).unwrap();
+    assert!(pinned.partitioned_index.as_ref().unwrap().pinned.is_some());
+    check_sst(Arc::new(pinned));
+
+    let block_cache = Arc::new(new_block_cache(1 << 20));
+    let sst = SsTable::open_with(
+        1,
+        Some(block_cache.clone()),
+        FileObject::open(&path).unwrap(),
+        SstPinning {
+            top_level_index: false,
+            filter: true,
+        },
+    )
+    .unwrap();
+    assert!(sst.partitioned_index.as_ref().unwrap().pinned.is_none());
+    assert_eq!(sst.first_key().key_ref(), key_of(0));
+    assert_eq!(sst.last_key().key_ref(), key_of(999));
+    // nothing is loaded until the first read
+    block_cache.sync();
+    assert_eq!(block_cache.entry_count(), 0);
+    let sst = Arc::new(sst);
+    let iter = SsTableIterator::create_and_seek_to_key(
+        sst.clone(),
+        KeySlice::for_testing_from_slice_with_ts(&key_of(500), 1),
+    )
+    .unwrap();
+    assert_eq!(iter.key().key_ref(), key_of(500));
+    block_cache.sync();
+    let count = |f: fn(&CachedBlock) -> bool| block_cache.iter().filter(|(_, b)| f(b)).count();
+    assert_eq!(count(|b| matches!(b, CachedBlock::TopLevelIndex(_))), 1);
+    assert_eq!(count(|b| matches!(b, CachedBlock::IndexPartition(_))), 1);
+    assert_eq!(count(|b| matches!(b, CachedBlock::Data(_))), 1);
+
+    check_sst(sst);
+    block_cache.sync();
+    assert_eq!(
+        count(|b| matches!(b, CachedBlock::IndexPartition(_))),
+        num_partitions
+    );
+}
+
+#[test]
+fn test_storage_with_partitioned_index() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.block_size = 128;
+    options.index_partition_size = 128;
+    options.pin_top_level_index = false;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    for round in 0..3 {
+        for i in 0..500 {
+            storage.put(&key_of(i), &value_of(i + round)).unwrap();
+        }
+        storage.force_flush().unwrap();
+    }
+    storage.force_full_compaction().unwrap();
+    for i in 0..500 {
+        assert_eq!(
+            storage.get(&key_of(i)).unwrap(),
+            Some(Bytes::from(value_of(i + 2)))
+        );
+    }
+    storage.close().unwrap();
+    drop(storage);
+
+    // the index type is recorded in each SST, whatever the options on reopen
+    options.index_partition_size = 0;
+    options.pin_top_level_index = true;
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    for sst in storage.inner.state.read().sstables.values() {
+        assert!(sst.partitioned_index.as_ref().unwrap().pinned.is_some());
+    }
+    let mut iter = storage
+        .scan(std::ops::Bound::Unbounded, std::ops::Bound::Unbounded)
+        .unwrap();
+    for i in 0..500 {
+        assert_eq!(iter.key(), key_of(i));
+        assert_eq!(iter.value(), value_of(i + 2));
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
@@ -0,0 +1,150 @@
+use std::{sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    error::Error,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+#[test]
+fn test_pessimistic_lock_wait() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"key1", b"1").unwrap();
+    let txn1 = storage.new_pessimistic_txn().unwrap();
+    txn1.put(b"key1", b"2").unwrap();
+    let handle = {
+        let storage = storage.clone();
+        std::thread::spawn(move || {
+            let txn2 = storage.new_pessimistic_txn().unwrap();
+            // blocks until txn1 commits, and then reads the value committed by txn1
+            let value = txn2.get_for_update(b"key1").unwrap();
+            assert_eq!(txn2.get(b"key1").unwrap(), Some(Bytes::from("1")));
+            txn2.put(b"key1", b"3").unwrap();
+            txn2.commit().unwrap();
+            value
+        })
+    };
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(!handle.is_finished());
+    txn1.commit().unwrap();
+    assert_eq!(handle.join().unwrap(), Some(Bytes::from("2")));
+    assert_eq!(storage.get(b"key1").unwrap(), Some(Bytes::from("3")));
+}
+
+#[test]
+fn test_pessimistic_lock_timeout() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.lock_timeout = Duration::from_millis(50);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    let txn1 = storage.new_pessimistic_txn().unwrap();
+    let txn2 = storage.new_pessimistic_txn().unwrap();
+    txn1.delete(b"key1").unwrap();
+    assert!(txn2.put(b"key1", b"2").is_err());
+    assert!(txn2.get_for_update(b"key1").is_err());
+    // locks are released when the transaction is dropped
+    drop(txn1);
+    txn2.put(b"key1", b"2").unwrap();
+    txn2.commit().unwrap();
+    assert_eq!(storage.get(b"key1").unwrap(), Some(Bytes::from("2")));
+}
+
+#[test]
+fn test_pessimistic_deadlock() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.lock_timeout = Duration::from_secs(10);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    let txn1 = storage.new_pessimistic_txn().unwrap();
+    let txn2 = storage.new_pessimistic_txn().unwrap();
+    txn1.put(b"key1", b"1").unwrap();
+    txn2.put(b"key2", b"2").unwrap();
+    let handle = {
+        let txn2 = txn2.clone();
+        std::thread::spawn(move || txn2.put(b"key1", b"2").is_ok())
+    };
+    while storage.inner.mvcc().lock_manager.num_waiters() == 0 {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    // txn1 -> txn2 -> txn1
+    assert!(txn1.put(b"key2", b"1").is_err());
+    drop(txn1);
+    assert!(handle.join().unwrap());
+    txn2.commit().unwrap();
+    assert_eq!(storage.get(b"key1").unwrap(), Some(Bytes::from("2")));
+    assert_eq!(storage.get(b"key2").unwrap(), Some(Bytes::from("2")));
+}
+
+#[test]
+fn test_pessimistic_counter() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.lock_timeout = Duration::from_secs(10);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"counter", b"0").unwrap();
+    let handles = (0..8)
+        .map(|_| {
+            let storage = Arc::clone(&storage);
+            std::thread::spawn(move || {
+                for _ in 0..50 {
+                    let txn = storage.new_pessimistic_txn().unwrap();
+                    let value = txn.get_for_update(b"counter").unwrap().unwrap();
+                    let value = std::str::from_utf8(&value).unwrap().parse::<u64>().unwrap();
+                    txn.put(b"counter", (value + 1).to_string().as_bytes())
+                        .unwrap();
+                    txn.commit().unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(storage.get(b"counter").unwrap(), Some(Bytes::from("400")));
+}
+
+#[test]
+fn test_pessimistic_write_conflict() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"key1", b"1").unwrap();
+
+    // a plain write after the read ts
+    let txn = storage.new_pessimistic_txn().unwrap();
+    storage.put(b"key1", b"2").unwrap();
+    assert!(matches!(txn.put(b"key1", b"3"), Err(Error::Conflict(_))));
+    assert!(matches!(txn.delete(b"key1"), Err(Error::Conflict(_))));
+    txn.put(b"key2", b"3").unwrap();
+    txn.commit().unwrap();
+    assert_eq!(storage.get(b"key1").unwrap(), Some(Bytes::from("2")));
+
+    // a pessimistic transaction committed after the read ts
+    let txn1 = storage.new_pessimistic_txn().unwrap();
+    let txn2 = storage.new_pessimistic_txn().unwrap();
+    txn2.put(b"key1", b"4").unwrap();
+    txn2.commit().unwrap();
+    assert!(matches!(txn1.put(b"key1", b"5"), Err(Error::Conflict(_))));
+
+    // the latest value read by get_for_update can be overwritten
+    assert_eq!(
+        txn1.get_for_update(b"key1").unwrap(),
+        Some(Bytes::from("4"))
+    );
+    txn1.put(b"key1", b"5").unwrap();
+    // unless it is committed again after that
+    assert_eq!(
+        txn1.get_for_update(b"key2").unwrap(),
+        Some(Bytes::from("3"))
+    );
+    storage.put(b"key2", b"6").unwrap();
+    assert!(matches!(txn1.delete(b"key2"), Err(Error::Conflict(_))));
+    txn1.commit().unwrap();
+    assert_eq!(storage.get(b"key1").unwrap(), Some(Bytes::from("5")));
+    assert_eq!(storage.get(b"key2").unwrap(), Some(Bytes::from("6")));
+}
@@ -0,0 +1,85 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    fs::{FaultInjectionFs, MemFs},
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+};
+
+const DB_PATH: &str = "/db";
+
+#[test]
+fn test_commit_ts_after_deletes_compacted() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"key1", b"1").unwrap();
+    storage.put(b"key2", b"1").unwrap();
+    storage.force_flush().unwrap();
+    storage.delete(b"key1").unwrap();
+    storage.delete(b"key2").unwrap();
+    storage.force_flush().unwrap();
+    // the tombstones and the versions below them are all removed
+    storage.force_full_compaction().unwrap();
+    assert!(storage.inner.state.read().sstables.is_empty());
+    let latest_commit_ts = storage.inner.mvcc().latest_commit_ts();
+    assert_eq!(latest_commit_ts, 4);
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    assert!(storage.inner.mvcc().latest_commit_ts() >= latest_commit_ts);
+    storage.put(b"key1", b"2").unwrap();
+    assert!(storage.inner.mvcc().latest_commit_ts() > latest_commit_ts);
+    assert_eq!(storage.get(b"key1").unwrap(), Some(Bytes::from("2")));
+}
+
+#[test]
+fn test_commit_ts_after_crash() {
+    let fs = Arc::new(FaultInjectionFs::new(Arc::new(MemFs::new())));
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    options.fs = fs.clone();
+    let storage = MiniLsm::open(DB_PATH, options.clone()).unwrap();
+    storage.put(b"key1", b"1").unwrap();
+    storage.sync().unwrap();
+    // a read above the latest commit ts, and an empty commit, leave no data behind
+    storage.get_at(b"key1", 5000).unwrap();
+    storage.new_txn().unwrap().commit().unwrap();
+    assert_eq!(storage.inner.mvcc().latest_commit_ts(), 5001);
+    // a write that is lost in the crash
+    storage.put(b"key2", b"2").unwrap();
+    let handed_out_ts = storage.inner.mvcc().latest_commit_ts();
+    let fs = fs.crash().unwrap();
+    drop(storage);
+
+    options.fs = fs;
+    let storage = MiniLsm::open(DB_PATH, options).unwrap();
+    assert!(storage.inner.mvcc().latest_commit_ts() >= handed_out_ts);
+    assert_eq!(storage.get(b"key1").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(storage.get(b"key2").unwrap(), None);
+    storage.put(b"key1", b"3").unwrap();
+    assert!(storage.inner.mvcc().latest_commit_ts() > handed_out_ts);
+    assert_eq!(storage.get(b"key1").unwrap(), Some(Bytes::from("3")));
+}
+
+#[test]
+fn test_safe_point_after_restart() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"key1", b"1").unwrap();
+    storage.set_safe_point(1).unwrap();
+    storage.put(b"key1", b"2").unwrap();
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    assert_eq!(storage.inner.mvcc().watermark(), 1);
+    storage.force_flush().unwrap();
+    storage.force_full_compaction().unwrap();
+    assert_eq!(storage.get_at(b"key1", 1).unwrap(), Some(Bytes::from("1")));
+}
@@ -0,0 +1,118 @@
+use std::ops::Bound;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    mvcc::IsolationLevel,
+    tests::harness::check_lsm_iter_result_by_key,
+};
+
+#[test]
+fn test_rollback_to_savepoint() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"key1", b"1").unwrap();
+    storage.put(b"key3", b"1").unwrap();
+    let txn = storage.new_txn().unwrap();
+    assert!(txn.rollback_to_savepoint().is_err());
+    txn.put(b"key1", b"2").unwrap();
+    txn.set_savepoint().unwrap();
+    txn.put(b"key1", b"3").unwrap();
+    txn.put(b"key2", b"3").unwrap();
+    txn.delete(b"key3").unwrap();
+    txn.set_savepoint().unwrap();
+    txn.put(b"key4", b"4").unwrap();
+    txn.rollback_to_savepoint().unwrap();
+    check_lsm_iter_result_by_key(
+        &mut txn.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        vec![
+            (Bytes::from("key1"), Bytes::from("3")),
+            (Bytes::from("key2"), Bytes::from("3")),
+        ],
+    );
+    txn.rollback_to_savepoint().unwrap();
+    check_lsm_iter_result_by_key(
+        &mut txn.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        vec![
+            (Bytes::from("key1"), Bytes::from("2")),
+            (Bytes::from("key3"), Bytes::from("1")),
+        ],
+    );
+    assert!(txn.rollback_to_savepoint().is_err());
+    txn.commit().unwrap();
+    assert_eq!(storage.get(b"key1").unwrap(), Some(Bytes::from("2")));
+    assert_eq!(storage.get(b"key2").unwrap(), None);
+    assert_eq!(storage.get(b"key3").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(storage.get(b"key4").unwrap(), None);
+}
+
+#[test]
+fn test_rollback() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"key1", b"1").unwrap();
+    let txn = storage.new_txn().unwrap();
+    txn.put(b"key1", b"2").unwrap();
+    txn.set_savepoint().unwrap();
+    txn.put(b"key2", b"2").unwrap();
+    txn.rollback().unwrap();
+    assert!(txn.rollback_to_savepoint().is_err());
+    assert_eq!(txn.get(b"key1").unwrap(), Some(Bytes::from("1")));
+    txn.put(b"key3", b"3").unwrap();
+    txn.commit().unwrap();
+    assert_eq!(storage.get(b"key1").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(storage.get(b"key2").unwrap(), None);
+    assert_eq!(storage.get(b"key3").unwrap(), Some(Bytes::from("3")));
+}
+
+#[test]
+fn test_rollback_to_savepoint_conflicts() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"key1", b"1").unwrap();
+    let txn1 = storage
+        .new_txn_with_isolation(IsolationLevel::Serializable)
+        .unwrap();
+    let txn2 = storage
+        .new_txn_with_isolation(IsolationLevel::Serializable)
+        .unwrap();
+    txn1.set_savepoint().unwrap();
+    // the read of key1 and the scan are rolled back and no longer conflict with txn2
+    assert_eq!(txn1.get(b"key1").unwrap(), Some(Bytes::from("1")));
+    txn1.scan(Bound::Included(b"key0"), Bound::Included(b"key9"))
+        .unwrap();
+    txn1.put(b"key1", b"2").unwrap();
+    txn1.rollback_to_savepoint().unwrap();
+    txn1.put(b"key3", b"3").unwrap();
+    txn2.put(b"key1", b"2").unwrap();
+    txn2.put(b"key2", b"2").unwrap();
+    txn2.commit().unwrap();
+    txn1.commit().unwrap();
+    assert_eq!(storage.get(b"key1").unwrap(), Some(Bytes::from("2")));
+    assert_eq!(storage.get(b"key3").unwrap(), Some(Bytes::from("3")));
+}
+
+#[test]
+fn test_pessimistic_rollback_to_savepoint() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    let txn = storage.new_pessimistic_txn().unwrap();
+    txn.put(b"key1", b"1").unwrap();
+    txn.set_savepoint().unwrap();
+    txn.put(b"key2", b"2").unwrap();
+    txn.rollback_to_savepoint().unwrap();
+    // key2 stays locked until commit
+    assert_eq!(storage.inner.mvcc().lock_manager.num_waiters(), 0);
+    assert_eq!(txn.locked_keys.lock().len(), 2);
+    txn.commit().unwrap();
+    assert_eq!(txn.locked_keys.lock().len(), 0);
+    assert_eq!(storage.get(b"key1").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(storage.get(b"key2").unwrap(), None);
+}
@@ -0,0 +1,116 @@
+use std::ops::Bound;
+
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    tests::harness::check_lsm_iter_result_by_key,
+};
+
+#[test]
+fn test_snapshot_read() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"key1", b"1").unwrap();
+    storage.put(b"key2", b"1").unwrap();
+    let snapshot = storage.snapshot().unwrap();
+    storage.put(b"key1", b"2").unwrap();
+    storage.delete(b"key2").unwrap();
+    storage.put(b"key3", b"2").unwrap();
+    assert_eq!(snapshot.get(b"key1").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(snapshot.get(b"key2").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(snapshot.get(b"key3").unwrap(), None);
+    storage.force_flush().unwrap();
+    storage.force_full_compaction().unwrap();
+    check_lsm_iter_result_by_key(
+        &mut snapshot.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
+        vec![
+            (Bytes::from("key1"), Bytes::from("1")),
+            (Bytes::from("key2"), Bytes::from("1")),
+        ],
+    );
+    assert_eq!(storage.inner.mvcc().watermark(), snapshot.read_ts());
+    drop(snapshot);
+    assert_eq!(
+        storage.inner.mvcc().watermark(),
+        storage.inner.mvcc().latest_commit_ts()
+    );
+}
+
+#[test]
+fn test_snapshot_iterator_holds_watermark() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"key1", b"1").unwrap();
+    let snapshot = storage.snapshot().unwrap();
+    let read_ts = snapshot.read_ts();
+    let mut iter = snapshot.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    drop(snapshot);
+    storage.put(b"key1", b"2").unwrap();
+    assert_eq!(storage.inner.mvcc().watermark(), read_ts);
+    check_lsm_iter_result_by_key(&mut iter, vec![(Bytes::from("key1"), Bytes::from("1"))]);
+    drop(iter);
+    assert_eq!(
+        storage.inner.mvcc().watermark(),
+        storage.inner.mvcc().latest_commit_ts()
+    );
+}
+
+#[test]
+fn test_get_at_history_retention() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.history_retention = 2;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    for i in 1..=5 {
+        storage.put(b"key", format!("{i}").as_bytes()).unwrap();
+    }
+    let latest_ts = storage.inner.mvcc().latest_commit_ts();
+    assert_eq!(storage.inner.mvcc().watermark(), latest_ts - 2);
+    storage.force_flush().unwrap();
+    storage.force_full_compaction().unwrap();
+    assert_eq!(
+        storage.get_at(b"key", latest_ts).unwrap(),
+        Some(Bytes::from("5"))
+    );
+    assert_eq!(
+        storage.get_at(b"key", latest_ts - 2).unwrap(),
+        Some(Bytes::from("3"))
+    );
+    assert!(storage.get_at(b"key", latest_ts - 3).is_err());
+    // reading above the latest commit ts advances it
+    assert_eq!(
+        storage.get_at(b"key", latest_ts + 1).unwrap(),
+        Some(Bytes::from("5"))
+    );
+    assert_eq!(storage.inner.mvcc().latest_commit_ts(), latest_ts + 1);
+}
+
+#[test]
+fn test_scan_excluded_bounds_skip_all_versions() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    for value in [b"1", b"2", b"3"] {
+        storage.put(b"key1", value).unwrap();
+        storage.put(b"key3", value).unwrap();
+    }
+    storage.put(b"key2", b"1").unwrap();
+    let snapshot = storage.snapshot().unwrap();
+    check_lsm_iter_result_by_key(
+        &mut snapshot
+            .scan(Bound::Excluded(b"key1"), Bound::Excluded(b"key3"))
+            .unwrap(),
+        vec![(Bytes::from("key2"), Bytes::from("1"))],
+    );
+    check_lsm_iter_result_by_key(
+        &mut snapshot
+            .scan(Bound::Excluded(b"key2"), Bound::Excluded(b"key3"))
+            .unwrap(),
+        vec![],
+    );
+}
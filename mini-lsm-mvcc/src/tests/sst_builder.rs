@@ -0,0 +1,81 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::{
+    error::Error,
+    fs::{temp_path, FaultInjectionFs, FileSystem, MemFs},
+    iterators::StorageIterator,
+    key::KeySlice,
+    table::{SsTableBuilder, SsTableIterator},
+};
+
+fn key_of(i: usize) -> Vec<u8> {
+    format!("key_{:05}", i).into_bytes()
+}
+
+fn value_of(i: usize) -> Vec<u8> {
+    format!("value_{:010}", i).into_bytes()
+}
+
+fn add_keys(builder: &mut SsTableBuilder, n: usize) {
+    for i in 0..n {
+        builder.add(
+            KeySlice::for_testing_from_slice_with_ts(&key_of(i), 1),
+            &value_of(i),
+        );
+    }
+}
+
+#[test]
+fn test_streaming_builder_matches_in_memory() {
+    let fs = Arc::new(MemFs::new());
+    fs.create_dir_all(Path::new("/db")).unwrap();
+    let memory_path = Path::new("/db/00001.sst");
+    let streaming_path = Path::new("/db/00002.sst");
+
+    let mut builder = SsTableBuilder::new(128);
+    add_keys(&mut builder, 500);
+    builder.build_in(fs.as_ref(), 1, None, memory_path).unwrap();
+
+    let mut builder = SsTableBuilder::new_streaming(fs.clone(), streaming_path, 128).unwrap();
+    add_keys(&mut builder, 500);
+    // the finished blocks are already in the temporary file
+    assert!(!fs.exists(streaming_path));
+    let written = fs.read(&temp_path(streaming_path)).unwrap().len();
+    assert_eq!(written, builder.estimated_size());
+    assert!(written > 0);
+    let sst = Arc::new(
+        builder
+            .build_in(fs.as_ref(), 2, None, streaming_path)
+            .unwrap(),
+    );
+    assert!(!fs.exists(&temp_path(streaming_path)));
+    assert_eq!(
+        fs.read(memory_path).unwrap(),
+        fs.read(streaming_path).unwrap()
+    );
+
+    let mut iter = SsTableIterator::create_and_seek_to_first(sst).unwrap();
+    for i in 0..500 {
+        assert!(iter.is_valid());
+        assert_eq!(iter.key().key_ref(), key_of(i));
+        assert_eq!(iter.value(), value_of(i));
+        iter.next().unwrap();
+    }
+    assert!(!iter.is_valid());
+}
+
+#[test]
+fn test_streaming_builder_write_failure() {
+    let fs = Arc::new(FaultInjectionFs::new(Arc::new(MemFs::new())));
+    fs.create_dir_all(Path::new("/db")).unwrap();
+    let path = Path::new("/db/00001.sst");
+    let mut builder = SsTableBuilder::new_streaming(fs.clone(), path, 128).unwrap();
+    add_keys(&mut builder, 100);
+    fs.fill_disk_after_writes(0);
+    add_keys(&mut builder, 100);
+    fs.clear_write_failures();
+    let err = builder.build_in(fs.as_ref(), 1, None, path).err().unwrap();
+    assert!(matches!(err, Error::Io(_)), "{}", err);
+    assert!(!fs.exists(path));
+}
@@ -0,0 +1,146 @@
+use bytes::Bytes;
+use tempfile::tempdir;
+
+use crate::{
+    compact::CompactionOptions,
+    error::Error,
+    lsm_storage::{LsmStorageOptions, MiniLsm},
+    mvcc::IsolationLevel,
+};
+
+#[test]
+fn test_prepare_commit() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"key2", b"1").unwrap();
+    let txn1 = storage.new_txn().unwrap();
+    txn1.put(b"key1", b"1").unwrap();
+    txn1.delete(b"key2").unwrap();
+    txn1.prepare("txn1").unwrap();
+    assert_eq!(storage.prepared_txns(), vec!["txn1".to_string()]);
+    // prepared writes are not visible until committed
+    assert_eq!(storage.get(b"key1").unwrap(), None);
+    // a prepared transaction conflicts with writes to the same keys
+    let txn2 = storage
+        .new_txn_with_isolation(IsolationLevel::SnapshotIsolation)
+        .unwrap();
+    txn2.put(b"key1", b"2").unwrap();
+    assert!(txn2.commit().is_err());
+    let txn3 = storage.new_txn().unwrap();
+    txn3.put(b"key1", b"3").unwrap();
+    assert!(txn3.prepare("txn1").is_err());
+    txn1.commit().unwrap();
+    assert!(storage.prepared_txns().is_empty());
+    assert!(storage.commit_prepared("txn1").is_err());
+    assert_eq!(storage.get(b"key1").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(storage.get(b"key2").unwrap(), None);
+}
+
+#[test]
+fn test_prepare_recover() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    let txn1 = storage.new_txn().unwrap();
+    txn1.put(b"key1", b"1").unwrap();
+    txn1.prepare("txn1").unwrap();
+    let txn2 = storage.new_txn().unwrap();
+    txn2.put(b"key2", b"2").unwrap();
+    txn2.prepare("txn2").unwrap();
+    drop((txn1, txn2));
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    assert_eq!(
+        storage.prepared_txns(),
+        vec!["txn1".to_string(), "txn2".to_string()]
+    );
+    storage.commit_prepared("txn1").unwrap();
+    storage.rollback_prepared("txn2").unwrap();
+    assert!(storage.rollback_prepared("txn2").is_err());
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    assert!(storage.prepared_txns().is_empty());
+    assert_eq!(storage.get(b"key1").unwrap(), Some(Bytes::from("1")));
+    assert_eq!(storage.get(b"key2").unwrap(), None);
+}
+
+#[test]
+fn test_prepare_recover_after_flush() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"key0", b"0").unwrap();
+    let txn1 = storage.new_txn().unwrap();
+    txn1.put(b"key1", b"1").unwrap();
+    txn1.prepare("txn1").unwrap();
+    let txn2 = storage.new_txn().unwrap();
+    txn2.put(b"key2", b"2").unwrap();
+    txn2.prepare("txn2").unwrap();
+    // the WAL with the prepare records is removed after the flush
+    storage.force_flush().unwrap();
+    storage.rollback_prepared("txn2").unwrap();
+    storage.put(b"key3", b"3").unwrap();
+    storage.force_flush().unwrap();
+    storage.close().unwrap();
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    assert_eq!(storage.prepared_txns(), vec!["txn1".to_string()]);
+    storage.rollback_prepared("txn1").unwrap();
+    storage.put(b"key4", b"4").unwrap();
+    storage.force_flush().unwrap();
+    storage.close().unwrap();
+    drop(storage);
+
+    // the prepare record in the WAL recovered before is not restored again
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    assert!(storage.prepared_txns().is_empty());
+    assert_eq!(storage.get(b"key1").unwrap(), None);
+    assert_eq!(storage.get(b"key2").unwrap(), None);
+    assert_eq!(storage.get(b"key4").unwrap(), Some(Bytes::from("4")));
+}
+
+#[test]
+fn test_prepare_requires_wal() {
+    let dir = tempdir().unwrap();
+    let options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    let txn = storage.new_txn().unwrap();
+    txn.put(b"key1", b"1").unwrap();
+    assert!(txn.prepare("txn").is_err());
+    assert!(storage.prepared_txns().is_empty());
+}
+
+#[test]
+fn test_prepare_name_length() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.enable_wal = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    for name in [String::new(), "x".repeat(u16::MAX as usize + 1)] {
+        let txn = storage.new_txn().unwrap();
+        txn.put(b"key1", b"1").unwrap();
+        assert!(matches!(txn.prepare(&name), Err(Error::InvalidArgument(_))));
+    }
+    let longest_name = "x".repeat(u16::MAX as usize);
+    let txn = storage.new_txn().unwrap();
+    txn.put(b"key2", b"2").unwrap();
+    txn.prepare(&longest_name).unwrap();
+    assert_eq!(storage.prepared_txns(), vec![longest_name.clone()]);
+    drop(txn);
+    drop(storage);
+
+    let storage = MiniLsm::open(&dir, options).unwrap();
+    assert_eq!(storage.prepared_txns(), vec![longest_name.clone()]);
+    storage.commit_prepared(&longest_name).unwrap();
+    assert_eq!(storage.get(b"key1").unwrap(), None);
+    assert_eq!(storage.get(b"key2").unwrap(), Some(Bytes::from("2")));
+}
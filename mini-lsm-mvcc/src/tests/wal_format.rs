@@ -0,0 +1,144 @@
+use std::path::Path;
+
+use bytes::{BufMut, Bytes};
+use crossbeam_skiplist::SkipMap;
+
+use crate::{
+    error::{Error, Result},
+    fs::{FileSystem, MemFs},
+    key::{KeyBytes, KeySlice},
+    wal::{PreparedLog, Wal},
+};
+
+const WAL_PATH: &str = "/db/00000.wal";
+
+/// Encode a WAL record the way the WALs before the two-phase commit records were written.
+fn baseline_record(entries: &[(&[u8], u64, &[u8])]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (key, ts, value) in entries {
+        body.put_u16(key.len() as u16);
+        body.put_slice(key);
+        body.put_u64(*ts);
+        body.put_u16(value.len() as u16);
+        body.put_slice(value);
+    }
+    let mut record = Vec::new();
+    record.put_u32(body.len() as u32);
+    record.put_slice(&body);
+    record.put_u32(crc32fast::hash(&body));
+    record
+}
+
+fn new_fs() -> MemFs {
+    let fs = MemFs::new();
+    fs.create_dir_all(Path::new(WAL_PATH).parent().unwrap())
+        .unwrap();
+    fs
+}
+
+fn write_wal(fs: &MemFs, data: &[u8]) {
+    let mut file = fs.create(Path::new(WAL_PATH)).unwrap();
+    file.append(data).unwrap();
+    file.sync().unwrap();
+}
+
+fn recover(fs: &MemFs) -> (Result<Wal>, SkipMap<KeyBytes, Bytes>, PreparedLog) {
+    let skiplist = SkipMap::new();
+    let mut prepared_log = PreparedLog::default();
+    let wal = Wal::recover(fs, WAL_PATH, &skiplist, &mut prepared_log);
+    (wal, skiplist, prepared_log)
+}
+
+fn get(skiplist: &SkipMap<KeyBytes, Bytes>, key: &[u8], ts: u64) -> Option<Bytes> {
+    skiplist
+        .get(&KeyBytes::from_bytes_with_ts(
+            Bytes::copy_from_slice(key),
+            ts,
+        ))
+        .map(|entry| entry.value().clone())
+}
+
+#[test]
+fn test_replay_baseline_wal() {
+    let fs = new_fs();
+    // a record whose first key is 1 or 2 bytes long, which must not be taken for a record type
+    let mut data = baseline_record(&[(&b"a"[..], 1, &b"1"[..]), (&b"bb"[..], 1, &b"2"[..])]);
+    data.extend(baseline_record(&[(&b"a"[..], 2, &b""[..])]));
+    data.extend(baseline_record(&[]));
+    write_wal(&fs, &data);
+
+    let (wal, skiplist, prepared_log) = recover(&fs);
+    let wal = wal.unwrap();
+    assert_eq!(skiplist.len(), 3);
+    assert_eq!(get(&skiplist, b"a", 1), Some(Bytes::from_static(b"1")));
+    assert_eq!(get(&skiplist, b"bb", 1), Some(Bytes::from_static(b"2")));
+    assert_eq!(get(&skiplist, b"a", 2), Some(Bytes::new()));
+    assert!(prepared_log.prepared.is_empty());
+
+    // the records appended after the baseline ones are replayed along with them
+    wal.put(KeySlice::from_slice(b"c", 3), b"3").unwrap();
+    wal.prepare(
+        "txn",
+        3,
+        &[(Bytes::from_static(b"d"), Bytes::from_static(b"4"))],
+    )
+    .unwrap();
+    wal.sync().unwrap();
+    drop(wal);
+    let (wal, skiplist, prepared_log) = recover(&fs);
+    wal.unwrap();
+    assert_eq!(skiplist.len(), 4);
+    assert_eq!(get(&skiplist, b"c", 3), Some(Bytes::from_static(b"3")));
+    assert!(prepared_log.prepared.contains_key("txn"));
+
+    // a WAL written now is still in the baseline layout when it has only write batches
+    let fs = new_fs();
+    let wal = Wal::create(&fs, WAL_PATH).unwrap();
+    wal.put(KeySlice::from_slice(b"a", 1), b"1").unwrap();
+    wal.sync().unwrap();
+    assert_eq!(
+        fs.read(Path::new(WAL_PATH)).unwrap(),
+        baseline_record(&[(&b"a"[..], 1, &b"1"[..])])
+    );
+}
+
+#[test]
+fn test_malformed_wal_record() {
+    let mut entry = Vec::new();
+    entry.put_u16(1);
+    entry.put_slice(b"a");
+    entry.put_u64(1);
+    entry.put_u16(1);
+    entry.put_slice(b"1");
+    // every truncation of the entry, and lengths past the end of the record, with a valid checksum
+    let mut bodies = (0..entry.len())
+        .map(|len| entry[..len].to_vec())
+        .filter(|body| !body.is_empty())
+        .collect::<Vec<_>>();
+    let mut long_key = entry.clone();
+    long_key[..2].copy_from_slice(&u16::MAX.to_be_bytes());
+    bodies.push(long_key);
+    let mut long_value = entry.clone();
+    let value_len = long_value.len() - 3;
+    long_value[value_len..value_len + 2].copy_from_slice(&100u16.to_be_bytes());
+    bodies.push(long_value);
+    for (typed, body) in bodies.iter().map(|body| (false, body.clone())).chain([
+        (true, Vec::new()),
+        (true, vec![9]),
+        (true, vec![1, 0, 5, b'a']),
+        (true, vec![2, 0, 1, b'a', 0, 0]),
+    ]) {
+        let mut data = Vec::new();
+        data.put_u32(body.len() as u32 | if typed { 1 << 31 } else { 0 });
+        data.put_slice(&body);
+        data.put_u32(crc32fast::hash(&body));
+        let fs = new_fs();
+        write_wal(&fs, &data);
+        let (wal, _, _) = recover(&fs);
+        assert!(
+            matches!(wal, Err(Error::Corruption { .. })),
+            "{:?}",
+            (typed, body)
+        );
+    }
+}
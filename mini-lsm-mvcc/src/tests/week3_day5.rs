@@ -16,8 +16,8 @@ fn test_txn_integration() {
     let storage = MiniLsm::open(&dir, options.clone()).unwrap();
     let txn1 = storage.new_txn().unwrap();
     let txn2 = storage.new_txn().unwrap();
-    txn1.put(b"test1", b"233");
-    txn2.put(b"test2", b"233");
+    txn1.put(b"test1", b"233").unwrap();
+    txn2.put(b"test2", b"233").unwrap();
     check_lsm_iter_result_by_key(
         &mut txn1.scan(Bound::Unbounded, Bound::Unbounded).unwrap(),
         vec![(Bytes::from("test1"), Bytes::from("233"))],
@@ -55,7 +55,7 @@ fn test_txn_integration() {
             (Bytes::from("test2"), Bytes::from("233")),
         ],
     );
-    txn4.put(b"test2", b"2333");
+    txn4.put(b"test2", b"2333").unwrap();
     assert_eq!(txn4.get(b"test1").unwrap(), Some(Bytes::from("233")));
     assert_eq!(txn4.get(b"test2").unwrap(), Some(Bytes::from("2333")));
     check_lsm_iter_result_by_key(
@@ -65,7 +65,7 @@ fn test_txn_integration() {
             (Bytes::from("test2"), Bytes::from("2333")),
         ],
     );
-    txn4.delete(b"test2");
+    txn4.delete(b"test2").unwrap();
     assert_eq!(txn4.get(b"test1").unwrap(), Some(Bytes::from("233")));
     assert_eq!(txn4.get(b"test2").unwrap(), None);
     check_lsm_iter_result_by_key(
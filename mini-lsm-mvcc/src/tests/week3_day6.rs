@@ -19,8 +19,10 @@ fn test_serializable_1() {
     storage.put(b"key2", b"2").unwrap();
     let txn1 = storage.new_txn().unwrap();
     let txn2 = storage.new_txn().unwrap();
-    txn1.put(b"key1", &txn1.get(b"key2").unwrap().unwrap());
-    txn2.put(b"key2", &txn2.get(b"key1").unwrap().unwrap());
+    txn1.put(b"key1", &txn1.get(b"key2").unwrap().unwrap())
+        .unwrap();
+    txn2.put(b"key2", &txn2.get(b"key1").unwrap().unwrap())
+        .unwrap();
     txn1.commit().unwrap();
     assert!(txn2.commit().is_err());
     drop(txn2);
@@ -36,8 +38,8 @@ fn test_serializable_2() {
     let storage = MiniLsm::open(&dir, options.clone()).unwrap();
     let txn1 = storage.new_txn().unwrap();
     let txn2 = storage.new_txn().unwrap();
-    txn1.put(b"key1", b"1");
-    txn2.put(b"key1", b"2");
+    txn1.put(b"key1", b"1").unwrap();
+    txn2.put(b"key1", b"2").unwrap();
     txn1.commit().unwrap();
     txn2.commit().unwrap();
     assert_eq!(storage.get(b"key1").unwrap(), Some(Bytes::from("2")));
@@ -52,10 +54,12 @@ fn test_serializable_3_ts_range() {
     storage.put(b"key1", b"1").unwrap();
     storage.put(b"key2", b"2").unwrap();
     let txn1 = storage.new_txn().unwrap();
-    txn1.put(b"key1", &txn1.get(b"key2").unwrap().unwrap());
+    txn1.put(b"key1", &txn1.get(b"key2").unwrap().unwrap())
+        .unwrap();
     txn1.commit().unwrap();
     let txn2 = storage.new_txn().unwrap();
-    txn2.put(b"key2", &txn2.get(b"key1").unwrap().unwrap());
+    txn2.put(b"key2", &txn2.get(b"key1").unwrap().unwrap())
+        .unwrap();
     txn2.commit().unwrap();
     drop(txn2);
     assert_eq!(storage.get(b"key1").unwrap(), Some(Bytes::from("2")));
@@ -72,13 +76,14 @@ fn test_serializable_4_scan() {
     storage.put(b"key2", b"2").unwrap();
     let txn1 = storage.new_txn().unwrap();
     let txn2 = storage.new_txn().unwrap();
-    txn1.put(b"key1", &txn1.get(b"key2").unwrap().unwrap());
+    txn1.put(b"key1", &txn1.get(b"key2").unwrap().unwrap())
+        .unwrap();
     txn1.commit().unwrap();
     let mut iter = txn2.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
     while iter.is_valid() {
         iter.next().unwrap();
     }
-    txn2.put(b"key2", b"1");
+    txn2.put(b"key2", b"1").unwrap();
     assert!(txn2.commit().is_err());
     drop(txn2);
     assert_eq!(storage.get(b"key1").unwrap(), Some(Bytes::from("2")));
@@ -94,7 +99,8 @@ fn test_serializable_5_read_only() {
     storage.put(b"key1", b"1").unwrap();
     storage.put(b"key2", b"2").unwrap();
     let txn1 = storage.new_txn().unwrap();
-    txn1.put(b"key1", &txn1.get(b"key2").unwrap().unwrap());
+    txn1.put(b"key1", &txn1.get(b"key2").unwrap().unwrap())
+        .unwrap();
     txn1.commit().unwrap();
     let txn2 = storage.new_txn().unwrap();
     txn2.get(b"key1").unwrap().unwrap();
@@ -106,3 +112,96 @@ fn test_serializable_5_read_only() {
     assert_eq!(storage.get(b"key1").unwrap(), Some(Bytes::from("2")));
     assert_eq!(storage.get(b"key2").unwrap(), Some(Bytes::from("2")));
 }
+
+#[test]
+fn test_serializable_6_phantom() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.serializable = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"key1", b"1").unwrap();
+    storage.put(b"key3", b"3").unwrap();
+    let txn1 = storage.new_txn().unwrap();
+    let txn2 = storage.new_txn().unwrap();
+    let mut iter = txn1
+        .scan(Bound::Included(b"key1"), Bound::Included(b"key3"))
+        .unwrap();
+    let mut cnt = 0;
+    while iter.is_valid() {
+        cnt += 1;
+        iter.next().unwrap();
+    }
+    assert_eq!(cnt, 2);
+    txn1.put(b"count", cnt.to_string().as_bytes()).unwrap();
+    // insert a key that was never returned by the scan of txn1
+    txn2.put(b"key2", b"2").unwrap();
+    txn2.commit().unwrap();
+    assert!(txn1.commit().is_err());
+    drop(txn1);
+    assert_eq!(storage.get(b"count").unwrap(), None);
+    assert_eq!(storage.get(b"key2").unwrap(), Some(Bytes::from("2")));
+}
+
+#[test]
+fn test_serializable_7_phantom_out_of_range() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.serializable = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"key1", b"1").unwrap();
+    storage.put(b"key3", b"3").unwrap();
+    let txn1 = storage.new_txn().unwrap();
+    let txn2 = storage.new_txn().unwrap();
+    let mut iter = txn1
+        .scan(Bound::Included(b"key1"), Bound::Excluded(b"key3"))
+        .unwrap();
+    while iter.is_valid() {
+        iter.next().unwrap();
+    }
+    txn1.put(b"count", b"1").unwrap();
+    txn2.put(b"key3", b"4").unwrap();
+    txn2.put(b"key4", b"4").unwrap();
+    txn2.commit().unwrap();
+    txn1.commit().unwrap();
+    assert_eq!(storage.get(b"count").unwrap(), Some(Bytes::from("1")));
+}
+
+#[test]
+fn test_serializable_8_phantom_delete() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.serializable = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    storage.put(b"key1", b"1").unwrap();
+    storage.put(b"key2", b"2").unwrap();
+    let txn1 = storage.new_txn().unwrap();
+    let txn2 = storage.new_txn().unwrap();
+    let mut iter = txn1.scan(Bound::Unbounded, Bound::Unbounded).unwrap();
+    while iter.is_valid() {
+        iter.next().unwrap();
+    }
+    txn1.put(b"key3", b"3").unwrap();
+    txn2.delete(b"key2").unwrap();
+    txn2.commit().unwrap();
+    assert!(txn1.commit().is_err());
+}
+
+#[test]
+fn test_serializable_9_exact_keys() {
+    let dir = tempdir().unwrap();
+    let mut options = LsmStorageOptions::default_for_week2_test(CompactionOptions::NoCompaction);
+    options.serializable = true;
+    let storage = MiniLsm::open(&dir, options.clone()).unwrap();
+    let mut txns = Vec::new();
+    for i in 0..100 {
+        let txn = storage.new_txn().unwrap();
+        let key = format!("key{:03}", i);
+        assert_eq!(txn.get(key.as_bytes()).unwrap(), None);
+        txn.put(key.as_bytes(), b"1").unwrap();
+        txns.push(txn);
+    }
+    // every transaction reads and writes a different key, so none of them conflicts
+    for txn in txns {
+        txn.commit().unwrap();
+    }
+}
@@ -1,90 +1,223 @@
-use std::fs::{File, OpenOptions};
-use std::hash::Hasher;
-use std::io::{BufWriter, Read, Write};
+use std::collections::{BTreeMap, BTreeSet};
 use std::path::Path;
 use std::sync::Arc;
 
-use anyhow::{bail, Context, Result};
 use bytes::{Buf, BufMut, Bytes};
 use crossbeam_skiplist::SkipMap;
 use parking_lot::Mutex;
 
+use crate::error::{Error, IoResultExt, Result};
+use crate::fs::{FileSystem, WritableFile};
 use crate::key::{KeyBytes, KeySlice};
 
+/// A WAL record is the size of the body, the body and the checksum of the body. A write batch has
+/// the same layout as in the WALs written before the two-phase commit records, and the other
+/// records set this bit in the size and start the body with the record type.
+const TYPED_RECORD_FLAG: u32 = 1 << 31;
+
+const RECORD_PREPARE: u8 = 1;
+const RECORD_COMMIT_PREPARED: u8 = 2;
+const RECORD_ROLLBACK_PREPARED: u8 = 3;
+
+/// The two-phase commit state recovered from the WALs.
+#[derive(Default)]
+pub struct PreparedLog {
+    /// Name -> (read ts, write batch) of the transactions that are prepared but not decided yet.
+    pub prepared: BTreeMap<String, (u64, Vec<(Bytes, Bytes)>)>,
+    /// Names of the transactions that are committed or rolled back after being prepared.
+    pub decided: BTreeSet<String>,
+}
+
+/// Decode a byte string with a u16 length, `None` if the buffer is too short.
+fn decode_bytes(buf: &mut &[u8]) -> Option<Bytes> {
+    if buf.remaining() < 2 {
+        return None;
+    }
+    let len = buf.get_u16() as usize;
+    if buf.remaining() < len {
+        return None;
+    }
+    let data = Bytes::copy_from_slice(&buf[..len]);
+    buf.advance(len);
+    Some(data)
+}
+
+fn decode_u64(buf: &mut &[u8]) -> Option<u64> {
+    (buf.remaining() >= 8).then(|| buf.get_u64())
+}
+
+fn encode_name(buf: &mut Vec<u8>, name: &str) {
+    buf.put_u16(name.len() as u16);
+    buf.put_slice(name.as_bytes());
+}
+
+fn decode_name(buf: &mut &[u8]) -> Option<String> {
+    String::from_utf8(decode_bytes(buf)?.to_vec()).ok()
+}
+
+fn encode_prepared_batch(buf: &mut Vec<u8>, batch: &[(Bytes, Bytes)]) {
+    for (key, value) in batch {
+        buf.put_u16(key.len() as u16);
+        buf.put_slice(key);
+        buf.put_u16(value.len() as u16);
+        buf.put_slice(value);
+    }
+}
+
+fn decode_prepared_batch(mut buf: &[u8]) -> Option<Vec<(Bytes, Bytes)>> {
+    let mut batch = Vec::new();
+    while buf.has_remaining() {
+        let key = decode_bytes(&mut buf)?;
+        let value = decode_bytes(&mut buf)?;
+        batch.push((key, value));
+    }
+    Some(batch)
+}
+
+/// Decode the entries of a write batch record, `None` if it is malformed.
+fn decode_batch(mut buf: &[u8]) -> Option<Vec<(KeyBytes, Bytes)>> {
+    let mut batch = Vec::new();
+    while buf.has_remaining() {
+        let key = decode_bytes(&mut buf)?;
+        let ts = decode_u64(&mut buf)?;
+        let value = decode_bytes(&mut buf)?;
+        batch.push((KeyBytes::from_bytes_with_ts(key, ts), value));
+    }
+    Some(batch)
+}
+
 pub struct Wal {
-    file: Arc<Mutex<BufWriter<File>>>,
+    file: Arc<Mutex<Box<dyn WritableFile>>>,
 }
 
 impl Wal {
-    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+    pub fn create(fs: &dyn FileSystem, path: impl AsRef<Path>) -> Result<Self> {
         Ok(Self {
-            file: Arc::new(Mutex::new(BufWriter::new(
-                OpenOptions::new()
-                    .read(true)
-                    .create_new(true)
-                    .write(true)
-                    .open(path)
-                    .context("failed to create WAL")?,
-            ))),
+            file: Arc::new(Mutex::new(
+                fs.create(path.as_ref()).context("failed to create WAL")?,
+            )),
         })
     }
 
-    pub fn recover(path: impl AsRef<Path>, skiplist: &SkipMap<KeyBytes, Bytes>) -> Result<Self> {
+    pub fn recover(
+        fs: &dyn FileSystem,
+        path: impl AsRef<Path>,
+        skiplist: &SkipMap<KeyBytes, Bytes>,
+        prepared_log: &mut PreparedLog,
+    ) -> Result<Self> {
         let path = path.as_ref();
-        let mut file = OpenOptions::new()
-            .read(true)
-            .append(true)
-            .open(path)
-            .context("failed to recover from WAL")?;
-        let mut buf = Vec::new();
-        file.read_to_end(&mut buf)?;
-        let mut rbuf: &[u8] = buf.as_slice();
+        let buf = fs.read(path).context("failed to recover from WAL")?;
+        Self::decode(path, &buf, skiplist, prepared_log)?;
+        let file = fs.open_append(path).context("failed to recover from WAL")?;
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    /// Read the records of a WAL file without opening it for writing. The two-phase commit records
+    /// are ignored.
+    pub fn read(
+        fs: &dyn FileSystem,
+        path: impl AsRef<Path>,
+        skiplist: &SkipMap<KeyBytes, Bytes>,
+    ) -> Result<()> {
+        let path = path.as_ref();
+        let buf = fs.read(path).context("failed to read WAL")?;
+        Self::decode(path, &buf, skiplist, &mut PreparedLog::default())
+    }
+
+    fn decode(
+        path: &Path,
+        buf: &[u8],
+        skiplist: &SkipMap<KeyBytes, Bytes>,
+        prepared_log: &mut PreparedLog,
+    ) -> Result<()> {
+        let mut rbuf = buf;
         while rbuf.has_remaining() {
-            let batch_size = rbuf.get_u32() as usize;
-            if rbuf.remaining() < batch_size {
-                bail!("incomplete WAL");
+            let offset = (buf.len() - rbuf.len()) as u64;
+            let corruption = |message: &str| Error::corruption(path, offset, message);
+            if rbuf.remaining() < 4 {
+                return Err(corruption("incomplete WAL"));
             }
-            let mut batch_buf = &rbuf[..batch_size];
-            let mut kv_pairs = Vec::new();
-            let mut hasher = crc32fast::Hasher::new();
-            // The checksum computed from the individual components should be the same as a direct checksum on the buffer.
-            // Students' implementation only needs to do a single checksum on the buffer. We compute both for verification purpose.
-            let single_checksum = crc32fast::hash(batch_buf);
-            while batch_buf.has_remaining() {
-                let key_len = batch_buf.get_u16() as usize;
-                hasher.write(&(key_len as u16).to_be_bytes());
-                let key = Bytes::copy_from_slice(&batch_buf[..key_len]);
-                hasher.write(&key);
-                batch_buf.advance(key_len);
-                let ts = batch_buf.get_u64();
-                hasher.write(&ts.to_be_bytes());
-                let value_len = batch_buf.get_u16() as usize;
-                hasher.write(&(value_len as u16).to_be_bytes());
-                let value = Bytes::copy_from_slice(&batch_buf[..value_len]);
-                hasher.write(&value);
-                kv_pairs.push((key, ts, value));
-                batch_buf.advance(value_len);
+            let header = rbuf.get_u32();
+            let batch_size = (header & !TYPED_RECORD_FLAG) as usize;
+            if rbuf.remaining() < batch_size + 4 {
+                return Err(corruption("incomplete WAL"));
             }
+            let mut batch_buf = &rbuf[..batch_size];
+            let checksum = crc32fast::hash(batch_buf);
             rbuf.advance(batch_size);
             let expected_checksum = rbuf.get_u32();
-            let component_checksum = hasher.finalize();
-            assert_eq!(component_checksum, single_checksum);
-            if single_checksum != expected_checksum {
-                bail!("checksum mismatch");
+            if checksum != expected_checksum {
+                return Err(corruption("checksum mismatch"));
+            }
+            let malformed = || corruption("malformed WAL record");
+            if header & TYPED_RECORD_FLAG == 0 {
+                for (key, value) in decode_batch(batch_buf).ok_or_else(malformed)? {
+                    skiplist.insert(key, value);
+                }
+                continue;
             }
-            for (key, ts, value) in kv_pairs {
-                skiplist.insert(KeyBytes::from_bytes_with_ts(key, ts), value);
+            if !batch_buf.has_remaining() {
+                return Err(malformed());
+            }
+            match batch_buf.get_u8() {
+                RECORD_PREPARE => {
+                    let name = decode_name(&mut batch_buf).ok_or_else(malformed)?;
+                    let read_ts = decode_u64(&mut batch_buf).ok_or_else(malformed)?;
+                    let batch = decode_prepared_batch(batch_buf).ok_or_else(malformed)?;
+                    prepared_log.decided.remove(&name);
+                    prepared_log.prepared.insert(name, (read_ts, batch));
+                }
+                RECORD_COMMIT_PREPARED => {
+                    let name = decode_name(&mut batch_buf).ok_or_else(malformed)?;
+                    let ts = decode_u64(&mut batch_buf).ok_or_else(malformed)?;
+                    for (key, value) in decode_prepared_batch(batch_buf).ok_or_else(malformed)? {
+                        skiplist.insert(KeyBytes::from_bytes_with_ts(key, ts), value);
+                    }
+                    prepared_log.prepared.remove(&name);
+                    prepared_log.decided.insert(name);
+                }
+                RECORD_ROLLBACK_PREPARED => {
+                    let name = decode_name(&mut batch_buf).ok_or_else(malformed)?;
+                    prepared_log.prepared.remove(&name);
+                    prepared_log.decided.insert(name);
+                }
+                record_type => {
+                    return Err(corruption(&format!(
+                        "unknown WAL record type {}",
+                        record_type
+                    )))
+                }
             }
         }
-        Ok(Self {
-            file: Arc::new(Mutex::new(BufWriter::new(file))),
-        })
+        Ok(())
+    }
+
+    /// Write a record with the body, where a typed record starts with the record type.
+    fn write_record(&self, buf: &[u8], typed: bool) -> Result<()> {
+        if buf.len() as u64 >= TYPED_RECORD_FLAG as u64 {
+            return Err(Error::InvalidArgument(format!(
+                "WAL record of {} bytes is too large",
+                buf.len()
+            )));
+        }
+        let mut file = self.file.lock();
+        let mut record = Vec::with_capacity(buf.len() + 8);
+        // batch_size header (u32)
+        let flag = if typed { TYPED_RECORD_FLAG } else { 0 };
+        record.put_u32(buf.len() as u32 | flag);
+        // body
+        record.put_slice(buf);
+        // checksum (u32)
+        record.put_u32(crc32fast::hash(buf));
+        file.append(&record).context("failed to write WAL")?;
+        Ok(())
     }
 
     /// Implement this in week 3, day 5.
     pub fn put_batch(&self, data: &[(KeySlice, &[u8])]) -> Result<()> {
-        let mut file = self.file.lock();
-        let mut buf = Vec::<u8>::new();
+        let mut buf = Vec::new();
         for (key, value) in data {
             buf.put_u16(key.key_len() as u16);
             buf.put_slice(key.key_ref());
@@ -92,13 +225,34 @@ impl Wal {
             buf.put_u16(value.len() as u16);
             buf.put_slice(value);
         }
-        // write batch_size header (u32)
-        file.write_all(&(buf.len() as u32).to_be_bytes())?;
-        // write key-value pairs body
-        file.write_all(&buf)?;
-        // write checksum (u32)
-        file.write_all(&crc32fast::hash(&buf).to_be_bytes())?;
-        Ok(())
+        self.write_record(&buf, false)
+    }
+
+    /// Log the write batch of a transaction prepared for two-phase commit. An empty value is a
+    /// delete.
+    pub fn prepare(&self, name: &str, read_ts: u64, batch: &[(Bytes, Bytes)]) -> Result<()> {
+        let mut buf = vec![RECORD_PREPARE];
+        encode_name(&mut buf, name);
+        buf.put_u64(read_ts);
+        encode_prepared_batch(&mut buf, batch);
+        self.write_record(&buf, true)
+    }
+
+    /// Log the commit of a prepared transaction. The batch is logged again so that the commit can
+    /// be recovered after the WAL with the prepare record is removed.
+    pub fn commit_prepared(&self, name: &str, ts: u64, batch: &[(Bytes, Bytes)]) -> Result<()> {
+        let mut buf = vec![RECORD_COMMIT_PREPARED];
+        encode_name(&mut buf, name);
+        buf.put_u64(ts);
+        encode_prepared_batch(&mut buf, batch);
+        self.write_record(&buf, true)
+    }
+
+    /// Log the rollback of a prepared transaction.
+    pub fn rollback_prepared(&self, name: &str) -> Result<()> {
+        let mut buf = vec![RECORD_ROLLBACK_PREPARED];
+        encode_name(&mut buf, name);
+        self.write_record(&buf, true)
     }
 
     pub fn put(&self, key: KeySlice, value: &[u8]) -> Result<()> {
@@ -106,9 +260,7 @@ impl Wal {
     }
 
     pub fn sync(&self) -> Result<()> {
-        let mut file = self.file.lock();
-        file.flush()?;
-        file.get_mut().sync_all()?;
+        self.file.lock().sync().context("failed to sync WAL")?;
         Ok(())
     }
 }
@@ -312,6 +312,9 @@ impl ReplBuilder {
     }
 }
 
+// This binary is shared by all crates, and some of them have extra options that are filled by the
+// struct update below.
+#[allow(clippy::needless_update)]
 fn main() -> Result<()> {
     let args = Args::parse();
     let lsm = MiniLsm::open(
@@ -347,6 +350,7 @@ fn main() -> Result<()> {
             },
             enable_wal: args.enable_wal,
             serializable: args.serializable,
+            ..LsmStorageOptions::default_for_week1_test()
         },
     )?;
 